@@ -6,6 +6,7 @@
 
 use alloc::format;
 use core::arch::asm;
+use core::ffi::c_void;
 use core::panic::PanicInfo;
 use core::ptr::null;
 use uefi::*;
@@ -165,14 +166,45 @@ fn load_kernel(
     Ok(())
 }
 
+/// Read an optional file from the root directory into loader data memory,
+/// which the kernel will not reuse
+fn load_file(
+    path: &str,
+    boot_service: &EfiBootServices,
+    image_handle: EfiHandle,
+) -> Result<BootFile, EfiStatus> {
+    let file_protocol = open_root_dir(image_handle, boot_service)?;
+    let file = file_protocol.open(path, EfiFileOpenMode::Read, EfiFileAttribute::None)?;
+
+    let file_size: usize = file.get_info()?.file_size.try_into().unwrap();
+    let buffer = boot_service.allocate_pool(EfiMemoryType::EfiLoaderData, file_size)?;
+    file.read(file_size, buffer as u64)?;
+    file.close()?;
+
+    Ok(BootFile {
+        buffer: buffer as *const u8,
+        size: file_size as u64,
+    })
+}
+
 /// Prepare kernel and jump to kernel
-fn run_kernel(boot_service: &EfiBootServices, image_handle: EfiHandle, memory_map: MemoryMap) -> ! {
+fn run_kernel(boot_service: &EfiBootServices, image_handle: EfiHandle, memory_map_buffer: &mut [u8], acpi_rsdp: *const c_void) -> ! {
     load_kernel(KERNEL_BASE_ADDRESS, boot_service, image_handle).expect("Failed to load kernel");
 
+    let wallpaper = match load_file("\\wallpaper", boot_service, image_handle) {
+        Ok(file) => {
+            println!("[DEBUG] wallpaper loaded ({} bytes)", file.size);
+            file
+        }
+        Err(_) => BootFile::empty(),
+    };
+
     let monitor_frame_buffer = get_monitor_config(image_handle, boot_service).unwrap();
 
-    match boot_service.exit_boot_service(image_handle) {
-        Ok(_) => goto_kernel(monitor_frame_buffer, memory_map),
+    // The memory map is fetched again here so that the kernel sees the pages
+    // allocated for itself and the files above as loader data
+    match boot_service.exit_boot_service(image_handle, memory_map_buffer) {
+        Ok(memory_map) => goto_kernel(monitor_frame_buffer, memory_map, wallpaper, acpi_rsdp),
         Err(res) => {
             panic!("Failed to exit boot service. {:?}", res)
         }
@@ -181,7 +213,7 @@ fn run_kernel(boot_service: &EfiBootServices, image_handle: EfiHandle, memory_ma
 
 /// Jump to kernel
 #[allow(unreachable_code)]
-fn goto_kernel(frame_buffer_config: FrameBufferConfig, memory_map: MemoryMap) -> ! {
+fn goto_kernel(frame_buffer_config: FrameBufferConfig, memory_map: MemoryMap, wallpaper: BootFile, acpi_rsdp: *const c_void) -> ! {
     unsafe {
         // Get entrypoint address of kernel from elf header
         let entry_point = ((KERNEL_BASE_ADDRESS + 24) as *const u64).as_ref().unwrap();
@@ -191,10 +223,10 @@ fn goto_kernel(frame_buffer_config: FrameBufferConfig, memory_map: MemoryMap) ->
         // Kernel binary is compiled with sysv64 calling convention
         let kernel_main = core::mem::transmute::<
             *const (),
-            unsafe extern "sysv64" fn(FrameBufferConfig, MemoryMap) -> !,
+            unsafe extern "sysv64" fn(FrameBufferConfig, MemoryMap, BootFile, *const c_void) -> !,
         >(kernel_main_ptr);
 
-        kernel_main(frame_buffer_config, memory_map);
+        kernel_main(frame_buffer_config, memory_map, wallpaper, acpi_rsdp);
 
         loop {
             asm!("hlt");
@@ -238,6 +270,7 @@ fn open_gop(
 enum PixelFormat {
     RGB = 0,
     BGR = 1,
+    BitMask = 2,
 }
 
 #[repr(C)]
@@ -247,6 +280,24 @@ struct FrameBufferConfig {
     horizontal_resolution: u32,
     vertical_resolution: u32,
     pixel_format: PixelFormat,
+    // Only meaningful when pixel_format is BitMask
+    pixel_bitmask: EfiPixelBitmask,
+}
+
+/// File loaded by the bootloader and handed to the kernel. `buffer` is null if absent.
+#[repr(C)]
+struct BootFile {
+    buffer: *const u8,
+    size: u64,
+}
+
+impl BootFile {
+    fn empty() -> Self {
+        Self {
+            buffer: null(),
+            size: 0,
+        }
+    }
 }
 
 /// Get the framebuffer address and size
@@ -263,8 +314,10 @@ fn get_monitor_config(
             pixel_format: match gop.mode.info.pixel_format {
                 EfiGraphicsPixelFormat::PixelRedGreenBlueReserved8BitPerColor => PixelFormat::RGB,
                 EfiGraphicsPixelFormat::PixelBlueGreenRedReserved8BitPerColor => PixelFormat::BGR,
+                EfiGraphicsPixelFormat::PixelBitMask => PixelFormat::BitMask,
                 _ => panic!("Unsupported pixel format"),
             },
+            pixel_bitmask: gop.mode.info.pixel_information,
         }),
         Err(err) => Err(err),
     }
@@ -303,9 +356,14 @@ pub extern "C" fn efi_main(image_handle: EfiHandle, system_table: &EfiSystemTabl
     opened_handle.close().unwrap();
     efi_file_proto.close().unwrap();
 
+    let acpi_rsdp = system_table
+        .find_configuration_table(&EFI_ACPI_TABLE_GUID)
+        .unwrap_or(null());
+    println!("[DEBUG] ACPI RSDP: {:p}", acpi_rsdp);
+
     println!("---- run kernel ----");
 
-    run_kernel(system_table.boot_services(), image_handle, memory_map);
+    run_kernel(system_table.boot_services(), image_handle, &mut memory_descriptor_buffer, acpi_rsdp);
 
     loop {
         unsafe {
@@ -48,7 +48,7 @@ pub enum EfiStatus {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct EfiGuid {
     data_1: u32,
     data_2: u16,
@@ -367,15 +367,18 @@ impl EfiBootServices {
         }
     }
 
+    /// Exit boot services and return the memory map that was current at that moment
+    /// # Arguments
+    /// * `memory_map_buffer` EfiMemoryDescriptor型の書き込まれる先のbuffer
     pub fn exit_boot_service(
         &self, 
         image_handle: EfiHandle,
-    ) -> Result<EfiStatus, EfiStatus> {
-        let mut memory_map: [u8; 8192] = [0; 8192];
-        let map_key = self.get_memory_map(&mut memory_map).unwrap().map_key;
-        let _res = (self.exit_boot_service)(image_handle, map_key as usize);
+        memory_map_buffer: &mut [u8],
+    ) -> Result<MemoryMap, EfiStatus> {
+        let memory_map = self.get_memory_map(memory_map_buffer)?;
+        let _res = (self.exit_boot_service)(image_handle, memory_map.map_key as usize);
         if _res == EfiStatus::Success {
-            Ok(_res)
+            Ok(memory_map)
         } else {
             Err(_res)
         }
@@ -442,6 +445,7 @@ pub struct EfiGraphicsOutputModeInformation {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy, Debug)]
 pub struct EfiPixelBitmask {
     red_mask: u32,
     green_mask: u32,
@@ -539,7 +543,19 @@ pub struct EfiMemoryDescriptor {
 
 pub type EfiPhysicalAddress = u64;
 pub type EfiVirtualAddress = u64;
-pub struct EfiConfigurationTable {}
+#[repr(C)]
+#[derive(Debug)]
+pub struct EfiConfigurationTable {
+    pub vendor_guid: EfiGuid,
+    pub vendor_table: *const c_void,
+}
+
+pub const EFI_ACPI_TABLE_GUID: EfiGuid = EfiGuid {
+    data_1: 0x8868e871,
+    data_2: 0xe4f1,
+    data_3: 0x11d3,
+    data_4: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -806,6 +822,18 @@ impl EfiSystemTable {
     pub fn boot_services(&self) -> &EfiBootServices {
         unsafe { &*self.boot_services }
     }
+
+    pub fn configuration_tables(&self) -> &[EfiConfigurationTable] {
+        unsafe { slice::from_raw_parts(self.econfiguration_table, self.number_of_table_entries) }
+    }
+
+    /// Find a configuration table such as the ACPI RSDP by its GUID
+    pub fn find_configuration_table(&self, guid: &EfiGuid) -> Option<*const c_void> {
+        self.configuration_tables()
+            .iter()
+            .find(|table| table.vendor_guid == *guid)
+            .map(|table| table.vendor_table)
+    }
 }
 
 
@@ -0,0 +1,346 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! ACPI table discovery.
+//!
+//! Tables are validated and copied into plain structs once at boot; nothing
+//! keeps pointers into firmware memory afterwards.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::{addr_of, read_unaligned};
+
+use crate::sync::OnceCell;
+use crate::timer::ReferenceClock;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    NoRsdp,
+    InvalidRsdp,
+    InvalidTable([u8; 4]),
+    NoXsdt,
+}
+
+/// Root System Description Pointer (ACPI 2.0+)
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+struct DescriptionHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// Generic Address Structure
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+fn sum_bytes(address: usize, length: usize) -> u8 {
+    let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// A validated system description table: header plus body
+struct Table {
+    address: usize,
+    length: usize,
+}
+
+impl Table {
+    unsafe fn new(address: usize) -> Result<Self, AcpiError> {
+        let header = address as *const DescriptionHeader;
+        let signature = read_unaligned(addr_of!((*header).signature));
+        let length = read_unaligned(addr_of!((*header).length)) as usize;
+        if length < size_of::<DescriptionHeader>() || sum_bytes(address, length) != 0 {
+            return Err(AcpiError::InvalidTable(signature));
+        }
+        Ok(Self { address, length })
+    }
+
+    fn signature(&self) -> [u8; 4] {
+        unsafe { read_unaligned(addr_of!((*(self.address as *const DescriptionHeader)).signature)) }
+    }
+
+    /// Read a field at `offset` from the start of the table, if the table is long enough
+    fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        if offset + size_of::<T>() > self.length {
+            return None;
+        }
+        Some(unsafe { read_unaligned((self.address + offset) as *const T) })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApicEntry {
+    pub apic_id: u32,
+    /// Enabled, or may be brought online
+    pub usable: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// ISA IRQ `source` is connected to `gsi` instead of the identity mapped GSI
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptSourceOverride {
+    pub source: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3
+    pub flags: u16,
+}
+
+#[derive(Debug, Default)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    /// A legacy 8259 PIC pair is present
+    pub pcat_compat: bool,
+    pub local_apics: Vec<LocalApicEntry>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+    /// I/O port of the PM timer
+    pub pm_timer_port: Option<u16>,
+    /// The PM timer counter is 32 bits wide rather than 24
+    pub pm_timer_extended: bool,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+    pub base_address: u64,
+    pub number: u8,
+    pub minimum_tick: u16,
+}
+
+/// PCI Express ECAM window for one range of buses
+#[derive(Clone, Copy, Debug)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct AcpiTables {
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Vec<McfgEntry>,
+}
+
+static TABLES: OnceCell<AcpiTables> = OnceCell::new();
+
+const HEADER_SIZE: usize = size_of::<DescriptionHeader>();
+
+fn parse_madt(table: &Table) -> Madt {
+    let mut madt = Madt {
+        local_apic_address: table.read::<u32>(HEADER_SIZE).unwrap_or(0) as u64,
+        pcat_compat: table.read::<u32>(HEADER_SIZE + 4).unwrap_or(0) & 1 != 0,
+        ..Madt::default()
+    };
+
+    let mut offset = HEADER_SIZE + 8;
+    while let (Some(entry_type), Some(length)) = (table.read::<u8>(offset), table.read::<u8>(offset + 1)) {
+        if length < 2 {
+            break;
+        }
+        match entry_type {
+            // Processor Local APIC
+            0 => {
+                if let (Some(id), Some(flags)) = (table.read::<u8>(offset + 3), table.read::<u32>(offset + 4)) {
+                    madt.local_apics.push(LocalApicEntry { apic_id: id as u32, usable: flags & 0b11 != 0 });
+                }
+            }
+            // I/O APIC
+            1 => {
+                if let (Some(id), Some(address), Some(gsi_base)) =
+                    (table.read::<u8>(offset + 2), table.read::<u32>(offset + 4), table.read::<u32>(offset + 8))
+                {
+                    madt.io_apics.push(IoApicEntry { id, address, gsi_base });
+                }
+            }
+            // Interrupt Source Override
+            2 => {
+                if let (Some(source), Some(gsi), Some(flags)) =
+                    (table.read::<u8>(offset + 3), table.read::<u32>(offset + 4), table.read::<u16>(offset + 8))
+                {
+                    madt.overrides.push(InterruptSourceOverride { source, gsi, flags });
+                }
+            }
+            // Local APIC Address Override
+            5 => {
+                if let Some(address) = table.read::<u64>(offset + 4) {
+                    madt.local_apic_address = address;
+                }
+            }
+            // Processor Local x2APIC
+            9 => {
+                if let (Some(id), Some(flags)) = (table.read::<u32>(offset + 4), table.read::<u32>(offset + 8)) {
+                    madt.local_apics.push(LocalApicEntry { apic_id: id, usable: flags & 0b11 != 0 });
+                }
+            }
+            _ => {}
+        }
+        offset += length as usize;
+    }
+    madt
+}
+
+fn parse_fadt(table: &Table) -> Fadt {
+    const PM_TMR_BLK: usize = 76;
+    const FLAGS: usize = 112;
+    const RESET_REG: usize = 116;
+    const RESET_VALUE: usize = 128;
+    const X_PM_TMR_BLK: usize = 208;
+    const TMR_VAL_EXT: u32 = 1 << 8;
+    const RESET_REG_SUP: u32 = 1 << 10;
+
+    let flags = table.read::<u32>(FLAGS).unwrap_or(0);
+    let pm_timer_port = match table.read::<GenericAddress>(X_PM_TMR_BLK) {
+        Some(gas) if gas.address_space == ADDRESS_SPACE_IO && gas.address != 0 => Some(gas.address as u16),
+        _ => table.read::<u32>(PM_TMR_BLK).filter(|port| *port != 0).map(|port| port as u16),
+    };
+    let reset_register = if flags & RESET_REG_SUP != 0 {
+        table.read::<GenericAddress>(RESET_REG)
+    } else {
+        None
+    };
+
+    Fadt {
+        pm_timer_port,
+        pm_timer_extended: flags & TMR_VAL_EXT != 0,
+        reset_register,
+        reset_value: table.read::<u8>(RESET_VALUE).unwrap_or(0),
+    }
+}
+
+fn parse_hpet(table: &Table) -> Option<Hpet> {
+    let base = table.read::<GenericAddress>(HEADER_SIZE + 4)?;
+    Some(Hpet {
+        base_address: base.address,
+        number: table.read::<u8>(HEADER_SIZE + 16)?,
+        minimum_tick: table.read::<u16>(HEADER_SIZE + 17)?,
+    })
+}
+
+fn parse_mcfg(table: &Table) -> Vec<McfgEntry> {
+    const ENTRY_SIZE: usize = 16;
+    // 8 reserved bytes follow the header
+    let mut offset = HEADER_SIZE + 8;
+    let mut entries = Vec::new();
+    while offset + ENTRY_SIZE <= table.length {
+        entries.push(McfgEntry {
+            base_address: table.read(offset).unwrap(),
+            segment_group: table.read(offset + 8).unwrap(),
+            start_bus: table.read(offset + 10).unwrap(),
+            end_bus: table.read(offset + 11).unwrap(),
+        });
+        offset += ENTRY_SIZE;
+    }
+    entries
+}
+
+/// Validate the RSDP handed over by the bootloader and parse the tables the kernel uses.
+///
+/// Tables with a bad checksum are skipped rather than failing the whole discovery.
+pub fn init(rsdp: *const u8) -> Result<(), AcpiError> {
+    if rsdp.is_null() {
+        return Err(AcpiError::NoRsdp);
+    }
+    let rsdp = rsdp as *const Rsdp;
+    let (signature, revision, xsdt_address) = unsafe {
+        (
+            read_unaligned(addr_of!((*rsdp).signature)),
+            read_unaligned(addr_of!((*rsdp).revision)),
+            read_unaligned(addr_of!((*rsdp).xsdt_address)),
+        )
+    };
+    // The first 20 bytes carry the ACPI 1.0 checksum, the whole structure the extended one
+    if &signature != b"RSD PTR " || sum_bytes(rsdp as usize, 20) != 0 {
+        return Err(AcpiError::InvalidRsdp);
+    }
+    if revision < 2 || sum_bytes(rsdp as usize, size_of::<Rsdp>()) != 0 {
+        return Err(AcpiError::NoXsdt);
+    }
+
+    let xsdt = unsafe { Table::new(xsdt_address as usize)? };
+    if &xsdt.signature() != b"XSDT" {
+        return Err(AcpiError::InvalidTable(xsdt.signature()));
+    }
+
+    let mut tables = AcpiTables::default();
+    let entry_count = (xsdt.length - HEADER_SIZE) / size_of::<u64>();
+    for i in 0..entry_count {
+        let address: u64 = xsdt.read(HEADER_SIZE + i * size_of::<u64>()).unwrap();
+        let table = match unsafe { Table::new(address as usize) } {
+            Ok(table) => table,
+            Err(_) => continue,
+        };
+        match &table.signature() {
+            b"APIC" => tables.madt = Some(parse_madt(&table)),
+            b"FACP" => tables.fadt = Some(parse_fadt(&table)),
+            b"HPET" => tables.hpet = parse_hpet(&table),
+            b"MCFG" => tables.mcfg = parse_mcfg(&table),
+            _ => {}
+        }
+    }
+
+    // A second call keeps the tables found first
+    let _ = TABLES.set(tables);
+    Ok(())
+}
+
+/// Parsed tables; empty if `init()` failed or was not called
+pub fn tables() -> &'static AcpiTables {
+    static EMPTY: AcpiTables = AcpiTables { madt: None, fadt: None, hpet: None, mcfg: Vec::new() };
+    TABLES.get().unwrap_or(&EMPTY)
+}
+
+/// Best clock available to calibrate the APIC timer
+pub fn reference_clock() -> ReferenceClock {
+    let tables = tables();
+    if let Some(port) = tables.fadt.and_then(|fadt| fadt.pm_timer_port) {
+        ReferenceClock::PmTimer { port, extended: tables.fadt.unwrap().pm_timer_extended }
+    } else if let Some(hpet) = tables.hpet {
+        ReferenceClock::Hpet { base: hpet.base_address as usize }
+    } else {
+        ReferenceClock::Pit
+    }
+}
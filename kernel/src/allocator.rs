@@ -0,0 +1,134 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::memory_manager::{self, BYTES_PER_FRAME};
+use crate::sync::SpinLock;
+
+const HEAP_FRAMES: usize = 64 * 512; // 64 MiB
+const MIN_ALIGN: usize = 16;
+
+/// Header stored at the beginning of every free block
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// First fit allocator over an address ordered free list.
+///
+/// Every block is a multiple of `MIN_ALIGN` bytes, which is also large enough
+/// to hold a `FreeBlock`, so splitting never leaves an unusable fragment.
+struct Heap {
+    head: *mut FreeBlock,
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+fn block_size(layout: &Layout) -> usize {
+    align_up(layout.size().max(1), MIN_ALIGN)
+}
+
+impl Heap {
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = block_size(&layout);
+        let align = layout.align().max(MIN_ALIGN);
+
+        let mut link: *mut *mut FreeBlock = &mut self.head;
+        while !(*link).is_null() {
+            let block = *link;
+            let block_start = block as usize;
+            let block_end = block_start + (*block).size;
+            let start = align_up(block_start, align);
+            let end = start + size;
+            if end <= block_end {
+                let mut rest = (*block).next;
+                if end < block_end {
+                    let back = end as *mut FreeBlock;
+                    (*back).size = block_end - end;
+                    (*back).next = rest;
+                    rest = back;
+                }
+                if start > block_start {
+                    (*block).size = start - block_start;
+                    (*block).next = rest;
+                } else {
+                    *link = rest;
+                }
+                return start as *mut u8;
+            }
+            link = &mut (*block).next;
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn free(&mut self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as usize;
+        let block = ptr as *mut FreeBlock;
+        (*block).size = block_size(&layout);
+
+        // Find the neighbors in address order
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        (*block).next = next;
+        if !next.is_null() && start + (*block).size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+// The free list is only reached through the lock
+unsafe impl Send for Heap {}
+
+static HEAP: SpinLock<Heap> = SpinLock::new(Heap { head: ptr::null_mut() });
+
+pub struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        HEAP.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        HEAP.lock().free(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+/// Reserve frames for the kernel heap. `memory_manager::init` must run first.
+pub fn init() {
+    let start = memory_manager::allocate_frames(HEAP_FRAMES).expect("[ERROR] failed to allocate kernel heap");
+    unsafe {
+        let block = start as *mut FreeBlock;
+        (*block).size = HEAP_FRAMES * BYTES_PER_FRAME;
+        (*block).next = ptr::null_mut();
+        HEAP.lock().head = block;
+    }
+}
+
+#[alloc_error_handler]
+fn out_of_memory(layout: Layout) -> ! {
+    panic!("Ran out of free memory while trying to allocate {:#?}", layout);
+}
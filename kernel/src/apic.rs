@@ -0,0 +1,217 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Legacy 8259 PIC shutdown and Local APIC setup
+
+use crate::cpu;
+use crate::interrupt::{self, vector, InterruptFrame};
+use crate::io_port::out8;
+use crate::msr;
+
+const PIC_MASTER_COMMAND: u16 = 0x20;
+const PIC_MASTER_DATA: u16 = 0x21;
+const PIC_SLAVE_COMMAND: u16 = 0xa0;
+const PIC_SLAVE_DATA: u16 = 0xa1;
+
+/// Remap the 8259 PICs above the CPU exception vectors and mask every IRQ.
+///
+/// Even when masked, a PIC can raise spurious IRQ7/IRQ15, which would otherwise
+/// be delivered as #DF or #GP.
+fn disable_pic() {
+    unsafe {
+        // ICW1: edge triggered, cascade, ICW4 needed
+        out8(PIC_MASTER_COMMAND, 0x11);
+        out8(PIC_SLAVE_COMMAND, 0x11);
+        // ICW2: vector offsets
+        out8(PIC_MASTER_DATA, vector::PIC_BASE);
+        out8(PIC_SLAVE_DATA, vector::PIC_BASE + 8);
+        // ICW3: slave on IRQ2
+        out8(PIC_MASTER_DATA, 1 << 2);
+        out8(PIC_SLAVE_DATA, 2);
+        // ICW4: 8086 mode
+        out8(PIC_MASTER_DATA, 0x01);
+        out8(PIC_SLAVE_DATA, 0x01);
+
+        out8(PIC_MASTER_DATA, 0xff);
+        out8(PIC_SLAVE_DATA, 0xff);
+    }
+}
+
+/// Local APIC register offsets in the xAPIC MMIO page
+pub mod register {
+    pub const ID: u32 = 0x020;
+    pub const VERSION: u32 = 0x030;
+    pub const TASK_PRIORITY: u32 = 0x080;
+    pub const END_OF_INTERRUPT: u32 = 0x0b0;
+    pub const SPURIOUS_VECTOR: u32 = 0x0f0;
+    pub const ERROR_STATUS: u32 = 0x280;
+    pub const INTERRUPT_COMMAND_LOW: u32 = 0x300;
+    pub const INTERRUPT_COMMAND_HIGH: u32 = 0x310;
+    pub const LVT_TIMER: u32 = 0x320;
+    pub const LVT_LINT0: u32 = 0x350;
+    pub const LVT_LINT1: u32 = 0x360;
+    pub const LVT_ERROR: u32 = 0x370;
+    pub const TIMER_INITIAL_COUNT: u32 = 0x380;
+    pub const TIMER_CURRENT_COUNT: u32 = 0x390;
+    pub const TIMER_DIVIDE_CONFIG: u32 = 0x3e0;
+}
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0xf_ffff_f000;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const SPURIOUS_VECTOR_ENABLE: u32 = 1 << 8;
+pub const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    Disabled,
+    XApic { base: usize },
+    X2Apic,
+}
+
+static mut MODE: Mode = Mode::Disabled;
+
+pub fn read_register(register: u32) -> u32 {
+    unsafe {
+        match MODE {
+            Mode::XApic { base } => ((base + register as usize) as *const u32).read_volatile(),
+            Mode::X2Apic => msr::read(X2APIC_MSR_BASE + (register >> 4)) as u32,
+            Mode::Disabled => panic!("Local APIC is not initialized"),
+        }
+    }
+}
+
+pub fn write_register(register: u32, value: u32) {
+    unsafe {
+        match MODE {
+            Mode::XApic { base } => ((base + register as usize) as *mut u32).write_volatile(value),
+            Mode::X2Apic => msr::write(X2APIC_MSR_BASE + (register >> 4), value as u64),
+            Mode::Disabled => panic!("Local APIC is not initialized"),
+        }
+    }
+}
+
+pub fn is_x2apic() -> bool {
+    matches!(unsafe { MODE }, Mode::X2Apic)
+}
+
+/// APIC ID of the running CPU
+pub fn id() -> u32 {
+    let id = read_register(register::ID);
+    if is_x2apic() {
+        id
+    } else {
+        id >> 24
+    }
+}
+
+/// Number of local vector table entries, taken from the version register
+pub fn lvt_count() -> u32 {
+    ((read_register(register::VERSION) >> 16) & 0xff) + 1
+}
+
+/// Signal the end of the current interrupt. Must not be called for spurious interrupts.
+pub fn end_of_interrupt() {
+    write_register(register::END_OF_INTERRUPT, 0);
+}
+
+extern "x86-interrupt" fn spurious_handler(_frame: InterruptFrame) {
+    // No EOI for spurious interrupts
+}
+
+extern "x86-interrupt" fn error_handler(_frame: InterruptFrame) {
+    // ESR latches errors; writing it moves them into the readable register
+    write_register(register::ERROR_STATUS, 0);
+    let status = read_register(register::ERROR_STATUS);
+    crate::println!("[ERROR] Local APIC error: ESR={:#x}", status);
+    end_of_interrupt();
+}
+
+/// Mask the legacy PIC and enable the Local APIC of the bootstrap processor.
+///
+/// x2APIC mode is used when the CPU supports it.
+pub fn init() {
+    disable_pic();
+
+    interrupt::set_handler(vector::SPURIOUS, spurious_handler);
+    interrupt::set_handler(vector::LOCAL_APIC_ERROR, error_handler);
+
+    unsafe {
+        let apic_base = msr::read(msr::IA32_APIC_BASE);
+        MODE = if cpu::info().features.x2apic {
+            Mode::X2Apic
+        } else {
+            Mode::XApic { base: (apic_base & APIC_BASE_ADDRESS_MASK) as usize }
+        };
+    }
+    enable_local_apic();
+}
+
+/// Enable the Local APIC of an application processor in the mode chosen by `init()`
+pub fn init_application_processor() {
+    enable_local_apic();
+}
+
+fn enable_local_apic() {
+    unsafe {
+        let apic_base = msr::read(msr::IA32_APIC_BASE);
+        // xAPIC must be enabled before switching to x2APIC
+        msr::write(msr::IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+        if is_x2apic() {
+            msr::write(msr::IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        }
+    }
+
+    write_register(register::SPURIOUS_VECTOR, SPURIOUS_VECTOR_ENABLE | vector::SPURIOUS as u32);
+
+    // LINT0 carried the PIC's ExtINT; LINT1 is wired to NMI on PC compatibles
+    write_register(register::LVT_LINT0, LVT_MASKED);
+    write_register(register::LVT_LINT1, LVT_DELIVERY_NMI);
+    write_register(register::LVT_TIMER, LVT_MASKED);
+
+    write_register(register::LVT_ERROR, vector::LOCAL_APIC_ERROR as u32);
+    // Clear errors collected so far (the register needs back to back writes)
+    write_register(register::ERROR_STATUS, 0);
+    write_register(register::ERROR_STATUS, 0);
+
+    write_register(register::TASK_PRIORITY, 0);
+    end_of_interrupt();
+}
+
+/// Send an inter-processor interrupt with the low ICR bits `command` to the
+/// CPU whose APIC ID is `destination`, and wait until it has been accepted
+fn send_ipi(destination: u32, command: u32) {
+    if is_x2apic() {
+        // One 64 bit MSR write; delivery status no longer exists
+        unsafe {
+            msr::write(X2APIC_MSR_BASE + (register::INTERRUPT_COMMAND_LOW >> 4), (destination as u64) << 32 | command as u64);
+        }
+        return;
+    }
+    write_register(register::INTERRUPT_COMMAND_HIGH, destination << 24);
+    // Writing the low half sends the interrupt
+    write_register(register::INTERRUPT_COMMAND_LOW, command);
+    while read_register(register::INTERRUPT_COMMAND_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset the CPU `destination` into its wait-for-SIPI state
+pub fn send_init(destination: u32) {
+    send_ipi(destination, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Start the CPU `destination` in real mode at `page` << 12 after `send_init()`
+pub fn send_startup(destination: u32, page: u8) {
+    send_ipi(destination, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
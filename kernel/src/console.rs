@@ -3,83 +3,131 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-use heapless::String;
+use core::fmt;
 
 use crate::graphics::{self, *};
+use crate::sync::SpinLock;
 
-mod font;
+pub mod font;
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+static CONSOLE: SpinLock<Option<Console>> = SpinLock::new(None);
+
+/// Create the console used by `print!`. Graphics must be initialized.
+pub fn init() {
+    let console = graphics::with_lock(Console::new);
+    *CONSOLE.lock() = Some(console);
+}
+
+pub fn _print(args: fmt::Arguments) {
+    use fmt::Write;
+    // The console draws into its layer, so the GUI lock comes first
+    graphics::with_lock(|| {
+        // Output before init() is dropped
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            console.write_fmt(args).unwrap();
+        }
+    });
+}
+
+/// Like `_print`, but drops the output instead of waiting if the console is in use
+#[cfg(feature = "lock-debug")]
+pub fn try_print(args: fmt::Arguments) {
+    use fmt::Write;
+    graphics::try_with_lock(|| {
+        if let Some(mut console) = CONSOLE.try_lock() {
+            if let Some(console) = console.as_mut() {
+                let _ = console.write_fmt(args);
+            }
+        }
+    });
+}
 
 const DEFAULT_LINE_SPACE: u32 = 16;
 const DEFAULT_WIDTH_BUFFER: u32 = 8;
 const DEFAULT_HEIGHT_BUFFER: u32 = 8;
+const FONT_WIDTH: u32 = 8;
 const MAX_LINE: usize = 30;
 const MAX_LINE_WIDTH: usize = 80;
 
-const DEFAULT_STRING: String::<MAX_LINE_WIDTH> = String::<MAX_LINE_WIDTH>::new();
+/// Key color of the console layer; the layers below show through it
+const BACKGROUND_COLOR: PixelColor = PixelColor { red: 0xff, green: 0x00, blue: 0xff };
 
-pub struct Console<'a> {
+pub struct Console {
+    layer: LayerId,
     cursor_x: usize,
     cursor_y: usize,
-    line_buffer: [String<MAX_LINE_WIDTH>; MAX_LINE],
-    frame_buffer_config: &'a FrameBufferConfig,
 }
 
-impl<'a> Console<'a> {
+impl Console {
 
-    pub fn new(fbc: &'a FrameBufferConfig) -> Self {
+    /// Create the console in its own transparent layer above the desktop
+    pub fn new() -> Self {
+        let width = 2 * DEFAULT_WIDTH_BUFFER + MAX_LINE_WIDTH as u32 * FONT_WIDTH;
+        let height = 2 * DEFAULT_HEIGHT_BUFFER + MAX_LINE as u32 * DEFAULT_LINE_SPACE;
+        let layers = graphics::layers();
+        let layer = layers.new_layer(width, height);
+        let console_layer = layers.layer(layer).unwrap();
+        console_layer.set_transparent_color(Some(BACKGROUND_COLOR));
+        let buffer = console_layer.buffer();
+        let bounds = buffer.bounds();
+        buffer.fill_rectangle(bounds, BACKGROUND_COLOR);
         Self {
+            layer,
             cursor_x: 0,
             cursor_y: 0,
-            line_buffer: [DEFAULT_STRING; MAX_LINE],
-            frame_buffer_config: fbc,
         }
     }
 
+    fn buffer(&self) -> &'static mut BackBuffer {
+        graphics::layers().layer(self.layer).expect("console layer is never removed").buffer()
+    }
+
     fn write_ascii_at(&self, x: u32, y: u32, c: char) {
 
         let font_data = font::get_font(c).expect("[ERROR] failed to get font");
+        let screen = self.buffer();
     
         for dy in 0..16 {
             for dx in 0..8 {
                 if (font_data[dy] << dx) & 0x80 > 0 {
-                    unsafe {write_pixel(x + dx as u32, y + dy as u32, basic_color::WHITE, self.frame_buffer_config);}
+                    screen.write_pixel(x + dx as u32, y + dy as u32, basic_color::WHITE);
                 }
             }
         }
+        screen.invalidate(Rect { x, y, width: FONT_WIDTH, height: DEFAULT_LINE_SPACE });
     }
 
+    /// Draw `s` into the console layer and show the result on screen
     pub fn write_string(&mut self, s: &str) {
         for c in s.chars() {
             if c == '\n' {
                 self.new_line();
-            } else {
-                self.write_ascii_at(DEFAULT_WIDTH_BUFFER + (self.cursor_x * 8) as u32, DEFAULT_HEIGHT_BUFFER + (self.cursor_y * 16) as u32, c);
-                self.line_buffer[self.cursor_y].push(c).unwrap();
+            } else if self.cursor_x < MAX_LINE_WIDTH {
+                self.write_ascii_at(DEFAULT_WIDTH_BUFFER + self.cursor_x as u32 * FONT_WIDTH, DEFAULT_HEIGHT_BUFFER + self.cursor_y as u32 * DEFAULT_LINE_SPACE, c);
                 self.cursor_x += 1;
             }
         }
+        graphics::flush();
     }
 
-    fn write_raw_string(&self, s: &str, mut x: usize, mut y: usize) {
-        for i in 0..MAX_LINE_WIDTH {
-            self.write_fill_ascii(DEFAULT_WIDTH_BUFFER + (i * 8) as u32, DEFAULT_HEIGHT_BUFFER + (y * 16) as u32);
-        }
-        for c in s.chars() {
-            self.write_ascii_at(DEFAULT_WIDTH_BUFFER + (x * 8) as u32, DEFAULT_HEIGHT_BUFFER + (y * 16) as u32, c);
-            x = x + 1;
-        }
-    }
-
-    fn write_fill_ascii(&self, x: u32, y: u32) {
-
-        let fill_font: [u8; 16] = [0b11111111; 16];
-    
-        for dy in 0..16 {
-            for dx in 0..8 {
-                if (fill_font[dy] << dx) & 0x80 > 0 {
-                    unsafe {write_pixel(x + dx as u32, y + dy as u32, basic_color::GRAY, self.frame_buffer_config);}
-                }
-            }
+    fn line_rect(line: usize) -> Rect {
+        Rect {
+            x: DEFAULT_WIDTH_BUFFER,
+            y: DEFAULT_HEIGHT_BUFFER + line as u32 * DEFAULT_LINE_SPACE,
+            width: MAX_LINE_WIDTH as u32 * FONT_WIDTH,
+            height: DEFAULT_LINE_SPACE,
         }
     }
 
@@ -87,19 +135,22 @@ impl<'a> Console<'a> {
         self.cursor_x = 0;
         self.cursor_y += 1;
         if self.cursor_y > (MAX_LINE - 1) as _ {
-            // graphics::fill_background(basic_color::BLACK, self.frame_buffer_config);
-            for i in 0..MAX_LINE  {
-                self.line_buffer[i].clear();
-                // self.line_buffer[i].push_str(self.line_buffer[i + 1].as_str()).unwrap();
-                if i + 1 == MAX_LINE {
-                    self.line_buffer[i] = DEFAULT_STRING;
-                } else {
-                    let s = self.line_buffer[i + 1].clone();
-                    self.line_buffer[i] = s;
-                }
-                self.write_raw_string(self.line_buffer[i].as_str(), 0, i);
-            }
+            // Scroll by moving the pixels in the layer instead of redrawing every line
+            let screen = self.buffer();
+            let text_area = Rect {
+                height: (MAX_LINE - 1) as u32 * DEFAULT_LINE_SPACE,
+                ..Self::line_rect(1)
+            };
+            screen.move_rect(DEFAULT_WIDTH_BUFFER, DEFAULT_HEIGHT_BUFFER, text_area);
+            screen.fill_rectangle(Self::line_rect(MAX_LINE - 1), BACKGROUND_COLOR);
             self.cursor_y = MAX_LINE - 1;
         }
     }
 }
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
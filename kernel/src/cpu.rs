@@ -0,0 +1,210 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Processor identification through CPUID.
+//!
+//! The leaves are decoded once, on first use, into `CpuInfo`. Every CPU in
+//! the system is assumed to report the same, so the bootstrap processor's
+//! answer is used everywhere. Check `info().features` before using an
+//! optional instruction or mode instead of calling CPUID again.
+
+use core::arch::x86_64::{CpuidResult, __cpuid, __cpuid_count};
+use core::fmt;
+
+use crate::sync::OnceCell;
+
+const EXTENDED_BASE: u32 = 0x8000_0000;
+
+/// Optional features the kernel may take different code paths for
+#[derive(Clone, Copy, Debug)]
+pub struct Features {
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    pub sse3: bool,
+    pub ssse3: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub xsave: bool,
+    pub avx: bool,
+    pub avx2: bool,
+    pub x2apic: bool,
+    /// The Local APIC timer has a TSC deadline mode
+    pub tsc_deadline: bool,
+    /// The TSC runs at a constant rate in every power state
+    pub invariant_tsc: bool,
+    pub huge_pages_1gib: bool,
+    /// No-execute page protection
+    pub nx: bool,
+    /// Supervisor mode execution prevention
+    pub smep: bool,
+    /// Supervisor mode access prevention
+    pub smap: bool,
+}
+
+impl Features {
+    fn names(&self) -> [(&'static str, bool); 17] {
+        [
+            ("fxsr", self.fxsr),
+            ("sse", self.sse),
+            ("sse2", self.sse2),
+            ("sse3", self.sse3),
+            ("ssse3", self.ssse3),
+            ("sse4.1", self.sse4_1),
+            ("sse4.2", self.sse4_2),
+            ("xsave", self.xsave),
+            ("avx", self.avx),
+            ("avx2", self.avx2),
+            ("x2apic", self.x2apic),
+            ("tsc-deadline", self.tsc_deadline),
+            ("invariant-tsc", self.invariant_tsc),
+            ("1gib-pages", self.huge_pages_1gib),
+            ("nx", self.nx),
+            ("smep", self.smep),
+            ("smap", self.smap),
+        ]
+    }
+}
+
+/// Supported features separated by spaces
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (name, _) in self.names().iter().filter(|(_, supported)| *supported) {
+            if !first {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    /// Zero padded, empty if the CPU has no brand string
+    brand: [u8; 48],
+    /// Family and model with the extended fields already folded in
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Features,
+}
+
+fn bit(value: u32, bit: u32) -> bool {
+    value & (1 << bit) != 0
+}
+
+fn ascii(bytes: &[u8]) -> &str {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..end]).unwrap_or("?").trim()
+}
+
+impl CpuInfo {
+    fn read() -> Self {
+        let cpuid = |leaf: u32| unsafe { __cpuid(leaf) };
+        let leaf0 = cpuid(0);
+        let max_leaf = leaf0.eax;
+        let max_extended_leaf = cpuid(EXTENDED_BASE).eax;
+        let leaf_or_zero = |leaf: u32| {
+            let max = if leaf >= EXTENDED_BASE { max_extended_leaf } else { max_leaf };
+            if leaf <= max {
+                cpuid(leaf)
+            } else {
+                CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 }
+            }
+        };
+
+        let mut vendor = [0; 12];
+        for (chunk, register) in vendor.chunks_mut(4).zip([leaf0.ebx, leaf0.edx, leaf0.ecx]) {
+            chunk.copy_from_slice(&register.to_le_bytes());
+        }
+
+        let mut brand = [0; 48];
+        for (index, leaf) in (EXTENDED_BASE + 2..=EXTENDED_BASE + 4).enumerate() {
+            let result = leaf_or_zero(leaf);
+            for (chunk, register) in brand[index * 16..].chunks_mut(4).zip([result.eax, result.ebx, result.ecx, result.edx]) {
+                chunk.copy_from_slice(&register.to_le_bytes());
+            }
+        }
+
+        let leaf1 = leaf_or_zero(1);
+        let base_family = leaf1.eax >> 8 & 0xf;
+        let base_model = leaf1.eax >> 4 & 0xf;
+        let family = if base_family == 0xf { base_family + (leaf1.eax >> 20 & 0xff) } else { base_family };
+        let model = if base_family == 0x6 || base_family == 0xf {
+            (leaf1.eax >> 16 & 0xf) << 4 | base_model
+        } else {
+            base_model
+        };
+
+        let leaf7 = if max_leaf >= 7 {
+            unsafe { __cpuid_count(7, 0) }
+        } else {
+            CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 }
+        };
+        let extended1 = leaf_or_zero(EXTENDED_BASE + 1);
+        let power = leaf_or_zero(EXTENDED_BASE + 7);
+
+        let features = Features {
+            fxsr: bit(leaf1.edx, 24),
+            sse: bit(leaf1.edx, 25),
+            sse2: bit(leaf1.edx, 26),
+            sse3: bit(leaf1.ecx, 0),
+            ssse3: bit(leaf1.ecx, 9),
+            sse4_1: bit(leaf1.ecx, 19),
+            sse4_2: bit(leaf1.ecx, 20),
+            xsave: bit(leaf1.ecx, 26),
+            avx: bit(leaf1.ecx, 28),
+            avx2: bit(leaf7.ebx, 5),
+            x2apic: bit(leaf1.ecx, 21),
+            tsc_deadline: bit(leaf1.ecx, 24),
+            invariant_tsc: bit(power.edx, 8),
+            huge_pages_1gib: bit(extended1.edx, 26),
+            nx: bit(extended1.edx, 20),
+            smep: bit(leaf7.ebx, 7),
+            smap: bit(leaf7.ebx, 20),
+        };
+
+        Self {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: leaf1.eax & 0xf,
+            features,
+        }
+    }
+
+    /// e.g. "GenuineIntel" or "AuthenticAMD"
+    pub fn vendor(&self) -> &str {
+        ascii(&self.vendor)
+    }
+
+    pub fn brand(&self) -> &str {
+        ascii(&self.brand)
+    }
+}
+
+static INFO: OnceCell<CpuInfo> = OnceCell::new();
+
+pub fn info() -> &'static CpuInfo {
+    INFO.get_or_init(CpuInfo::read)
+}
+
+/// Print the identification and supported features of the processor
+pub fn print_info() {
+    let info = info();
+    println!(
+        "CPU: {} family {:#x} model {:#x} stepping {}: {}",
+        info.vendor(),
+        info.family,
+        info.model,
+        info.stepping,
+        info.brand()
+    );
+    println!("CPU features: {}", info.features);
+}
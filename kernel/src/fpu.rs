@@ -0,0 +1,215 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! x87, SSE and AVX register state.
+//!
+//! The kernel itself is still compiled without SIMD (see the target spec), so
+//! only code that uses SIMD explicitly, once `init()` has enabled it, touches
+//! these registers. Every task has its own `ExtendedState`, saved and
+//! restored eagerly on each task switch. Eager switching keeps tasks that
+//! move between CPUs simple, at the cost of a save and restore even for
+//! tasks that never use the registers.
+//!
+//! Interrupt handlers have no extended state of their own: they run on top
+//! of whichever task was interrupted. SIMD code that can be reached from an
+//! interrupt handler must therefore save and restore the XMM registers it
+//! uses, as `graphics::blit` does.
+//!
+//! XSAVE is used when the CPU has it, which also covers the AVX registers;
+//! otherwise FXSAVE handles x87 and SSE.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::cpu;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR0_NE: u64 = 1 << 5;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// State components in XCR0
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const FXSAVE_SIZE: usize = 512;
+/// Defaults after FNINIT: all exceptions masked, round to nearest
+const DEFAULT_FCW: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// XCR0 value when XSAVE is used, 0 for FXSAVE
+static XSAVE_MASK: AtomicU64 = AtomicU64::new(0);
+static STATE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// SIMD instructions can be used; true on every CPU once this is set
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+fn uses_xsave() -> bool {
+    XSAVE_MASK.load(Ordering::Relaxed) != 0
+}
+
+/// Size of the save area of one task in bytes
+pub fn state_size() -> usize {
+    STATE_SIZE.load(Ordering::Relaxed)
+}
+
+/// Turn on SSE, and XSAVE with AVX if the CPU has them, on the running CPU
+fn enable_on_this_cpu() {
+    let xsave_mask = XSAVE_MASK.load(Ordering::Relaxed);
+    unsafe {
+        let mut cr0: u64;
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        cr0 = cr0 & !(CR0_EM | CR0_TS) | CR0_MP | CR0_NE;
+        asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+
+        let mut cr4: u64;
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+        if xsave_mask != 0 {
+            cr4 |= CR4_OSXSAVE;
+        }
+        asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+
+        if xsave_mask != 0 {
+            asm!(
+                "xsetbv",
+                in("ecx") 0,
+                in("eax") xsave_mask as u32,
+                in("edx") (xsave_mask >> 32) as u32,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        let mxcsr = DEFAULT_MXCSR;
+        asm!("fninit", "ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, preserves_flags));
+    }
+}
+
+/// Enable SIMD on the bootstrap processor and choose the save method for all
+/// CPUs. Must run before `task::init()`, which sizes the save areas.
+pub fn init() {
+    let features = cpu::info().features;
+    if !(features.fxsr && features.sse && features.sse2) {
+        return;
+    }
+    if features.xsave {
+        let mut mask = XCR0_X87 | XCR0_SSE;
+        if features.avx {
+            mask |= XCR0_AVX;
+        }
+        XSAVE_MASK.store(mask, Ordering::Relaxed);
+    }
+    enable_on_this_cpu();
+    let size = if uses_xsave() {
+        // EBX of leaf 0xd is the size needed for the components enabled in XCR0
+        unsafe { __cpuid_count(0xd, 0).ebx as usize }
+    } else {
+        FXSAVE_SIZE
+    };
+    STATE_SIZE.store(size, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Enable SIMD on an application processor the same way as on the bootstrap processor
+pub fn init_application_processor() {
+    if is_enabled() {
+        enable_on_this_cpu();
+    }
+}
+
+/// Print how extended state is switched
+pub fn print_info() {
+    if !is_enabled() {
+        println!("FPU: SIMD is not available");
+        return;
+    }
+    let mask = XSAVE_MASK.load(Ordering::Relaxed);
+    let method = if mask == 0 { "FXSAVE" } else { "XSAVE" };
+    let avx = if mask & XCR0_AVX != 0 { " AVX" } else { "" };
+    println!("FPU: {} of x87 SSE{}, {} bytes per task", method, avx, state_size());
+}
+
+/// XSAVE needs 64 byte alignment, FXSAVE 16
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct Block([u8; 64]);
+
+/// Saved registers of one task
+pub struct ExtendedState {
+    /// Empty if SIMD is not enabled
+    area: Vec<Block>,
+}
+
+impl ExtendedState {
+    /// Registers as left by FNINIT, with all SIMD registers zero
+    pub fn new() -> Self {
+        if !is_enabled() {
+            return Self { area: Vec::new() };
+        }
+        let blocks = (state_size() + 63) / 64;
+        let mut area = vec![Block([0; 64]); blocks];
+        // An all zero XSAVE header puts every component into its initial
+        // state, but the control words are loaded from the legacy area anyway
+        let legacy = &mut area[0].0;
+        legacy[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        legacy[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        Self { area }
+    }
+
+    /// Store the running CPU's registers here
+    pub fn save(&mut self) {
+        if self.area.is_empty() {
+            return;
+        }
+        let area = self.area.as_mut_ptr();
+        let mask = XSAVE_MASK.load(Ordering::Relaxed);
+        unsafe {
+            if mask != 0 {
+                asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(nostack, preserves_flags),
+                );
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Load the registers saved here into the running CPU
+    pub fn restore(&self) {
+        if self.area.is_empty() {
+            return;
+        }
+        let area = self.area.as_ptr();
+        let mask = XSAVE_MASK.load(Ordering::Relaxed);
+        unsafe {
+            if mask != 0 {
+                asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(readonly, nostack, preserves_flags),
+                );
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(readonly, nostack, preserves_flags));
+            }
+        }
+    }
+}
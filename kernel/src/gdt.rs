@@ -0,0 +1,123 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Global descriptor table and task state segment.
+//!
+//! Every CPU loads its own GDT because the TSS descriptor is marked busy by
+//! `ltr` and each TSS names the stacks of one CPU. The selectors are the same
+//! on all CPUs, so the IDT can be shared.
+
+use core::arch::asm;
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const TSS_SELECTOR: u16 = 0x18;
+
+/// 64 bit code, DPL 0
+const KERNEL_CODE_DESCRIPTOR: u64 = 0x00af_9a00_0000_ffff;
+/// Writable data, DPL 0; only the present bit matters in long mode
+const KERNEL_DATA_DESCRIPTOR: u64 = 0x00cf_9200_0000_ffff;
+/// Present, available 64 bit TSS
+const TSS_TYPE: u64 = 0x89;
+
+/// 64 bit task state segment. Only used for the stacks it points to.
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    reserved0: u32,
+    /// Stacks loaded on a privilege change to ring 0..=2
+    pub rsp: [u64; 3],
+    reserved1: u64,
+    /// Stacks selected by the IST field of an interrupt descriptor
+    pub ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> Self {
+        Self {
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            // Beyond the limit: no I/O permission bitmap
+            iomap_base: core::mem::size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+/// Null, kernel code, kernel data and the two halves of the TSS descriptor
+#[repr(C, align(16))]
+pub struct Gdt {
+    entries: [u64; 5],
+    tss: TaskStateSegment,
+}
+
+impl Gdt {
+    pub const fn new() -> Self {
+        Self {
+            entries: [0; 5],
+            tss: TaskStateSegment::new(),
+        }
+    }
+
+    /// Load this table on the running CPU, reload every segment register and
+    /// the task register. `kernel_stack` becomes RSP0 of the TSS.
+    ///
+    /// # Safety
+    /// The table must stay at this address for as long as the CPU runs.
+    pub unsafe fn load(&'static mut self, kernel_stack: u64) {
+        self.tss.rsp[0] = kernel_stack;
+        let tss = &self.tss as *const TaskStateSegment as u64;
+        let limit = (core::mem::size_of::<TaskStateSegment>() - 1) as u64;
+        self.entries = [
+            0,
+            KERNEL_CODE_DESCRIPTOR,
+            KERNEL_DATA_DESCRIPTOR,
+            (limit & 0xffff)
+                | (tss & 0x00ff_ffff) << 16
+                | TSS_TYPE << 40
+                | (limit >> 16 & 0xf) << 48
+                | (tss >> 24 & 0xff) << 56,
+            tss >> 32,
+        ];
+
+        let pointer = DescriptorTablePointer {
+            limit: (core::mem::size_of::<[u64; 5]>() - 1) as u16,
+            base: self.entries.as_ptr() as u64,
+        };
+        asm!(
+            "lgdt [{pointer}]",
+            // CS can only be changed by a far transfer
+            "push {code}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov ss, {data:x}",
+            // Loading FS and GS clears their bases; set them afterwards
+            "mov fs, {null:x}",
+            "mov gs, {null:x}",
+            "ltr {tss:x}",
+            pointer = in(reg) &pointer,
+            code = in(reg) KERNEL_CODE_SELECTOR as u64,
+            data = in(reg) KERNEL_DATA_SELECTOR as u64,
+            null = in(reg) 0u64,
+            tss = in(reg) TSS_SELECTOR as u64,
+            tmp = out(reg) _,
+        );
+    }
+}
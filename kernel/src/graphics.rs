@@ -3,6 +3,21 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
+mod back_buffer;
+mod blit;
+mod canvas;
+pub mod cursor;
+mod layer;
+mod pixel_writer;
+
+pub use back_buffer::BackBuffer;
+pub use canvas::{Bitmap, Canvas, DrawTarget, FONT_HEIGHT, FONT_WIDTH};
+pub use layer::{LayerId, LayerManager};
+
+use pixel_writer::pixel_writer;
+
+use crate::sync::SpinLock;
+
 #[repr(C)]
 pub struct FrameBufferConfig {
     frame_buffer: *mut u64,
@@ -10,6 +25,7 @@ pub struct FrameBufferConfig {
     horizontal_resolution: u32,
     vertical_resolution: u32,
     pixel_format: PixelFormat,
+    pixel_bitmask: PixelBitmask,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -19,43 +35,146 @@ pub struct PixelColor {
     pub blue: u8,
 }
 
+// Values are produced by the bootloader
+#[allow(dead_code)]
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum PixelFormat {
     RGB = 0,
     BGR = 1,
+    BitMask = 2,
 }
 
-pub unsafe fn write_pixel(x: u32, y: u32, color: PixelColor, frame_config: &FrameBufferConfig) {
-    let p = (frame_config.frame_buffer as *const u8)
-        .add((4 * (frame_config.pixels_per_scan_line * y + x)) as usize) as *mut u8;
-    if frame_config.pixel_format == PixelFormat::RGB {
-        p.write_volatile(color.red.into());
-        p.add(1).write_volatile(color.green.into());
-        p.add(2).write_volatile(color.blue.into());
-    } else if frame_config.pixel_format == PixelFormat::BGR {
-        p.write_volatile(color.blue.into());
-        p.add(1).write_volatile(color.green.into());
-        p.add(2).write_volatile(color.red.into());
-    }
+/// Channel masks reported by GOP for `PixelFormat::BitMask`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PixelBitmask {
+    red_mask: u32,
+    green_mask: u32,
+    blue_mask: u32,
+    reserved_mask: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
 }
 
-pub fn fill_background(color: PixelColor, frame_config: &FrameBufferConfig) {
-    for x in 0..frame_config.horizontal_resolution {
-        for y in 0..frame_config.vertical_resolution {
-            unsafe {write_pixel(x, y, color, frame_config);}
+impl Rect {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> u32 {
+        self.x.saturating_add(self.width)
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.y.saturating_add(self.height)
+    }
+
+    pub fn contains(&self, other: &Rect) -> bool {
+        self.x <= other.x && self.y <= other.y && other.right() <= self.right() && other.bottom() <= self.bottom()
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.right() && other.x < self.right() && self.y < other.bottom() && other.y < self.bottom()
+    }
+
+    /// Area covered by both, if any
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        if !self.intersects(other) {
+            return None;
         }
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        Some(Rect {
+            x,
+            y,
+            width: self.right().min(other.right()) - x,
+            height: self.bottom().min(other.bottom()) - y,
+        })
     }
-}
 
-pub fn fill_rectangle(x: u32, y: u32, width: u32, height: u32, color: PixelColor, frame_config: &FrameBufferConfig) {
-    for dx in 0..width {
-        for dy in 0..height {
-            unsafe {write_pixel(x + dx, y + dy, color, frame_config);}
+    /// Smallest rectangle containing both
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: self.right().max(other.right()) - x,
+            height: self.bottom().max(other.bottom()) - y,
         }
     }
 }
 
+/// Serializes everything that touches the layers, the screen or the windows
+static GUI_LOCK: SpinLock<()> = SpinLock::new(());
+
+/// Run `f` holding the GUI lock, which the accessors below assume. Not reentrant.
+pub fn with_lock<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = GUI_LOCK.lock();
+    f()
+}
+
+/// Like `with_lock`, but gives up instead of waiting if the lock is taken
+#[cfg(feature = "lock-debug")]
+pub fn try_with_lock<R>(f: impl FnOnce() -> R) -> Option<R> {
+    let _guard = GUI_LOCK.try_lock()?;
+    Some(f())
+}
+
+/// Composition of all layers, copied to the frame buffer by `flush()`
+static mut SCREEN: Option<BackBuffer> = None;
+static mut LAYERS: Option<LayerManager> = None;
+static mut DESKTOP: LayerId = 0;
+
+/// Set up the pixel writer, the screen and the desktop layer at the bottom.
+/// The kernel heap must be ready.
+pub fn init(frame_config: &FrameBufferConfig) {
+    pixel_writer::init(frame_config);
+    let (width, height) = (frame_config.horizontal_resolution, frame_config.vertical_resolution);
+    let mut layers = LayerManager::new();
+    unsafe {
+        DESKTOP = layers.new_layer(width, height);
+        SCREEN = Some(BackBuffer::new(width, height));
+        LAYERS = Some(layers);
+    }
+}
+
+/// The composed screen. Draw into a layer instead; this is overwritten by `flush()`.
+pub fn screen() -> &'static mut BackBuffer {
+    unsafe {
+        (*core::ptr::addr_of_mut!(SCREEN)).as_mut().expect("graphics::init has not been called")
+    }
+}
+
+pub fn layers() -> &'static mut LayerManager {
+    unsafe {
+        (*core::ptr::addr_of_mut!(LAYERS)).as_mut().expect("graphics::init has not been called")
+    }
+}
+
+/// Back buffer of the bottom layer, which covers the whole screen
+pub fn desktop() -> &'static mut BackBuffer {
+    layers().layer(unsafe { DESKTOP }).expect("desktop layer is never removed").buffer()
+}
+
+pub fn fill_background(color: PixelColor) {
+    let desktop = desktop();
+    let rect = desktop.bounds();
+    desktop.fill_rectangle(rect, color);
+}
+
+/// Compose the layers that changed and show the result
+pub fn flush() {
+    layers().update(screen());
+}
+
 #[allow(dead_code)]
 pub mod basic_color {
     use super::PixelColor;
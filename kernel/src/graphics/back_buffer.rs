@@ -0,0 +1,216 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{pixel_writer, DrawTarget, PixelColor, Rect};
+
+const MAX_DIRTY_RECTS: usize = 16;
+
+/// Set of screen areas changed since the last flush.
+///
+/// Overlapping rectangles are merged. When the list is full everything is
+/// collapsed into its bounding box, which may redraw a little more than needed
+/// but never misses a change.
+pub struct DirtyRegion {
+    rects: heapless::Vec<Rect, MAX_DIRTY_RECTS>,
+}
+
+impl DirtyRegion {
+    pub const fn new() -> Self {
+        Self { rects: heapless::Vec::new() }
+    }
+
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() || self.rects.iter().any(|r| r.contains(&rect)) {
+            return;
+        }
+
+        let mut merged = rect;
+        let mut i = 0;
+        while i < self.rects.len() {
+            if self.rects[i].intersects(&merged) {
+                merged = merged.union(&self.rects.swap_remove(i));
+                // The grown rectangle may now overlap ones we already passed
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+
+        if let Err(merged) = self.rects.push(merged) {
+            let bounds = self.rects.iter().fold(merged, |acc, r| acc.union(r));
+            self.rects.clear();
+            let _ = self.rects.push(bounds);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rect> {
+        self.rects.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+}
+
+/// Off-screen copy of the frame buffer in normal RAM.
+///
+/// Pixels are stored already encoded for the frame buffer's pixel format, so
+/// `flush()` is a plain copy of the dirty rectangles.
+pub struct BackBuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<u32>,
+    dirty: DirtyRegion,
+}
+
+impl BackBuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (width * height) as usize],
+            dirty: DirtyRegion::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect { x: 0, y: 0, width: self.width, height: self.height }
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    /// Write a single pixel without marking it dirty. Pixels outside the buffer are ignored.
+    ///
+    /// Callers drawing many pixels should `invalidate()` the covered area once.
+    pub fn write_pixel(&mut self, x: u32, y: u32, color: PixelColor) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let index = self.index(x, y);
+        self.pixels[index] = pixel_writer().encode(color);
+    }
+
+    pub fn fill_rectangle(&mut self, rect: Rect, color: PixelColor) {
+        let rect = match rect.intersection(&self.bounds()) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let value = pixel_writer().encode(color);
+        for y in rect.y..rect.bottom() {
+            let start = self.index(rect.x, y);
+            self.pixels[start..start + rect.width as usize].fill(value);
+        }
+        self.invalidate(rect);
+    }
+
+    /// Copy the pixels in `src` so that its top left corner lands on (x, y).
+    ///
+    /// Only the part that is inside the buffer both before and after the move is copied.
+    pub fn move_rect(&mut self, x: u32, y: u32, src: Rect) {
+        let bounds = self.bounds();
+        let src_clipped = match src.intersection(&bounds) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let dst = Rect {
+            x: x.saturating_add(src_clipped.x - src.x),
+            y: y.saturating_add(src_clipped.y - src.y),
+            width: src_clipped.width,
+            height: src_clipped.height,
+        };
+        let dst = match dst.intersection(&bounds) {
+            Some(rect) => rect,
+            None => return,
+        };
+        // dst can only have shrunk on its right and bottom edges
+        let (src_x, src_y) = (src_clipped.x, src_clipped.y);
+
+        let copy_row = |buffer: &mut Self, dy: u32| {
+            let from = buffer.index(src_x, src_y + dy);
+            let to = buffer.index(dst.x, dst.y + dy);
+            buffer.pixels.copy_within(from..from + dst.width as usize, to);
+        };
+        if dst.y <= src_y {
+            (0..dst.height).for_each(|dy| copy_row(self, dy));
+        } else {
+            (0..dst.height).rev().for_each(|dy| copy_row(self, dy));
+        }
+        self.invalidate(dst);
+    }
+
+    pub fn invalidate(&mut self, rect: Rect) {
+        if let Some(rect) = rect.intersection(&self.bounds()) {
+            self.dirty.add(rect);
+        }
+    }
+
+    /// Hand the areas changed since the last call to a compositor
+    pub fn take_dirty(&mut self) -> DirtyRegion {
+        core::mem::replace(&mut self.dirty, DirtyRegion::new())
+    }
+
+    /// Encoded pixels of one row, starting at (x, y)
+    pub fn row(&self, x: u32, y: u32, width: u32) -> &[u32] {
+        let start = self.index(x, y);
+        &self.pixels[start..start + width as usize]
+    }
+
+    pub fn row_mut(&mut self, x: u32, y: u32, width: u32) -> &mut [u32] {
+        let start = self.index(x, y);
+        &mut self.pixels[start..start + width as usize]
+    }
+
+    /// Copy the changed areas to the frame buffer.
+    ///
+    /// Dirty rectangles are clipped in `invalidate()`, so the frame buffer is
+    /// never written outside the screen.
+    pub fn flush(&mut self) {
+        let writer = pixel_writer();
+        for rect in self.dirty.iter() {
+            for y in rect.y..rect.bottom() {
+                let start = self.index(rect.x, y);
+                unsafe {
+                    writer.copy_encoded_span(rect.x, y, &self.pixels[start..start + rect.width as usize]);
+                }
+            }
+        }
+        self.dirty.clear();
+    }
+}
+
+impl DrawTarget for BackBuffer {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn read_pixel(&self, x: u32, y: u32) -> PixelColor {
+        pixel_writer().decode(self.pixels[self.index(x, y)])
+    }
+
+    fn write_pixel(&mut self, x: u32, y: u32, color: PixelColor) {
+        BackBuffer::write_pixel(self, x, y, color);
+    }
+
+    fn invalidate(&mut self, rect: Rect) {
+        BackBuffer::invalidate(self, rect);
+    }
+}
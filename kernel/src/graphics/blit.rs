@@ -0,0 +1,70 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Pixel row copies for the compositor, four pixels at a time with SSE2 once
+//! `fpu::init()` has enabled it.
+
+use core::arch::asm;
+
+use crate::fpu;
+
+/// Copy `src` over `dst`, leaving pixels where `src` is `key` untouched
+pub fn copy_keyed(dst: &mut [u32], src: &[u32], key: u32) {
+    let len = dst.len().min(src.len());
+    let mut done = 0;
+    if fpu::is_enabled() {
+        let chunks = len / 4;
+        unsafe { copy_keyed_sse2(dst.as_mut_ptr(), src.as_ptr(), chunks, key) };
+        done = chunks * 4;
+    }
+    for (dst, &src) in dst[done..len].iter_mut().zip(&src[done..len]) {
+        if src != key {
+            *dst = src;
+        }
+    }
+}
+
+/// Keyed copy of `chunks` groups of four pixels.
+///
+/// Composition also happens in interrupt handlers (printing an error from
+/// one, for example), which have no extended state of their own, so the XMM
+/// registers used here are saved and restored around the copy.
+unsafe fn copy_keyed_sse2(dst: *mut u32, src: *const u32, chunks: usize, key: u32) {
+    if chunks == 0 {
+        return;
+    }
+    asm!(
+        "sub rsp, 64",
+        "movdqu [rsp], xmm0",
+        "movdqu [rsp + 16], xmm1",
+        "movdqu [rsp + 32], xmm2",
+        "movdqu [rsp + 48], xmm3",
+        "movd xmm2, {key:e}",
+        "pshufd xmm2, xmm2, 0",
+        "2:",
+        "movdqu xmm0, [{src}]",
+        "movdqu xmm1, [{dst}]",
+        // xmm3: all ones where the source pixel is transparent
+        "movdqa xmm3, xmm0",
+        "pcmpeqd xmm3, xmm2",
+        "pand xmm1, xmm3",
+        "pandn xmm3, xmm0",
+        "por xmm3, xmm1",
+        "movdqu [{dst}], xmm3",
+        "add {src}, 16",
+        "add {dst}, 16",
+        "dec {count}",
+        "jnz 2b",
+        "movdqu xmm0, [rsp]",
+        "movdqu xmm1, [rsp + 16]",
+        "movdqu xmm2, [rsp + 32]",
+        "movdqu xmm3, [rsp + 48]",
+        "add rsp, 64",
+        src = inout(reg) src => _,
+        dst = inout(reg) dst => _,
+        count = inout(reg) chunks => _,
+        key = in(reg) key,
+    );
+}
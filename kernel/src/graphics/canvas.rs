@@ -0,0 +1,322 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use super::{PixelColor, Rect};
+use crate::console::font;
+
+/// Size of a glyph drawn by `Canvas::draw_char`
+pub const FONT_WIDTH: u32 = 8;
+pub const FONT_HEIGHT: u32 = 16;
+
+/// Surface that a `Canvas` can draw on
+pub trait DrawTarget {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    /// (x, y) is always inside the target
+    fn read_pixel(&self, x: u32, y: u32) -> PixelColor;
+    /// (x, y) is always inside the target
+    fn write_pixel(&mut self, x: u32, y: u32, color: PixelColor);
+    /// Called once per primitive with the area that was touched
+    fn invalidate(&mut self, rect: Rect);
+
+    fn bounds(&self) -> Rect {
+        Rect { x: 0, y: 0, width: self.width(), height: self.height() }
+    }
+}
+
+/// Image with 0xAARRGGBB pixels, stored row by row
+#[derive(Clone, Copy)]
+pub struct Bitmap<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: &'a [u32],
+    /// Pixels with exactly this value are skipped, regardless of their alpha
+    pub transparent_color: Option<u32>,
+}
+
+impl PixelColor {
+    pub const fn from_argb(argb: u32) -> Self {
+        Self {
+            red: (argb >> 16) as u8,
+            green: (argb >> 8) as u8,
+            blue: argb as u8,
+        }
+    }
+
+    /// Mix `self` over `background`; alpha 255 is opaque
+    pub fn blend(&self, background: PixelColor, alpha: u8) -> PixelColor {
+        let mix = |fg: u8, bg: u8| -> u8 {
+            let alpha = alpha as u32;
+            ((fg as u32 * alpha + bg as u32 * (255 - alpha) + 127) / 255) as u8
+        };
+        PixelColor {
+            red: mix(self.red, background.red),
+            green: mix(self.green, background.green),
+            blue: mix(self.blue, background.blue),
+        }
+    }
+}
+
+/// 2D drawing primitives on top of a `DrawTarget`.
+///
+/// Coordinates are signed so shapes may extend past the edges; everything is
+/// clipped to the target and to the clip rectangle, if one is set.
+pub struct Canvas<'a, T: DrawTarget> {
+    target: &'a mut T,
+    clip: Rect,
+}
+
+impl<'a, T: DrawTarget> Canvas<'a, T> {
+    pub fn new(target: &'a mut T) -> Self {
+        let clip = target.bounds();
+        Self { target, clip }
+    }
+
+    /// Restrict drawing to `rect` (within the target) until `reset_clip()`
+    pub fn set_clip(&mut self, rect: Rect) {
+        self.clip = rect.intersection(&self.target.bounds()).unwrap_or(Rect { x: 0, y: 0, width: 0, height: 0 });
+    }
+
+    pub fn reset_clip(&mut self) {
+        self.clip = self.target.bounds();
+    }
+
+    fn inside(&self, x: i64, y: i64) -> bool {
+        x >= self.clip.x as i64 && y >= self.clip.y as i64 && x < self.clip.right() as i64 && y < self.clip.bottom() as i64
+    }
+
+    /// Intersection of the clip area with the given rectangle in signed coordinates
+    fn clip_rect(&self, x: i32, y: i32, width: u32, height: u32) -> Option<Rect> {
+        self.clip_span(x as i64, y as i64, x as i64 + width as i64, y as i64 + height as i64)
+    }
+
+    /// Like `clip_rect()`, with the edges given as exclusive 64 bit coordinates
+    fn clip_span(&self, left: i64, top: i64, right: i64, bottom: i64) -> Option<Rect> {
+        let left = left.max(self.clip.x as i64);
+        let top = top.max(self.clip.y as i64);
+        let right = right.min(self.clip.right() as i64);
+        let bottom = bottom.min(self.clip.bottom() as i64);
+        if left >= right || top >= bottom {
+            return None;
+        }
+        Some(Rect {
+            x: left as u32,
+            y: top as u32,
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+        })
+    }
+
+    fn plot(&mut self, x: i64, y: i64, color: PixelColor) {
+        if self.inside(x, y) {
+            self.target.write_pixel(x as u32, y as u32, color);
+        }
+    }
+
+    fn plot_blended(&mut self, x: i64, y: i64, color: PixelColor, alpha: u8) {
+        if !self.inside(x, y) {
+            return;
+        }
+        let (x, y) = (x as u32, y as u32);
+        let blended = match alpha {
+            255 => color,
+            0 => return,
+            _ => color.blend(self.target.read_pixel(x, y), alpha),
+        };
+        self.target.write_pixel(x, y, blended);
+    }
+
+    /// Mark the bounding box of a primitive as changed
+    fn touch(&mut self, x: i32, y: i32, width: u32, height: u32) {
+        if let Some(rect) = self.clip_rect(x, y, width, height) {
+            self.target.invalidate(rect);
+        }
+    }
+
+    pub fn draw_pixel(&mut self, x: i32, y: i32, color: PixelColor) {
+        self.plot(x.into(), y.into(), color);
+        self.touch(x, y, 1, 1);
+    }
+
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: PixelColor, alpha: u8) {
+        self.plot_blended(x.into(), y.into(), color, alpha);
+        self.touch(x, y, 1, 1);
+    }
+
+    /// Bresenham line including both end points
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: PixelColor) {
+        let (left, right) = (x0.min(x1), x0.max(x1));
+        let (top, bottom) = (y0.min(y1), y0.max(y1));
+        let width = (right as i64 - left as i64 + 1) as u32;
+        let height = (bottom as i64 - top as i64 + 1) as u32;
+        if self.clip_rect(left, top, width, height).is_none() {
+            return;
+        }
+
+        let dx = (x1 as i64 - x0 as i64).abs();
+        let dy = -(y1 as i64 - y0 as i64).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            self.plot(x.into(), y.into(), color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+        self.touch(left, top, width, height);
+    }
+
+    pub fn fill_rectangle(&mut self, x: i32, y: i32, width: u32, height: u32, color: PixelColor) {
+        if let Some(rect) = self.clip_rect(x, y, width, height) {
+            for py in rect.y..rect.bottom() {
+                for px in rect.x..rect.right() {
+                    self.target.write_pixel(px, py, color);
+                }
+            }
+            self.target.invalidate(rect);
+        }
+    }
+
+    /// One pixel wide outline
+    pub fn draw_rectangle(&mut self, x: i32, y: i32, width: u32, height: u32, color: PixelColor) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let right = (x as i64 + width as i64 - 1) as i32;
+        let bottom = (y as i64 + height as i64 - 1) as i32;
+        self.fill_rectangle(x, y, width, 1, color);
+        self.fill_rectangle(x, bottom, width, 1, color);
+        self.fill_rectangle(x, y, 1, height, color);
+        self.fill_rectangle(right, y, 1, height, color);
+    }
+
+    /// Midpoint circle outline
+    pub fn draw_circle(&mut self, center_x: i32, center_y: i32, radius: u32, color: PixelColor) {
+        let (cx, cy) = (center_x as i64, center_y as i64);
+        let r = radius as i64;
+        if self.clip_span(cx - r, cy - r, cx + r + 1, cy + r + 1).is_none() {
+            return;
+        }
+        let (mut x, mut y) = (r, 0);
+        let mut error = 1 - x;
+        while x >= y {
+            // `plot()` skips the points outside the clip area
+            for (px, py) in [(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)] {
+                self.plot(cx + px, cy + py, color);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+        self.touch_circle(center_x, center_y, radius);
+    }
+
+    pub fn fill_circle(&mut self, center_x: i32, center_y: i32, radius: u32, color: PixelColor) {
+        /// Largest x with x * x <= n
+        fn isqrt(n: u64) -> u64 {
+            if n < 2 {
+                return n;
+            }
+            // Newton's method from a power of two at or above the root
+            let mut x = 1u64 << ((64 - n.leading_zeros() + 1) / 2);
+            loop {
+                let next = (x + n / x) / 2;
+                if next >= x {
+                    return x;
+                }
+                x = next;
+            }
+        }
+
+        let (center_x, center_y) = (center_x as i64, center_y as i64);
+        // Squares of u32 values only fit in 64 bits unsigned
+        let radius_squared = radius as u64 * radius as u64 + radius as u64;
+        let radius = radius as i64;
+        // Rows outside the clip rectangle are skipped rather than walked through
+        let top = (center_y - radius).max(self.clip.y as i64);
+        let bottom = (center_y + radius + 1).min(self.clip.bottom() as i64);
+        for y in top..bottom {
+            let dy = (y - center_y).unsigned_abs();
+            // Half width of the row, i.e. the widest dx with dx^2 + dy^2 <= r^2 + r.
+            // This matches the outline drawn by `draw_circle`.
+            let half = isqrt(radius_squared - dy * dy) as i64;
+            if let Some(rect) = self.clip_span(center_x - half, y, center_x + half + 1, y + 1) {
+                for px in rect.x..rect.right() {
+                    self.target.write_pixel(px, rect.y, color);
+                }
+            }
+        }
+        self.touch_circle(center_x as i32, center_y as i32, radius as u32);
+    }
+
+    fn touch_circle(&mut self, center_x: i32, center_y: i32, radius: u32) {
+        let (x, y, radius) = (center_x as i64, center_y as i64, radius as i64);
+        if let Some(rect) = self.clip_span(x - radius, y - radius, x + radius + 1, y + radius + 1) {
+            self.target.invalidate(rect);
+        }
+    }
+
+    /// Draw `bitmap` with its top left corner at (x, y), blending by each pixel's alpha
+    pub fn draw_bitmap(&mut self, x: i32, y: i32, bitmap: &Bitmap) {
+        if bitmap.pixels.len() < bitmap.width as usize * bitmap.height as usize {
+            return;
+        }
+        let area = match self.clip_rect(x, y, bitmap.width, bitmap.height) {
+            Some(area) => area,
+            None => return,
+        };
+        for py in area.y..area.bottom() {
+            let row = (py as i64 - y as i64) as usize * bitmap.width as usize;
+            for px in area.x..area.right() {
+                let argb = bitmap.pixels[row + (px as i64 - x as i64) as usize];
+                if bitmap.transparent_color == Some(argb) {
+                    continue;
+                }
+                self.plot_blended(px as i64, py as i64, PixelColor::from_argb(argb), (argb >> 24) as u8);
+            }
+        }
+        self.target.invalidate(area);
+    }
+
+    /// Draw `c` from the kernel font with its top left corner at (x, y); the background is left as is
+    pub fn draw_char(&mut self, x: i32, y: i32, c: char, color: PixelColor) {
+        let glyph = font::get_font(c).or_else(|_| font::get_font('?')).unwrap_or([0; 16]);
+        for (dy, bits) in glyph.iter().enumerate() {
+            for dx in 0..FONT_WIDTH {
+                if (bits << dx) & 0x80 != 0 {
+                    self.plot(x as i64 + dx as i64, y as i64 + dy as i64, color);
+                }
+            }
+        }
+        self.touch(x, y, FONT_WIDTH, FONT_HEIGHT);
+    }
+
+    /// Draw `s` on one line starting at (x, y)
+    pub fn draw_string(&mut self, x: i32, y: i32, s: &str, color: PixelColor) {
+        for (i, c) in s.chars().enumerate() {
+            let left = x as i64 + i as i64 * FONT_WIDTH as i64;
+            // The rest of the string is clipped anyway
+            if left >= self.clip.right() as i64 || left > i32::MAX as i64 {
+                break;
+            }
+            self.draw_char(left as i32, y, c, color);
+        }
+    }
+}
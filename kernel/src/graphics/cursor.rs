@@ -0,0 +1,132 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Mouse pointer, kept in a topmost layer so it is composited above everything else
+
+use alloc::vec::Vec;
+
+use super::{layers, with_lock, Bitmap, Canvas, LayerId, PixelColor};
+
+const WIDTH: u32 = 15;
+const HEIGHT: u32 = 24;
+
+#[rustfmt::skip]
+const SHAPE: [&[u8; WIDTH as usize]; HEIGHT as usize] = [
+    b"@              ",
+    b"@@             ",
+    b"@.@            ",
+    b"@..@           ",
+    b"@...@          ",
+    b"@....@         ",
+    b"@.....@        ",
+    b"@......@       ",
+    b"@.......@      ",
+    b"@........@     ",
+    b"@.........@    ",
+    b"@..........@   ",
+    b"@...........@  ",
+    b"@............@ ",
+    b"@......@@@@@@@@",
+    b"@......@       ",
+    b"@....@@.@      ",
+    b"@...@ @.@      ",
+    b"@..@   @.@     ",
+    b"@.@    @.@     ",
+    b"@@      @.@    ",
+    b"@       @.@    ",
+    b"         @.@   ",
+    b"         @@@   ",
+];
+
+/// ARGB value marking pixels of the shape that are not drawn
+pub const TRANSPARENT_COLOR: u32 = 0x00ff_00ff;
+const EDGE_COLOR: u32 = 0xff00_0000;
+const FILL_COLOR: u32 = 0xffff_ffff;
+
+/// The pointer shape as ARGB pixels, `TRANSPARENT_COLOR` outside the arrow
+pub fn shape() -> (u32, u32, Vec<u32>) {
+    let pixels = SHAPE
+        .iter()
+        .flat_map(|row| row.iter())
+        .map(|&c| match c {
+            b'@' => EDGE_COLOR,
+            b'.' => FILL_COLOR,
+            _ => TRANSPARENT_COLOR,
+        })
+        .collect();
+    (WIDTH, HEIGHT, pixels)
+}
+
+struct Cursor {
+    layer: LayerId,
+    x: u32,
+    y: u32,
+}
+
+static mut CURSOR: Option<Cursor> = None;
+
+fn cursor() -> Option<&'static mut Cursor> {
+    unsafe { (*core::ptr::addr_of_mut!(CURSOR)).as_mut() }
+}
+
+fn screen_size() -> (u32, u32) {
+    let screen = super::screen();
+    (screen.width(), screen.height())
+}
+
+/// Show the pointer with its tip at (x, y)
+pub fn init(x: u32, y: u32) {
+    with_lock(|| create_layer(x, y));
+}
+
+fn create_layer(x: u32, y: u32) {
+    let layers = layers();
+    let id = layers.new_layer(WIDTH, HEIGHT);
+    let layer = layers.layer(id).unwrap();
+    let transparent = PixelColor::from_argb(TRANSPARENT_COLOR);
+    layer.set_transparent_color(Some(transparent));
+    let buffer = layer.buffer();
+    let bounds = buffer.bounds();
+    buffer.fill_rectangle(bounds, transparent);
+    let (width, height, pixels) = shape();
+    let bitmap = Bitmap {
+        width,
+        height,
+        pixels: &pixels,
+        transparent_color: Some(TRANSPARENT_COLOR),
+    };
+    Canvas::new(buffer).draw_bitmap(0, 0, &bitmap);
+    layers.set_topmost(id, true);
+
+    let (screen_width, screen_height) = screen_size();
+    let (x, y) = (x.min(screen_width - 1), y.min(screen_height - 1));
+    layers.move_to(id, x as i32, y as i32);
+    unsafe {
+        CURSOR = Some(Cursor { layer: id, x, y });
+    }
+}
+
+/// Move the tip by a relative amount, keeping it on screen
+pub fn move_by(dx: i32, dy: i32) {
+    with_lock(|| {
+        if let Some(cursor) = cursor() {
+            let (width, height) = screen_size();
+            let x = (cursor.x as i32 + dx).clamp(0, width as i32 - 1) as u32;
+            let y = (cursor.y as i32 + dy).clamp(0, height as i32 - 1) as u32;
+            if (x, y) == (cursor.x, cursor.y) {
+                return;
+            }
+            cursor.x = x;
+            cursor.y = y;
+            layers().move_to(cursor.layer, x as i32, y as i32);
+            super::flush();
+        }
+    })
+}
+
+/// Tip position; the caller must hold the GUI lock
+pub fn position() -> Option<(u32, u32)> {
+    cursor().map(|cursor| (cursor.x, cursor.y))
+}
@@ -0,0 +1,230 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Layers and the compositor that stacks them onto the screen.
+//!
+//! Every layer draws into its own back buffer. `LayerManager::update()` looks
+//! at what changed (drawing, moves, z-order changes) and recomposes only those
+//! screen areas, bottom layer first.
+
+use alloc::vec::Vec;
+
+use super::back_buffer::DirtyRegion;
+use super::blit;
+use super::pixel_writer::pixel_writer;
+use super::{BackBuffer, PixelColor, Rect};
+
+pub type LayerId = usize;
+
+pub struct Layer {
+    x: i32,
+    y: i32,
+    buffer: BackBuffer,
+    /// Encoded value of pixels that let lower layers show through
+    transparent_color: Option<u32>,
+    /// Stays above every layer without this flag, like the mouse pointer
+    topmost: bool,
+}
+
+impl Layer {
+    /// Draw here; changes appear on the next `graphics::flush()`
+    pub fn buffer(&mut self) -> &mut BackBuffer {
+        &mut self.buffer
+    }
+
+    pub fn position(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    pub fn set_transparent_color(&mut self, color: Option<PixelColor>) {
+        self.transparent_color = color.map(|color| pixel_writer().encode(color));
+    }
+
+    /// Translate a rectangle in layer coordinates to screen coordinates,
+    /// keeping only the part inside `screen`
+    fn to_screen(&self, rect: Rect, screen: &Rect) -> Option<Rect> {
+        let left = (self.x as i64 + rect.x as i64).max(0);
+        let top = (self.y as i64 + rect.y as i64).max(0);
+        let right = (self.x as i64 + rect.right() as i64).min(screen.right() as i64);
+        let bottom = (self.y as i64 + rect.bottom() as i64).min(screen.bottom() as i64);
+        if left >= right || top >= bottom {
+            return None;
+        }
+        Some(Rect {
+            x: left as u32,
+            y: top as u32,
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+        })
+    }
+
+    fn screen_rect(&self, screen: &Rect) -> Option<Rect> {
+        self.to_screen(self.buffer.bounds(), screen)
+    }
+
+    /// Copy the part of this layer inside `area` (screen coordinates) onto `screen`
+    fn draw_to(&self, screen: &mut BackBuffer, area: &Rect) {
+        let rect = match self.screen_rect(&screen.bounds()).and_then(|rect| rect.intersection(area)) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let src_x = (rect.x as i64 - self.x as i64) as u32;
+        let src_y = (rect.y as i64 - self.y as i64) as u32;
+        for row in 0..rect.height {
+            let src = self.buffer.row(src_x, src_y + row, rect.width);
+            let dst = screen.row_mut(rect.x, rect.y + row, rect.width);
+            match self.transparent_color {
+                None => dst.copy_from_slice(src),
+                Some(key) => blit::copy_keyed(dst, src, key),
+            }
+        }
+    }
+}
+
+pub struct LayerManager {
+    /// Indexed by `LayerId`; removed layers leave `None` behind
+    layers: Vec<Option<Layer>>,
+    /// Bottom to top
+    z_order: Vec<LayerId>,
+    /// Screen areas to recompose regardless of layer contents
+    damaged: DirtyRegion,
+}
+
+impl LayerManager {
+    pub fn new() -> Self {
+        Self {
+            layers: Vec::new(),
+            z_order: Vec::new(),
+            damaged: DirtyRegion::new(),
+        }
+    }
+
+    /// Create a layer at (0, 0), above all other non-topmost layers
+    pub fn new_layer(&mut self, width: u32, height: u32) -> LayerId {
+        let id = self.layers.len();
+        let mut buffer = BackBuffer::new(width, height);
+        let bounds = buffer.bounds();
+        buffer.invalidate(bounds);
+        self.layers.push(Some(Layer {
+            x: 0,
+            y: 0,
+            buffer,
+            transparent_color: None,
+            topmost: false,
+        }));
+        self.insert_in_z_order(id);
+        id
+    }
+
+    pub fn layer(&mut self, id: LayerId) -> Option<&mut Layer> {
+        self.layers.get_mut(id).and_then(Option::as_mut)
+    }
+
+    fn insert_in_z_order(&mut self, id: LayerId) {
+        let topmost = self.layers[id].as_ref().map_or(false, |layer| layer.topmost);
+        let layers = &self.layers;
+        let index = if topmost {
+            self.z_order.len()
+        } else {
+            self.z_order
+                .iter()
+                .position(|&other| layers[other].as_ref().map_or(false, |layer| layer.topmost))
+                .unwrap_or(self.z_order.len())
+        };
+        self.z_order.insert(index, id);
+    }
+
+    /// Mark the whole area of `id` on screen for recomposition
+    fn damage(&mut self, id: LayerId) {
+        if let Some(layer) = self.layers.get(id).and_then(Option::as_ref) {
+            let bounds = layer.buffer.bounds();
+            // Clipping against the screen happens when composing
+            let screen = Rect { x: 0, y: 0, width: u32::MAX, height: u32::MAX };
+            if let Some(rect) = layer.to_screen(bounds, &screen) {
+                self.damaged.add(rect);
+            }
+        }
+    }
+
+    pub fn move_to(&mut self, id: LayerId, x: i32, y: i32) {
+        self.damage(id);
+        if let Some(layer) = self.layer(id) {
+            layer.x = x;
+            layer.y = y;
+        }
+        self.damage(id);
+    }
+
+    pub fn move_by(&mut self, id: LayerId, dx: i32, dy: i32) {
+        if let Some((x, y)) = self.layer(id).map(|layer| layer.position()) {
+            self.move_to(id, x + dx, y + dy);
+        }
+    }
+
+    /// Keep `id` above every ordinary layer
+    pub fn set_topmost(&mut self, id: LayerId, topmost: bool) {
+        if let Some(layer) = self.layer(id) {
+            layer.topmost = topmost;
+            self.raise(id);
+        }
+    }
+
+    /// Bring `id` to the top of its group (ordinary or topmost)
+    pub fn raise(&mut self, id: LayerId) {
+        if let Some(index) = self.z_order.iter().position(|&other| other == id) {
+            self.z_order.remove(index);
+            self.insert_in_z_order(id);
+            self.damage(id);
+        }
+    }
+
+    pub fn remove(&mut self, id: LayerId) {
+        self.damage(id);
+        self.z_order.retain(|&other| other != id);
+        if let Some(layer) = self.layers.get_mut(id) {
+            *layer = None;
+        }
+    }
+
+    /// Topmost layer covering (x, y) on screen that `filter` accepts
+    pub fn find_at(&self, x: u32, y: u32, mut filter: impl FnMut(LayerId) -> bool) -> Option<LayerId> {
+        let point = Rect { x, y, width: 1, height: 1 };
+        let screen = Rect { x: 0, y: 0, width: u32::MAX, height: u32::MAX };
+        self.z_order.iter().rev().copied().find(|&id| {
+            let covers = self.layers[id]
+                .as_ref()
+                .and_then(|layer| layer.screen_rect(&screen))
+                .map_or(false, |rect| rect.contains(&point));
+            covers && filter(id)
+        })
+    }
+
+    /// Recompose everything that changed into `screen`, then show it
+    pub fn update(&mut self, screen: &mut BackBuffer) {
+        let screen_bounds = screen.bounds();
+        let mut damaged = core::mem::replace(&mut self.damaged, DirtyRegion::new());
+        for layer in self.layers.iter_mut().flatten() {
+            for rect in layer.buffer.take_dirty().iter() {
+                if let Some(rect) = layer.to_screen(*rect, &screen_bounds) {
+                    damaged.add(rect);
+                }
+            }
+        }
+
+        for area in damaged.iter() {
+            let area = match area.intersection(&screen_bounds) {
+                Some(area) => area,
+                None => continue,
+            };
+            for &id in &self.z_order {
+                if let Some(layer) = self.layers[id].as_ref() {
+                    layer.draw_to(screen, &area);
+                }
+            }
+            screen.invalidate(area);
+        }
+        screen.flush();
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use super::{FrameBufferConfig, PixelBitmask, PixelColor, PixelFormat};
+
+/// Writes pixels in the native format of the frame buffer.
+///
+/// One implementation is chosen per pixel format when the kernel starts, so
+/// drawing code never has to look at `PixelFormat` again.
+pub trait PixelWriter {
+    /// Convert a color into the value stored in the frame buffer, in the low bytes of a `u32`
+    fn encode(&self, color: PixelColor) -> u32;
+
+    /// Inverse of `encode()`, used when blending with what is already drawn
+    fn decode(&self, value: u32) -> PixelColor;
+
+    fn frame_buffer(&self) -> &FrameBuffer;
+
+    /// Copy pixels that were already converted with `encode()`
+    unsafe fn copy_encoded_span(&self, x: u32, y: u32, pixels: &[u32]) {
+        let frame_buffer = self.frame_buffer();
+        debug_assert!(x as usize + pixels.len() <= frame_buffer.horizontal_resolution as usize);
+        let p = frame_buffer.pixel_at(x, y);
+        if frame_buffer.bytes_per_pixel == 4 {
+            core::ptr::copy_nonoverlapping(pixels.as_ptr(), p as *mut u32, pixels.len());
+        } else {
+            for (i, &value) in pixels.iter().enumerate() {
+                frame_buffer.store(p.add(i * frame_buffer.bytes_per_pixel), value);
+            }
+        }
+    }
+}
+
+/// Base address and stride of a frame buffer.
+///
+/// Pixels are 4 bytes wide in the RGB/BGR formats and 1 to 4 bytes in bitmask
+/// modes. Nothing here checks bounds in release builds; the only caller
+/// outside this module is `BackBuffer::flush()`, which works on clipped
+/// rectangles.
+pub struct FrameBuffer {
+    base: *mut u8,
+    bytes_per_pixel: usize,
+    bytes_per_scan_line: usize,
+    horizontal_resolution: u32,
+    vertical_resolution: u32,
+}
+
+impl FrameBuffer {
+    fn new(frame_config: &FrameBufferConfig, bytes_per_pixel: usize) -> Self {
+        Self {
+            base: frame_config.frame_buffer as *mut u8,
+            bytes_per_pixel,
+            bytes_per_scan_line: bytes_per_pixel * frame_config.pixels_per_scan_line as usize,
+            horizontal_resolution: frame_config.horizontal_resolution,
+            vertical_resolution: frame_config.vertical_resolution,
+        }
+    }
+
+    pub unsafe fn pixel_at(&self, x: u32, y: u32) -> *mut u8 {
+        debug_assert!(x < self.horizontal_resolution && y < self.vertical_resolution);
+        self.base.add(self.bytes_per_scan_line * y as usize + self.bytes_per_pixel * x as usize)
+    }
+
+    /// Write the low `bytes_per_pixel` bytes of an encoded pixel to `p`
+    unsafe fn store(&self, p: *mut u8, value: u32) {
+        if self.bytes_per_pixel == 4 {
+            (p as *mut u32).write_volatile(value);
+        } else {
+            for (i, byte) in value.to_le_bytes().iter().take(self.bytes_per_pixel).enumerate() {
+                p.add(i).write_volatile(*byte);
+            }
+        }
+    }
+}
+
+pub struct RgbWriter {
+    frame_buffer: FrameBuffer,
+}
+
+impl PixelWriter for RgbWriter {
+    fn encode(&self, color: PixelColor) -> u32 {
+        u32::from_le_bytes([color.red, color.green, color.blue, 0])
+    }
+
+    fn decode(&self, value: u32) -> PixelColor {
+        let [red, green, blue, _] = value.to_le_bytes();
+        PixelColor { red, green, blue }
+    }
+
+    fn frame_buffer(&self) -> &FrameBuffer {
+        &self.frame_buffer
+    }
+}
+
+pub struct BgrWriter {
+    frame_buffer: FrameBuffer,
+}
+
+impl PixelWriter for BgrWriter {
+    fn encode(&self, color: PixelColor) -> u32 {
+        u32::from_le_bytes([color.blue, color.green, color.red, 0])
+    }
+
+    fn decode(&self, value: u32) -> PixelColor {
+        let [blue, green, red, _] = value.to_le_bytes();
+        PixelColor { red, green, blue }
+    }
+
+    fn frame_buffer(&self) -> &FrameBuffer {
+        &self.frame_buffer
+    }
+}
+
+/// Position and width of one color channel inside a pixel
+#[derive(Clone, Copy, Debug)]
+struct Channel {
+    shift: u32,
+    width: u32,
+}
+
+impl Channel {
+    fn from_mask(mask: u32) -> Self {
+        if mask == 0 {
+            return Self { shift: 0, width: 0 };
+        }
+        let shift = mask.trailing_zeros();
+        Self {
+            shift,
+            width: 32 - (mask >> shift).leading_zeros(),
+        }
+    }
+
+    /// Scale an 8 bit intensity to the channel width and move it into place
+    fn encode(&self, value: u8) -> u32 {
+        let value = value as u32;
+        let scaled = if self.width >= 8 {
+            value << (self.width - 8)
+        } else {
+            value >> (8 - self.width)
+        };
+        scaled << self.shift
+    }
+
+    fn decode(&self, value: u32) -> u8 {
+        if self.width == 0 {
+            return 0;
+        }
+        let raw = (value >> self.shift) & (u32::MAX >> (32 - self.width));
+        if self.width >= 8 {
+            (raw >> (self.width - 8)) as u8
+        } else {
+            // Scale up so that full intensity maps to 255
+            ((raw * 255) / ((1 << self.width) - 1)) as u8
+        }
+    }
+}
+
+/// Writer for `PixelFormat::BitMask`, with the channel shifts precomputed
+pub struct BitmaskWriter {
+    frame_buffer: FrameBuffer,
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+}
+
+impl BitmaskWriter {
+    fn new(frame_config: &FrameBufferConfig) -> Self {
+        let bitmask: &PixelBitmask = &frame_config.pixel_bitmask;
+        let all = bitmask.red_mask | bitmask.green_mask | bitmask.blue_mask | bitmask.reserved_mask;
+        // UEFI spec: the pixel size is given by the highest bit set in any of the masks
+        let bits = (32 - all.leading_zeros()).max(1);
+        Self {
+            frame_buffer: FrameBuffer::new(frame_config, ((bits + 7) / 8) as usize),
+            red: Channel::from_mask(bitmask.red_mask),
+            green: Channel::from_mask(bitmask.green_mask),
+            blue: Channel::from_mask(bitmask.blue_mask),
+        }
+    }
+}
+
+impl PixelWriter for BitmaskWriter {
+    fn encode(&self, color: PixelColor) -> u32 {
+        self.red.encode(color.red) | self.green.encode(color.green) | self.blue.encode(color.blue)
+    }
+
+    fn decode(&self, value: u32) -> PixelColor {
+        PixelColor {
+            red: self.red.decode(value),
+            green: self.green.decode(value),
+            blue: self.blue.decode(value),
+        }
+    }
+
+    fn frame_buffer(&self) -> &FrameBuffer {
+        &self.frame_buffer
+    }
+}
+
+enum WriterStorage {
+    Uninit,
+    Rgb(RgbWriter),
+    Bgr(BgrWriter),
+    Bitmask(BitmaskWriter),
+}
+
+static mut PIXEL_WRITER: WriterStorage = WriterStorage::Uninit;
+
+/// Select the writer matching the frame buffer's pixel format
+pub(super) fn init(frame_config: &FrameBufferConfig) {
+    let writer = match frame_config.pixel_format {
+        PixelFormat::RGB => WriterStorage::Rgb(RgbWriter { frame_buffer: FrameBuffer::new(frame_config, 4) }),
+        PixelFormat::BGR => WriterStorage::Bgr(BgrWriter { frame_buffer: FrameBuffer::new(frame_config, 4) }),
+        PixelFormat::BitMask => WriterStorage::Bitmask(BitmaskWriter::new(frame_config)),
+    };
+    unsafe {
+        PIXEL_WRITER = writer;
+    }
+}
+
+pub fn pixel_writer() -> &'static dyn PixelWriter {
+    unsafe {
+        match &*core::ptr::addr_of!(PIXEL_WRITER) {
+            WriterStorage::Rgb(writer) => writer,
+            WriterStorage::Bgr(writer) => writer,
+            WriterStorage::Bitmask(writer) => writer,
+            WriterStorage::Uninit => panic!("graphics::init has not been called"),
+        }
+    }
+}
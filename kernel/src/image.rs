@@ -0,0 +1,102 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use alloc::vec::Vec;
+
+use crate::graphics::Bitmap;
+
+mod bmp;
+mod qoi;
+
+/// Images larger than this are rejected before allocating anything
+const MAX_PIXELS: usize = 8192 * 8192;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownFormat,
+    Truncated,
+    Unsupported,
+    TooLarge,
+}
+
+/// Decoded image with 0xAARRGGBB pixels, stored row by row from the top
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u32>,
+}
+
+impl Image {
+    fn new(width: u32, height: u32) -> Result<Self, DecodeError> {
+        let count = (width as usize)
+            .checked_mul(height as usize)
+            .filter(|count| *count <= MAX_PIXELS)
+            .ok_or(DecodeError::TooLarge)?;
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(count).map_err(|_| DecodeError::TooLarge)?;
+        Ok(Self { width, height, pixels })
+    }
+
+    pub fn as_bitmap(&self) -> Bitmap<'_> {
+        Bitmap {
+            width: self.width,
+            height: self.height,
+            pixels: &self.pixels,
+            transparent_color: None,
+        }
+    }
+}
+
+/// Decode a BMP or QOI image, detected from its signature
+pub fn decode(data: &[u8]) -> Result<Image, DecodeError> {
+    if data.starts_with(b"BM") {
+        bmp::decode(data)
+    } else if data.starts_with(b"qoif") {
+        qoi::decode(data)
+    } else {
+        Err(DecodeError::UnknownFormat)
+    }
+}
+
+/// Little helper for reading fixed size fields without panicking on short input
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn at(data: &'a [u8], position: usize) -> Self {
+        Self { data, position }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let end = self.position.checked_add(N).ok_or(DecodeError::Truncated)?;
+        let bytes = self.data.get(self.position..end).ok_or(DecodeError::Truncated)?;
+        self.position = end;
+        let mut result = [0; N];
+        result.copy_from_slice(bytes);
+        Ok(result)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16_le(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+
+    fn u32_le(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u32_be(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.bytes()?))
+    }
+}
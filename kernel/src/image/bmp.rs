@@ -0,0 +1,111 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Uncompressed Windows bitmaps: 1/4/8 bit palettes, 24 bit BGR and 16/32 bit
+//! with BI_RGB or BI_BITFIELDS.
+
+use super::{DecodeError, Image, Reader};
+
+const FILE_HEADER_SIZE: usize = 14;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+/// Extract one channel with `mask` and scale it to 8 bits
+fn channel(value: u32, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = (mask >> shift) as u64;
+    // In 64 bits, since a malformed mask may be up to 32 bits wide
+    ((((value & mask) >> shift) as u64 * 255) / max) as u32
+}
+
+pub fn decode(data: &[u8]) -> Result<Image, DecodeError> {
+    let mut header = Reader::at(data, 10);
+    let pixel_offset = header.u32_le()? as usize;
+    let info_size = header.u32_le()?;
+    let width = header.u32_le()? as i32;
+    let height = header.u32_le()? as i32;
+    let _planes = header.u16_le()?;
+    let bits_per_pixel = header.u16_le()?;
+    let compression = header.u32_le()?;
+    let _image_size = header.u32_le()?;
+    let _resolution = header.bytes::<8>()?;
+    let colors_used = header.u32_le()?;
+
+    if info_size < 40 || width <= 0 || height == 0 {
+        return Err(DecodeError::Unsupported);
+    }
+    // Positive height means the rows are stored bottom-up
+    let bottom_up = height > 0;
+    let width = width as u32;
+    let height = height.unsigned_abs();
+
+    // Masks follow a 40 byte header for BI_BITFIELDS, and are part of V4/V5 headers
+    let (red_mask, green_mask, blue_mask, alpha_mask) = match (compression, bits_per_pixel) {
+        (BI_RGB, 16) => (0x7c00, 0x03e0, 0x001f, 0),
+        // The fourth byte is usually zero rather than a real alpha channel
+        (BI_RGB, 32) => (0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0),
+        (BI_RGB, _) => (0, 0, 0, 0),
+        (BI_BITFIELDS, 16) | (BI_BITFIELDS, 32) => {
+            let mut masks = Reader::at(data, FILE_HEADER_SIZE + 40);
+            let red = masks.u32_le()?;
+            let green = masks.u32_le()?;
+            let blue = masks.u32_le()?;
+            let alpha = if info_size >= 56 { masks.u32_le()? } else { 0 };
+            (red, green, blue, alpha)
+        }
+        _ => return Err(DecodeError::Unsupported),
+    };
+
+    let palette_offset = FILE_HEADER_SIZE + info_size as usize;
+    let palette_size = match (colors_used, bits_per_pixel) {
+        (0, 1) | (0, 4) | (0, 8) => 1 << bits_per_pixel,
+        (n, 1) | (n, 4) | (n, 8) => n.min(256) as usize,
+        (_, 16) | (_, 24) | (_, 32) => 0,
+        _ => return Err(DecodeError::Unsupported),
+    };
+    let palette_entry = |index: usize| -> Result<u32, DecodeError> {
+        if index >= palette_size {
+            return Err(DecodeError::Truncated);
+        }
+        let [blue, green, red, _] = Reader::at(data, palette_offset + 4 * index).bytes::<4>()?;
+        Ok(0xff00_0000 | u32::from_be_bytes([0, red, green, blue]))
+    };
+
+    let mut image = Image::new(width, height)?;
+    // Rows are padded to a multiple of 4 bytes
+    let row_size = ((width as usize * bits_per_pixel as usize + 31) / 32) * 4;
+
+    for y in 0..height as usize {
+        let row_index = if bottom_up { height as usize - 1 - y } else { y };
+        let row_start = pixel_offset + row_index * row_size;
+        let row = data.get(row_start..row_start + row_size).ok_or(DecodeError::Truncated)?;
+        let mut reader = Reader::new(row);
+        for x in 0..width as usize {
+            let argb = match bits_per_pixel {
+                1 | 4 | 8 => {
+                    let bits = bits_per_pixel as usize;
+                    let bit_offset = x * bits;
+                    let byte = row[bit_offset / 8];
+                    let shift = 8 - bits - bit_offset % 8;
+                    palette_entry(((byte >> shift) as usize) & ((1 << bits) - 1))?
+                }
+                24 => {
+                    let [blue, green, red] = reader.bytes::<3>()?;
+                    u32::from_be_bytes([0xff, red, green, blue])
+                }
+                _ => {
+                    let value = if bits_per_pixel == 16 { reader.u16_le()? as u32 } else { reader.u32_le()? };
+                    let alpha = if alpha_mask == 0 { 255 } else { channel(value, alpha_mask) };
+                    alpha << 24 | channel(value, red_mask) << 16 | channel(value, green_mask) << 8 | channel(value, blue_mask)
+                }
+            };
+            image.pixels.push(argb);
+        }
+    }
+    Ok(image)
+}
@@ -0,0 +1,91 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! "Quite OK Image" format, see https://qoiformat.org/qoi-specification.pdf
+
+use super::{DecodeError, Image, Reader};
+
+const HEADER_SIZE: usize = 14;
+
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const MASK_2: u8 = 0xc0;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Rgba {
+    red: u8,
+    green: u8,
+    blue: u8,
+    alpha: u8,
+}
+
+impl Rgba {
+    fn hash(&self) -> usize {
+        (self.red as usize * 3 + self.green as usize * 5 + self.blue as usize * 7 + self.alpha as usize * 11) % 64
+    }
+
+    fn argb(&self) -> u32 {
+        u32::from_be_bytes([self.alpha, self.red, self.green, self.blue])
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Image, DecodeError> {
+    let mut reader = Reader::at(data, 4);
+    let width = reader.u32_be()?;
+    let height = reader.u32_be()?;
+    let channels = reader.u8()?;
+    let _colorspace = reader.u8()?;
+    if width == 0 || height == 0 || !(channels == 3 || channels == 4) {
+        return Err(DecodeError::Unsupported);
+    }
+
+    let mut image = Image::new(width, height)?;
+    let pixel_count = width as usize * height as usize;
+    let mut reader = Reader::at(data, HEADER_SIZE);
+    let mut index = [Rgba { red: 0, green: 0, blue: 0, alpha: 0 }; 64];
+    let mut pixel = Rgba { red: 0, green: 0, blue: 0, alpha: 255 };
+    let mut run = 0;
+
+    while image.pixels.len() < pixel_count {
+        if run > 0 {
+            run -= 1;
+        } else {
+            let op = reader.u8()?;
+            match op {
+                OP_RGB => {
+                    let [red, green, blue] = reader.bytes::<3>()?;
+                    pixel = Rgba { red, green, blue, ..pixel };
+                }
+                OP_RGBA => {
+                    let [red, green, blue, alpha] = reader.bytes::<4>()?;
+                    pixel = Rgba { red, green, blue, alpha };
+                }
+                _ => match op & MASK_2 {
+                    OP_INDEX => pixel = index[op as usize],
+                    OP_DIFF => {
+                        pixel.red = pixel.red.wrapping_add(((op >> 4) & 0x03).wrapping_sub(2));
+                        pixel.green = pixel.green.wrapping_add(((op >> 2) & 0x03).wrapping_sub(2));
+                        pixel.blue = pixel.blue.wrapping_add((op & 0x03).wrapping_sub(2));
+                    }
+                    OP_LUMA => {
+                        let second = reader.u8()?;
+                        let green_diff = (op & 0x3f).wrapping_sub(32);
+                        pixel.red = pixel.red.wrapping_add(green_diff.wrapping_sub(8).wrapping_add((second >> 4) & 0x0f));
+                        pixel.green = pixel.green.wrapping_add(green_diff);
+                        pixel.blue = pixel.blue.wrapping_add(green_diff.wrapping_sub(8).wrapping_add(second & 0x0f));
+                    }
+                    // QOI_OP_RUN (0xc0): the current pixel plus `run` more copies
+                    _ => run = op & 0x3f,
+                },
+            }
+            index[pixel.hash()] = pixel;
+        }
+        image.pixels.push(pixel.argb());
+    }
+    Ok(image)
+}
@@ -0,0 +1,116 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Keyboard and mouse events shared by every input device driver
+//!
+//! Key codes are USB HID usage IDs (keyboard page) regardless of the device
+//! that produced them. Drivers call `push_event()`, and the events reach the
+//! task chosen with `set_receiver()` as messages.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::message::{self, Message};
+use crate::task::TaskId;
+
+mod keymap;
+
+pub use keymap::to_char;
+
+/// Modifier keys, laid out like the first byte of a HID boot keyboard report
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers(pub u8);
+
+impl Modifiers {
+    /// Modifier bit of a key code between `KEYCODE_LEFT_CONTROL` and right GUI
+    pub fn bit_of(keycode: u8) -> Option<u8> {
+        match keycode {
+            KEYCODE_LEFT_CONTROL..=0xe7 => Some(1 << (keycode - KEYCODE_LEFT_CONTROL)),
+            _ => None,
+        }
+    }
+
+    /// Apply a press or release of `keycode`
+    pub fn update(&mut self, keycode: u8, pressed: bool) {
+        if let Some(bit) = Self::bit_of(keycode) {
+            if pressed {
+                self.0 |= bit;
+            } else {
+                self.0 &= !bit;
+            }
+        }
+    }
+
+    pub const LEFT_CONTROL: u8 = 1 << 0;
+    pub const LEFT_SHIFT: u8 = 1 << 1;
+    pub const LEFT_ALT: u8 = 1 << 2;
+    pub const LEFT_GUI: u8 = 1 << 3;
+    pub const RIGHT_CONTROL: u8 = 1 << 4;
+    pub const RIGHT_SHIFT: u8 = 1 << 5;
+    pub const RIGHT_ALT: u8 = 1 << 6;
+    pub const RIGHT_GUI: u8 = 1 << 7;
+
+    pub fn shift(&self) -> bool {
+        self.0 & (Self::LEFT_SHIFT | Self::RIGHT_SHIFT) != 0
+    }
+
+    pub fn control(&self) -> bool {
+        self.0 & (Self::LEFT_CONTROL | Self::RIGHT_CONTROL) != 0
+    }
+
+    pub fn alt(&self) -> bool {
+        self.0 & (Self::LEFT_ALT | Self::RIGHT_ALT) != 0
+    }
+}
+
+/// Usage ID of the left control key; the eight modifiers follow in `Modifiers` bit order
+pub const KEYCODE_LEFT_CONTROL: u8 = 0xe0;
+
+pub mod mouse_button {
+    pub const LEFT: u8 = 1 << 0;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum InputEvent {
+    Key {
+        keycode: u8,
+        /// State after this event
+        modifiers: Modifiers,
+        pressed: bool,
+        /// Character produced by the active keymap, if any
+        character: Option<char>,
+    },
+    /// Relative movement; positive `dy` is downwards. `buttons` has left, right
+    /// and middle in bits 0-2.
+    Mouse { dx: i32, dy: i32, buttons: u8 },
+}
+
+impl InputEvent {
+    pub fn key(keycode: u8, modifiers: Modifiers, pressed: bool) -> Self {
+        InputEvent::Key {
+            keycode,
+            modifiers,
+            pressed,
+            character: to_char(keycode, modifiers),
+        }
+    }
+}
+
+/// Task that gets every event as `Message::Input`; `NO_RECEIVER` until one is set
+static RECEIVER: AtomicU64 = AtomicU64::new(NO_RECEIVER);
+const NO_RECEIVER: u64 = u64::MAX;
+
+/// Deliver all input events to `task` from now on
+pub fn set_receiver(task: TaskId) {
+    RECEIVER.store(task, Ordering::Relaxed);
+}
+
+/// Send an event to the receiving task. Safe to call from interrupt handlers;
+/// events are dropped while its queue is full or before a receiver is set.
+pub fn push_event(event: InputEvent) {
+    let receiver = RECEIVER.load(Ordering::Relaxed);
+    if receiver != NO_RECEIVER {
+        let _ = message::send(receiver, Message::Input(event));
+    }
+}
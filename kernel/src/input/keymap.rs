@@ -0,0 +1,87 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Translation of HID usage IDs into characters
+
+use super::Modifiers;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Layout {
+    Us,
+    /// Japanese 106/109 key layout
+    Jis,
+}
+
+/// Chosen at build time with the `jis-keyboard` feature
+const LAYOUT: Layout = if cfg!(feature = "jis-keyboard") { Layout::Jis } else { Layout::Us };
+
+/// Returns `None` for keys without a character in the active layout
+pub fn to_char(keycode: u8, modifiers: Modifiers) -> Option<char> {
+    let shift = modifiers.shift();
+    let c = match keycode {
+        0x04..=0x1d => {
+            let c = b'a' + (keycode - 0x04);
+            if shift {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        }
+        0x1e..=0x27 if !shift => b"1234567890"[(keycode - 0x1e) as usize],
+        0x28 | 0x58 => b'\n',
+        0x2a => 0x08,
+        0x2b => b'\t',
+        0x2c => b' ',
+        0x54 => b'/',
+        0x55 => b'*',
+        0x56 => b'-',
+        0x57 => b'+',
+        0x59..=0x61 => b'1' + (keycode - 0x59),
+        0x62 => b'0',
+        0x63 => b'.',
+        _ => match LAYOUT {
+            Layout::Us => return us_symbol(keycode, shift).map(char::from),
+            Layout::Jis => return jis_symbol(keycode, shift).map(char::from),
+        },
+    };
+    Some(c as char)
+}
+
+fn us_symbol(keycode: u8, shift: bool) -> Option<u8> {
+    let (normal, shifted) = match keycode {
+        0x1e..=0x27 => (0, b"!@#$%^&*()"[(keycode - 0x1e) as usize]),
+        0x2d..=0x38 => {
+            let index = (keycode - 0x2d) as usize;
+            (b"-=[]\\#;'`,./"[index], b"_+{}|~:\"~<>?"[index])
+        }
+        0x64 => (b'\\', b'|'),
+        _ => return None,
+    };
+    Some(if shift { shifted } else { normal })
+}
+
+fn jis_symbol(keycode: u8, shift: bool) -> Option<u8> {
+    let (normal, shifted) = match keycode {
+        // Shift+0 produces nothing on JIS keyboards
+        0x27 => return None,
+        0x1e..=0x26 => (0, b"!\"#$%&'()"[(keycode - 0x1e) as usize]),
+        0x2d => (b'-', b'='),
+        0x2e => (b'^', b'~'),
+        0x2f => (b'@', b'`'),
+        0x30 => (b'[', b'{'),
+        // The key left of Enter; PS/2 keyboards report it as the US backslash
+        0x31 | 0x32 => (b']', b'}'),
+        0x33 => (b';', b'+'),
+        0x34 => (b':', b'*'),
+        0x36 => (b',', b'<'),
+        0x37 => (b'.', b'>'),
+        0x38 => (b'/', b'?'),
+        // International1 (Ro) and International3 (Yen)
+        0x87 => (b'\\', b'_'),
+        0x89 => (b'\\', b'|'),
+        _ => return None,
+    };
+    Some(if shift { shifted } else { normal })
+}
@@ -0,0 +1,167 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use core::arch::asm;
+use core::ptr::addr_of;
+
+/// Interrupt vectors used by the kernel
+pub mod vector {
+    /// The 8259 PIC is remapped here so that stray IRQs do not look like CPU exceptions
+    pub const PIC_BASE: u8 = 0x20;
+    pub const LOCAL_APIC_TIMER: u8 = 0x41;
+    pub const PS2_KEYBOARD: u8 = 0x42;
+    pub const PS2_MOUSE: u8 = 0x43;
+    /// Vectors in `DYNAMIC_FIRST..=DYNAMIC_LAST` are handed out by `allocate_vectors()`
+    pub const DYNAMIC_FIRST: u8 = 0x50;
+    pub const DYNAMIC_LAST: u8 = 0xef;
+    pub const LOCAL_APIC_ERROR: u8 = 0xfe;
+    /// Low 4 bits must be all ones on older processors
+    pub const SPURIOUS: u8 = 0xff;
+}
+
+/// Stack frame pushed by the CPU when an interrupt is delivered
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+pub type InterruptHandler = extern "x86-interrupt" fn(InterruptFrame);
+
+const INTERRUPT_GATE: u16 = 0xe;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct InterruptDescriptor {
+    offset_low: u16,
+    segment_selector: u16,
+    /// IST, type, DPL and present bit
+    attributes: u16,
+    offset_middle: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl InterruptDescriptor {
+    const fn missing() -> Self {
+        Self {
+            offset_low: 0,
+            segment_selector: 0,
+            attributes: 0,
+            offset_middle: 0,
+            offset_high: 0,
+            reserved: 0,
+        }
+    }
+
+    fn new(handler: usize, segment_selector: u16) -> Self {
+        Self {
+            offset_low: handler as u16,
+            segment_selector,
+            attributes: 1 << 15 | INTERRUPT_GATE << 8,
+            offset_middle: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+static mut IDT: [InterruptDescriptor; 256] = [InterruptDescriptor::missing(); 256];
+
+fn code_segment() -> u16 {
+    let cs: u16;
+    unsafe {
+        asm!("mov {:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+    }
+    cs
+}
+
+/// Load the IDT on the running CPU. The bootstrap processor loads it empty;
+/// handlers can be added at any time afterwards and apply to every CPU.
+pub fn init() {
+    let pointer = DescriptorTablePointer {
+        limit: (core::mem::size_of::<[InterruptDescriptor; 256]>() - 1) as u16,
+        base: unsafe { addr_of!(IDT) } as u64,
+    };
+    unsafe {
+        asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+    }
+}
+
+pub fn set_handler(vector: u8, handler: InterruptHandler) {
+    let descriptor = InterruptDescriptor::new(handler as usize, code_segment());
+    unsafe {
+        IDT[vector as usize] = descriptor;
+    }
+}
+
+/// One bit per vector, set while the vector is allocated
+static mut ALLOCATED_VECTORS: [u64; 4] = [0; 4];
+
+fn is_allocated(vector: u8) -> bool {
+    unsafe { ALLOCATED_VECTORS[vector as usize / 64] & 1 << (vector % 64) != 0 }
+}
+
+/// Allocate `count` consecutive vectors from the dynamic range, with the first one
+/// aligned to `count` rounded up to a power of two as multi-message MSI requires.
+/// Returns the first vector.
+pub fn allocate_vectors(count: usize) -> Option<u8> {
+    if count == 0 {
+        return None;
+    }
+    let align = count.next_power_of_two();
+    without_interrupts(|| {
+        let mut first = (vector::DYNAMIC_FIRST as usize + align - 1) / align * align;
+        while first + count - 1 <= vector::DYNAMIC_LAST as usize {
+            if (first..first + count).all(|vector| !is_allocated(vector as u8)) {
+                for vector in first..first + count {
+                    unsafe { ALLOCATED_VECTORS[vector / 64] |= 1 << (vector % 64) };
+                }
+                return Some(first as u8);
+            }
+            first += align;
+        }
+        None
+    })
+}
+
+pub fn enable() {
+    unsafe { asm!("sti", options(nomem, nostack)) }
+}
+
+pub fn disable() {
+    unsafe { asm!("cli", options(nomem, nostack)) }
+}
+
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & (1 << 9) != 0
+}
+
+/// Run `f` with interrupts disabled, restoring the previous state afterwards
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+    let result = f();
+    if enabled {
+        enable();
+    }
+    result
+}
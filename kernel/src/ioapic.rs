@@ -0,0 +1,184 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! I/O APIC driver routing global system interrupts (GSIs) to IDT vectors
+
+use alloc::vec::Vec;
+
+use crate::acpi::{self, InterruptSourceOverride};
+use crate::apic;
+use crate::interrupt::{self, InterruptHandler};
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const REGISTER_VERSION: u32 = 0x01;
+const REGISTER_REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 14;
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_DESTINATION_SHIFT: u64 = 56;
+
+/// MPS INTI flags as used by MADT interrupt source overrides
+const INTI_POLARITY_MASK: u16 = 0b11;
+const INTI_POLARITY_ACTIVE_LOW: u16 = 0b11;
+const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+const INTI_TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+/// Number of IRQs of the legacy ISA bus, which are identity mapped to GSIs unless overridden
+const ISA_IRQ_COUNT: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    entry_count: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(register);
+            ((self.base + IOWIN) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(register);
+            ((self.base + IOWIN) as *mut u32).write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entry_count
+    }
+
+    pub fn read_entry(&self, index: u32) -> u64 {
+        let register = REGISTER_REDIRECTION_TABLE + 2 * index;
+        (self.read(register + 1) as u64) << 32 | self.read(register) as u64
+    }
+
+    pub fn write_entry(&self, index: u32, entry: u64) {
+        let register = REGISTER_REDIRECTION_TABLE + 2 * index;
+        // Write the low half (with the mask bit) last so the entry never fires half written
+        self.write(register, ENTRY_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+static mut IO_APICS: Vec<IoApic> = Vec::new();
+
+fn io_apics() -> &'static [IoApic] {
+    unsafe { &*core::ptr::addr_of!(IO_APICS) }
+}
+
+fn io_apic_for(gsi: u32) -> Option<(&'static IoApic, u32)> {
+    io_apics()
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .map(|io_apic| (io_apic, gsi - io_apic.gsi_base))
+}
+
+/// Register every I/O APIC listed in the MADT and mask all of their inputs
+pub fn init() {
+    let madt = match &acpi::tables().madt {
+        Some(madt) => madt,
+        None => return,
+    };
+    let io_apics = unsafe { &mut *core::ptr::addr_of_mut!(IO_APICS) };
+    for entry in madt.io_apics.iter() {
+        let mut io_apic = IoApic {
+            base: entry.address as usize,
+            gsi_base: entry.gsi_base,
+            entry_count: 0,
+        };
+        io_apic.entry_count = ((io_apic.read(REGISTER_VERSION) >> 16) & 0xff) + 1;
+        for index in 0..io_apic.entry_count {
+            io_apic.write_entry(index, ENTRY_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+}
+
+fn find_override(isa_irq: u8) -> Option<&'static InterruptSourceOverride> {
+    acpi::tables()
+        .madt
+        .as_ref()
+        .and_then(|madt| madt.overrides.iter().find(|o| o.source == isa_irq))
+}
+
+/// GSI that an ISA IRQ is wired to, e.g. the PIT's IRQ0 is usually GSI 2
+pub fn isa_irq_to_gsi(isa_irq: u8) -> u32 {
+    find_override(isa_irq).map(|o| o.gsi).unwrap_or(isa_irq as u32)
+}
+
+/// Trigger mode and polarity of `gsi`: taken from a MADT override if one targets
+/// it, otherwise ISA defaults (edge, active high) below 16 and PCI defaults
+/// (level, active low) above.
+fn default_mode(gsi: u32) -> (Trigger, Polarity) {
+    let overridden = acpi::tables()
+        .madt
+        .as_ref()
+        .and_then(|madt| madt.overrides.iter().find(|o| o.gsi == gsi));
+    if let Some(o) = overridden {
+        let trigger = if o.flags & INTI_TRIGGER_MASK == INTI_TRIGGER_LEVEL { Trigger::Level } else { Trigger::Edge };
+        let polarity = if o.flags & INTI_POLARITY_MASK == INTI_POLARITY_ACTIVE_LOW { Polarity::ActiveLow } else { Polarity::ActiveHigh };
+        (trigger, polarity)
+    } else if gsi < ISA_IRQ_COUNT {
+        (Trigger::Edge, Polarity::ActiveHigh)
+    } else {
+        (Trigger::Level, Polarity::ActiveLow)
+    }
+}
+
+/// Route `gsi` to `vector` on the bootstrap processor and install `handler`.
+///
+/// The handler must call `apic::end_of_interrupt()`. Returns false if no I/O APIC
+/// serves the GSI.
+pub fn register_irq(gsi: u32, vector: u8, handler: InterruptHandler) -> bool {
+    let (trigger, polarity) = default_mode(gsi);
+    register_irq_with_mode(gsi, vector, handler, trigger, polarity)
+}
+
+pub fn register_irq_with_mode(gsi: u32, vector: u8, handler: InterruptHandler, trigger: Trigger, polarity: Polarity) -> bool {
+    let (io_apic, index) = match io_apic_for(gsi) {
+        Some(found) => found,
+        None => return false,
+    };
+    let current = io_apic.read_entry(index);
+    if current & ENTRY_MASKED == 0 && current & 0xff != vector as u64 {
+        println!("[WARN] I/O APIC: GSI {} moves from vector {:#x} to {:#x}", gsi, current & 0xff, vector);
+    }
+    interrupt::set_handler(vector, handler);
+
+    let mut entry = vector as u64 | (apic::id() as u64) << ENTRY_DESTINATION_SHIFT;
+    if trigger == Trigger::Level {
+        entry |= ENTRY_LEVEL_TRIGGERED;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    io_apic.write_entry(index, entry);
+    true
+}
+
+/// Route a legacy ISA IRQ (keyboard, serial ports, ...) honoring MADT overrides
+pub fn register_isa_irq(isa_irq: u8, vector: u8, handler: InterruptHandler) -> bool {
+    register_irq(isa_irq_to_gsi(isa_irq), vector, handler)
+}
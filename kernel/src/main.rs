@@ -5,22 +5,92 @@
 
 #![no_std]
 #![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
-use core::{arch::asm, ffi::c_void, panic::PanicInfo};
-use heapless::String;
-use core::fmt::Write;
+use core::{arch::asm, panic::PanicInfo};
 
+extern crate alloc;
+
+mod acpi;
+mod allocator;
+mod apic;
 mod graphics;
+#[macro_use]
 mod console;
+mod cpu;
+mod fpu;
+mod gdt;
+mod image;
+mod input;
+mod interrupt;
+mod io_port;
+mod ioapic;
+mod memory_manager;
+mod memory_map;
+mod message;
+mod msr;
+mod pci;
+mod ps2;
+#[cfg(feature = "boot-screenshot")]
+mod screenshot;
+#[cfg(any(feature = "boot-screenshot", feature = "lock-debug"))]
+mod serial;
+mod smp;
+mod sync;
+mod task;
+mod timer;
+mod usb;
+mod window;
+
+use memory_map::MemoryMap;
 
+/// File loaded by the bootloader. `buffer` is null if the file was not found.
 #[repr(C)]
-pub struct MemoryMap {
-    buffer_size: u64,
-    buffer: *mut c_void,
-    map_size: u64,
-    map_key: u64,
-    descriptor_size: u64,
-    descriptor_version: u32,
+pub struct BootFile {
+    buffer: *const u8,
+    size: u64,
+}
+
+impl BootFile {
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        if self.buffer.is_null() {
+            None
+        } else {
+            Some(unsafe { core::slice::from_raw_parts(self.buffer, self.size as usize) })
+        }
+    }
+}
+
+/// Half period of the caret in the hello window
+const CARET_BLINK_MS: u64 = 500;
+
+/// Draw the wallpaper centered on the screen, if the bootloader found one
+fn draw_wallpaper(wallpaper: &BootFile) {
+    let data = match wallpaper.as_slice() {
+        Some(data) => data,
+        None => return,
+    };
+    if let Ok(image) = image::decode(data) {
+        let desktop = graphics::desktop();
+        let x = (desktop.width() as i32 - image.width as i32) / 2;
+        let y = (desktop.height() as i32 - image.height as i32) / 2;
+        graphics::Canvas::new(desktop).draw_bitmap(x, y, &image.as_bitmap());
+    }
+}
+
+/// Background task counting up in its own window until the window is closed
+fn counter_task(_: u64) {
+    let counter_window = window::create("Counter", 160, 20);
+    window::move_to(counter_window, 300, 220);
+    let mut count: u64 = 0;
+    while window::draw(counter_window, |canvas| {
+        canvas.fill_rectangle(4, 2, 152, graphics::FONT_HEIGHT, window::FACE_COLOR);
+        canvas.draw_string(4, 2, &alloc::format!("{:010}", count), graphics::basic_color::BLACK);
+    }) {
+        count += 1;
+        task::sleep(100);
+    }
 }
 
 #[panic_handler]
@@ -33,20 +103,159 @@ fn panic(_panic: &PanicInfo<'_>) -> ! {
 
 #[no_mangle]
 #[allow(unreachable_code)]
-pub extern "C" fn kernel_main(frame_buffer_config: graphics::FrameBufferConfig, memory_map: MemoryMap) {
+pub extern "C" fn kernel_main(frame_buffer_config: graphics::FrameBufferConfig, memory_map: MemoryMap, wallpaper: BootFile, acpi_rsdp: *const u8) {
+
+    smp::init();
+    memory_manager::init(&memory_map);
+    allocator::init();
 
-    graphics::fill_background(graphics::basic_color::GRAY, &frame_buffer_config);
+    graphics::init(&frame_buffer_config);
+    graphics::with_lock(|| {
+        graphics::fill_background(graphics::basic_color::GRAY);
+        draw_wallpaper(&wallpaper);
+        graphics::flush();
+    });
 
-    let mut console = console::Console::new(&frame_buffer_config);
+    console::init();
+    graphics::cursor::init(200, 100);
     for i in 0..35 {
-        let mut s = String::<40>::new();
-        write!(s, "[LINE{}] Hello, World!\n", i + 1).unwrap();
-        console.write_string(&s);
+        println!("[LINE{}] Hello, World!", i + 1);
+    }
+
+    cpu::print_info();
+    fpu::init();
+    fpu::print_info();
+
+    match acpi::init(acpi_rsdp) {
+        Ok(_) => {
+            let tables = acpi::tables();
+            if let Some(madt) = &tables.madt {
+                println!(
+                    "ACPI: {} CPUs, {} I/O APICs{}",
+                    madt.local_apics.len(),
+                    madt.io_apics.len(),
+                    if madt.pcat_compat { ", 8259 PICs" } else { "" }
+                );
+                for io_apic in madt.io_apics.iter() {
+                    println!("  I/O APIC {} at {:#x}, GSI base {}", io_apic.id, io_apic.address, io_apic.gsi_base);
+                }
+            }
+            if let Some(register) = tables.fadt.and_then(|fadt| fadt.reset_register) {
+                let address = register.address;
+                println!(
+                    "ACPI: reset register {:#x} in address space {}, value {:#x}",
+                    address,
+                    register.address_space,
+                    tables.fadt.unwrap().reset_value
+                );
+            }
+            if let Some(hpet) = &tables.hpet {
+                println!("ACPI: HPET {} at {:#x}, minimum tick {}", hpet.number, hpet.base_address, hpet.minimum_tick);
+            }
+        }
+        Err(err) => println!("[ERROR] ACPI: {:?}", err),
     }
 
+    interrupt::init();
+    apic::init();
+    println!(
+        "Local APIC {} enabled ({}, {} LVT entries)",
+        apic::id(),
+        if apic::is_x2apic() { "x2APIC" } else { "xAPIC" },
+        apic::lvt_count()
+    );
+    ioapic::init();
+    let reference_clock = acpi::reference_clock();
+    timer::init(reference_clock);
+    println!("Timer calibrated with {:?}", reference_clock);
+    println!("[{}ms] Local APIC timer: {} Hz", timer::uptime() / 1_000_000, timer::apic_timer_hz());
+
+    task::init();
+
+    pci::init();
+    println!("PCI: {} functions ({})", pci::devices().len(), if pci::uses_ecam() { "ECAM" } else { "port I/O" });
+    pci::print_devices();
+
+    input::set_receiver(task::current_id());
+
+    // Driver initialization below waits with timeouts driven by the timer interrupt
+    interrupt::enable();
+    match smp::start_application_processors(&memory_map) {
+        Ok(count) => println!("SMP: {} CPUs online", count),
+        Err(err) => println!("[ERROR] SMP: {:?}", err),
+    }
+    if let Err(err) = ps2::init() {
+        println!("[ERROR] PS/2: {:?}", err);
+    }
+    if let Err(err) = usb::xhci::init() {
+        println!("[ERROR] xHCI: {:?}", err);
+    }
+
+    // Lets CI grab exactly this frame from the serial log
+    #[cfg(feature = "boot-screenshot")]
+    {
+        let mut port = serial::SerialPort::new(serial::COM1);
+        port.init();
+        screenshot::capture(&mut port);
+    }
+
+    let counter = task::spawn("counter", counter_task, 0);
+    // The counter is only decoration; anything else may run first
+    task::set_level(counter, 0);
+    task::dump();
+
+    let hello_window = window::create("Hello Window", 160, 52);
+    window::move_to(hello_window, 300, 100);
+    window::draw(hello_window, |canvas| {
+        canvas.draw_string(4, 2, "Welcome to", graphics::basic_color::BLACK);
+        canvas.draw_string(4, 18, "Rikan world!", graphics::basic_color::BLACK);
+    });
+    // Keys typed while the window has the focus are echoed on its last line,
+    // followed by a blinking caret
+    let mut text_column = 0;
+    let mut caret_visible = false;
+    let draw_caret = |canvas: &mut graphics::Canvas<window::ClientArea>, column: u32, color| {
+        canvas.fill_rectangle(4 + (column * graphics::FONT_WIDTH) as i32, 34 + graphics::FONT_HEIGHT as i32, graphics::FONT_WIDTH, 2, color);
+    };
+    message::start_timer(CARET_BLINK_MS);
+
     loop {
-        unsafe {
-            asm!("hlt");
+        match message::receive() {
+            message::Message::Xhci => usb::xhci::poll(),
+            message::Message::Input(event) => match event {
+                input::InputEvent::Key { .. } if window::handle_key(&event) => {}
+                input::InputEvent::Key { pressed: true, character: Some(c), .. } => print!("{}", c),
+                input::InputEvent::Mouse { dx, dy, buttons, .. } => {
+                    graphics::cursor::move_by(dx, dy);
+                    window::handle_mouse(buttons);
+                }
+                _ => {}
+            },
+            message::Message::Timer => {
+                caret_visible = !caret_visible;
+                let color = if caret_visible { graphics::basic_color::BLACK } else { window::FACE_COLOR };
+                window::draw(hello_window, |canvas| draw_caret(canvas, text_column, color));
+                message::start_timer(CARET_BLINK_MS);
+            }
+            message::Message::Window {
+                window,
+                event: window::WindowEvent::Key { pressed: true, character: Some(c), .. },
+            } if window == hello_window => {
+                let (width, _) = window::client_size(hello_window).unwrap_or((0, 0));
+                window::draw(hello_window, |canvas| {
+                    draw_caret(canvas, text_column, window::FACE_COLOR);
+                    if (text_column + 1) * graphics::FONT_WIDTH > width - 8 {
+                        canvas.fill_rectangle(4, 34, width - 8, graphics::FONT_HEIGHT, window::FACE_COLOR);
+                        text_column = 0;
+                    }
+                    canvas.draw_char(4 + (text_column * graphics::FONT_WIDTH) as i32, 34, c, graphics::basic_color::BLACK);
+                    text_column += 1;
+                    if caret_visible {
+                        draw_caret(canvas, text_column, graphics::basic_color::BLACK);
+                    }
+                });
+            }
+            _ => {}
         }
     }
 }
@@ -0,0 +1,112 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use crate::memory_map::{MemoryMap, MemoryType, UEFI_PAGE_SIZE};
+use crate::sync::SpinLock;
+
+pub const BYTES_PER_FRAME: usize = 4096;
+const MAX_PHYSICAL_MEMORY_BYTES: usize = 16 * 1024 * 1024 * 1024;
+const FRAME_COUNT: usize = MAX_PHYSICAL_MEMORY_BYTES / BYTES_PER_FRAME;
+const BITS_PER_MAP_LINE: usize = u64::BITS as usize;
+
+extern "C" {
+    // End of the kernel image, defined by the linker
+    static _end: u8;
+}
+
+/// Physical frame allocator with one bit per 4 KiB frame (1 = in use)
+struct BitmapMemoryManager {
+    alloc_map: [u64; FRAME_COUNT / BITS_PER_MAP_LINE],
+    range_begin: usize,
+    range_end: usize,
+}
+
+impl BitmapMemoryManager {
+    fn set_bit(&mut self, frame: usize, allocated: bool) {
+        let line = frame / BITS_PER_MAP_LINE;
+        let bit = frame % BITS_PER_MAP_LINE;
+        if allocated {
+            self.alloc_map[line] |= 1 << bit;
+        } else {
+            self.alloc_map[line] &= !(1 << bit);
+        }
+    }
+
+    fn get_bit(&self, frame: usize) -> bool {
+        self.alloc_map[frame / BITS_PER_MAP_LINE] & (1 << (frame % BITS_PER_MAP_LINE)) != 0
+    }
+
+    fn mark(&mut self, start_frame: usize, num_frames: usize, allocated: bool) {
+        for frame in start_frame..start_frame + num_frames {
+            self.set_bit(frame, allocated);
+        }
+    }
+
+    /// First fit search for `num_frames` contiguous free frames
+    fn allocate(&mut self, num_frames: usize) -> Option<usize> {
+        let mut start = self.range_begin;
+        while start + num_frames <= self.range_end {
+            // Skip fully used lines quickly
+            if start % BITS_PER_MAP_LINE == 0 && self.alloc_map[start / BITS_PER_MAP_LINE] == u64::MAX {
+                start += BITS_PER_MAP_LINE;
+                continue;
+            }
+            match (0..num_frames).find(|i| self.get_bit(start + i)) {
+                Some(used) => start += used + 1,
+                None => {
+                    self.mark(start, num_frames, true);
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+}
+
+static MEMORY_MANAGER: SpinLock<BitmapMemoryManager> = SpinLock::new(BitmapMemoryManager {
+    alloc_map: [0; FRAME_COUNT / BITS_PER_MAP_LINE],
+    range_begin: 0,
+    range_end: 0,
+});
+
+/// Build the frame bitmap from the UEFI memory map.
+///
+/// Only `EfiConventionalMemory` is handed out: boot services regions still hold
+/// the stack we are running on. Everything below the end of the kernel image is
+/// reserved as well, which keeps the low memory used by firmware untouched.
+pub fn init(memory_map: &MemoryMap) {
+    let mut manager = MEMORY_MANAGER.lock();
+    manager.alloc_map.iter_mut().for_each(|line| *line = u64::MAX);
+
+    let kernel_end = unsafe { &_end as *const u8 as usize };
+    let first_free_frame = (kernel_end + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME;
+
+    let mut range_end = 0;
+    for desc in memory_map.iter() {
+        if !desc.is_type(MemoryType::EfiConventionalMemory) {
+            continue;
+        }
+        let start = desc.physical_start as usize / BYTES_PER_FRAME;
+        let end = (desc.physical_start as usize + desc.number_of_pages as usize * UEFI_PAGE_SIZE) / BYTES_PER_FRAME;
+        let start = start.max(first_free_frame);
+        let end = end.min(FRAME_COUNT);
+        if start >= end {
+            continue;
+        }
+        manager.mark(start, end - start, false);
+        range_end = range_end.max(end);
+    }
+    manager.range_begin = first_free_frame;
+    manager.range_end = range_end;
+}
+
+/// Allocate physically contiguous frames and return the physical (= virtual) address
+pub fn allocate_frames(num_frames: usize) -> Option<usize> {
+    MEMORY_MANAGER.lock().allocate(num_frames).map(|frame| frame * BYTES_PER_FRAME)
+}
+
+pub fn free_frames(address: usize, num_frames: usize) {
+    MEMORY_MANAGER.lock().mark(address / BYTES_PER_FRAME, num_frames, false);
+}
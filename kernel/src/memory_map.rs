@@ -0,0 +1,69 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use core::ffi::c_void;
+
+/// Memory map obtained by the bootloader through `GetMemoryMap()`
+#[repr(C)]
+pub struct MemoryMap {
+    buffer_size: u64,
+    buffer: *mut c_void,
+    map_size: u64,
+    map_key: u64,
+    descriptor_size: u64,
+    descriptor_version: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryDescriptor {
+    pub memory_type: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+pub const UEFI_PAGE_SIZE: usize = 4096;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MemoryType {
+    EfiReservedMemoryType,
+    EfiLoaderCode,
+    EfiLoaderData,
+    EfiBootServicesCode,
+    EfiBootServicesData,
+    EfiRuntimeServicesCode,
+    EfiRuntimeServicesData,
+    EfiConventionalMemory,
+    EfiUnusableMemory,
+    EfiACPIReclaimMemory,
+    EfiACPIMemoryNVS,
+    EfiMemoryMappedIO,
+    EfiMemoryMappedIOPortSpace,
+    EfiPalCode,
+    EfiPersistentMemory,
+}
+
+impl MemoryDescriptor {
+    pub fn is_type(&self, memory_type: MemoryType) -> bool {
+        self.memory_type == memory_type as u32
+    }
+}
+
+impl MemoryMap {
+    /// Iterate over the descriptors.
+    ///
+    /// Descriptors must be stepped by `descriptor_size`, which may be larger
+    /// than `size_of::<MemoryDescriptor>()`.
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryDescriptor> {
+        let base = self.buffer as usize;
+        let descriptor_size = self.descriptor_size as usize;
+        let count = self.map_size as usize / descriptor_size;
+        (0..count).map(move |i| unsafe { &*((base + i * descriptor_size) as *const MemoryDescriptor) })
+    }
+}
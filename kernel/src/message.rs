@@ -0,0 +1,85 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Message queues between tasks, interrupt handlers and timers.
+//!
+//! Every task owns one bounded queue. Anyone may `send()` to it, including
+//! interrupt handlers since nothing here allocates; only the owner receives.
+//! `receive()` blocks the task until a message arrives, so an event loop
+//! costs no CPU time while there is nothing to do.
+
+use crate::input::InputEvent;
+use crate::sync::SpinLock;
+use crate::task::{self, TaskId};
+use crate::timer;
+use crate::window::{WindowEvent, WindowId};
+
+/// Messages sent to a full queue are dropped
+pub const QUEUE_CAPACITY: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Message {
+    /// A timer started with `start_timer()` expired
+    Timer,
+    /// Keyboard or mouse input, sent to the task chosen with `input::set_receiver()`
+    Input(InputEvent),
+    /// Input for a window, sent to the task that created it
+    Window { window: WindowId, event: WindowEvent },
+    /// The xHC has events; call `usb::xhci::poll()`
+    Xhci,
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    NoSuchTask,
+    QueueFull,
+}
+
+pub struct MessageQueue {
+    messages: SpinLock<heapless::Deque<Message, QUEUE_CAPACITY>>,
+}
+
+impl MessageQueue {
+    pub const fn new() -> Self {
+        Self { messages: SpinLock::new(heapless::Deque::new()) }
+    }
+
+    fn push(&self, message: Message) -> Result<(), Message> {
+        self.messages.lock().push_back(message)
+    }
+
+    fn pop(&self) -> Option<Message> {
+        self.messages.lock().pop_front()
+    }
+}
+
+/// Queue `message` for `task` and wake it up if it is waiting in `receive()`
+pub fn send(task: TaskId, message: Message) -> Result<(), SendError> {
+    let queue = task::message_queue(task).ok_or(SendError::NoSuchTask)?;
+    queue.push(message).map_err(|_| SendError::QueueFull)?;
+    task::wake(task);
+    Ok(())
+}
+
+/// Next message for the current task, blocking until one arrives
+pub fn receive() -> Message {
+    let queue = task::message_queue(task::current_id()).expect("current task has no message queue");
+    loop {
+        if let Some(message) = queue.pop() {
+            return message;
+        }
+        // A message sent between the check and here makes block() return at once
+        task::block();
+    }
+}
+
+fn send_timer(task: u64) {
+    let _ = send(task, Message::Timer);
+}
+
+/// Send `Message::Timer` to the current task after `milliseconds`
+pub fn start_timer(milliseconds: u64) {
+    timer::add_timer_after_ms(milliseconds, send_timer, task::current_id());
+}
@@ -0,0 +1,22 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Model specific registers
+
+use core::arch::asm;
+
+pub const IA32_APIC_BASE: u32 = 0x1b;
+pub const IA32_EFER: u32 = 0xc000_0080;
+pub const IA32_GS_BASE: u32 = 0xc000_0101;
+
+pub unsafe fn read(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    (high as u64) << 32 | low as u64
+}
+
+pub unsafe fn write(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+}
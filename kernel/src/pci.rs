@@ -0,0 +1,424 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! PCI configuration space access and bus enumeration
+
+use alloc::vec::Vec;
+
+use crate::acpi;
+use crate::io_port::{in32, out32};
+
+mod msi;
+
+pub use msi::{enable_interrupts, Interrupts};
+
+const CONFIG_ADDRESS: u16 = 0x0cf8;
+const CONFIG_DATA: u16 = 0x0cfc;
+
+pub const MAX_DEVICE: u8 = 32;
+pub const MAX_FUNCTION: u8 = 8;
+
+/// Offsets of the common configuration space header
+pub mod register {
+    pub const VENDOR_ID: u8 = 0x00;
+    pub const COMMAND: u8 = 0x04;
+    pub const CLASS_CODE: u8 = 0x08;
+    pub const HEADER_TYPE: u8 = 0x0c;
+    pub const BAR0: u8 = 0x10;
+    /// Primary, secondary and subordinate bus numbers of a PCI-to-PCI bridge
+    pub const BUS_NUMBERS: u8 = 0x18;
+    pub const CAPABILITIES_POINTER: u8 = 0x34;
+}
+
+/// Bits of the command register
+pub mod command {
+    pub const MEMORY_SPACE: u16 = 1 << 1;
+    pub const BUS_MASTER: u16 = 1 << 2;
+    pub const INTERRUPT_DISABLE: u16 = 1 << 10;
+}
+
+/// Capability IDs
+pub mod capability {
+    pub const MSI: u8 = 0x05;
+    pub const MSIX: u8 = 0x11;
+}
+
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+const INVALID_VENDOR_ID: u16 = 0xffff;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+
+/// How configuration space is reached
+#[derive(Clone, Copy, Debug)]
+enum ConfigAccess {
+    /// Legacy 0xCF8/0xCFC mechanism, first 256 bytes only
+    PortIo,
+    /// PCI Express memory mapped configuration space (ECAM) from the MCFG table
+    Ecam { base: usize, start_bus: u8, end_bus: u8 },
+}
+
+static mut ACCESS: ConfigAccess = ConfigAccess::PortIo;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClassCode {
+    pub base: u8,
+    pub sub: u8,
+    pub interface: u8,
+}
+
+impl ClassCode {
+    pub fn matches(&self, base: u8, sub: u8, interface: u8) -> bool {
+        self.base == base && self.sub == sub && self.interface == interface
+    }
+
+    pub fn is_pci_bridge(&self) -> bool {
+        self.base == 0x06 && self.sub == 0x04
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    None,
+    Io {
+        port: u32,
+    },
+    Memory {
+        address: u64,
+        is_64bit: bool,
+        prefetchable: bool,
+    },
+}
+
+/// One function found during the scan
+#[derive(Clone, Copy, Debug)]
+pub struct Device {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision: u8,
+    pub class_code: ClassCode,
+    pub header_type: u8,
+    pub bars: [Bar; 6],
+}
+
+impl Device {
+    pub fn read_config32(&self, offset: u16) -> u32 {
+        read_config32(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn write_config32(&self, offset: u16, value: u32) {
+        write_config32(self.bus, self.device, self.function, offset, value)
+    }
+
+    pub fn read_config16(&self, offset: u16) -> u16 {
+        (self.read_config32(offset & !0b11) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    pub fn read_config8(&self, offset: u16) -> u8 {
+        (self.read_config32(offset & !0b11) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    pub fn write_config16(&self, offset: u16, value: u16) {
+        let shift = (offset & 0b10) * 8;
+        let dword = self.read_config32(offset & !0b11) & !(0xffff << shift);
+        self.write_config32(offset & !0b11, dword | (value as u32) << shift);
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_config16(register::COMMAND as u16)
+    }
+
+    /// Set bits in the command register. Only the low half of the dword is written
+    /// back, so write-one-to-clear status bits are left alone.
+    pub fn set_command(&self, bits: u16) {
+        let command = self.command();
+        self.write_config32(register::COMMAND as u16, (command | bits) as u32);
+    }
+
+    /// Iterate over `(capability id, offset)` of the standard capability list
+    pub fn capabilities(&self) -> Capabilities<'_> {
+        let status = (self.read_config32(register::COMMAND as u16) >> 16) as u16;
+        let next = if status & STATUS_CAPABILITIES_LIST != 0 {
+            self.read_config8(register::CAPABILITIES_POINTER as u16) & !0b11
+        } else {
+            0
+        };
+        Capabilities {
+            device: self,
+            next,
+            remaining: 48,
+        }
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities().find(|&(capability, _)| capability == id).map(|(_, offset)| offset)
+    }
+
+    /// Memory address of a memory BAR, if it is one
+    pub fn memory_bar(&self, index: usize) -> Option<u64> {
+        match self.bars.get(index) {
+            Some(Bar::Memory { address, .. }) => Some(*address),
+            _ => None,
+        }
+    }
+}
+
+pub struct Capabilities<'a> {
+    device: &'a Device,
+    next: u8,
+    /// Guards against malformed lists that loop
+    remaining: usize,
+}
+
+impl Iterator for Capabilities<'_> {
+    type Item = (u8, u16);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = self.next as u16;
+        let header = self.device.read_config16(offset);
+        self.next = (header >> 8) as u8 & !0b11;
+        Some((header as u8, offset))
+    }
+}
+
+fn config_address(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    1 << 31 | (bus as u32) << 16 | (device as u32) << 11 | (function as u32) << 8 | (offset as u32 & 0xfc)
+}
+
+fn ecam_address(bus: u8, device: u8, function: u8, offset: u16) -> Option<usize> {
+    match unsafe { ACCESS } {
+        ConfigAccess::Ecam {
+            base,
+            start_bus,
+            end_bus,
+        } if start_bus <= bus && bus <= end_bus => Some(
+            base + (((bus - start_bus) as usize) << 20
+                | (device as usize) << 15
+                | (function as usize) << 12
+                | (offset as usize & 0xffc)),
+        ),
+        _ => None,
+    }
+}
+
+/// Read a dword of configuration space. Offsets past 0xff need ECAM.
+pub fn read_config32(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    if let Some(address) = ecam_address(bus, device, function, offset) {
+        return unsafe { (address as *const u32).read_volatile() };
+    }
+    if offset > 0xff {
+        return 0xffff_ffff;
+    }
+    unsafe {
+        out32(CONFIG_ADDRESS, config_address(bus, device, function, offset));
+        in32(CONFIG_DATA)
+    }
+}
+
+pub fn write_config32(bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+    if let Some(address) = ecam_address(bus, device, function, offset) {
+        unsafe { (address as *mut u32).write_volatile(value) };
+        return;
+    }
+    if offset > 0xff {
+        return;
+    }
+    unsafe {
+        out32(CONFIG_ADDRESS, config_address(bus, device, function, offset));
+        out32(CONFIG_DATA, value);
+    }
+}
+
+fn read_bars(bus: u8, device: u8, function: u8, header_type: u8) -> [Bar; 6] {
+    let mut bars = [Bar::None; 6];
+    // Bridges (header type 1) only have two BARs
+    let count = if header_type & 0x7f == 0 { 6 } else { 2 };
+    let mut index = 0;
+    while index < count {
+        let offset = register::BAR0 as u16 + 4 * index as u16;
+        let low = read_config32(bus, device, function, offset);
+        if low & 1 != 0 {
+            bars[index] = Bar::Io { port: low & !0b11 };
+        } else {
+            let is_64bit = (low >> 1) & 0b11 == 0b10;
+            let mut address = (low & !0b1111) as u64;
+            if is_64bit && index + 1 < count {
+                address |= (read_config32(bus, device, function, offset + 4) as u64) << 32;
+            }
+            if address != 0 {
+                bars[index] = Bar::Memory {
+                    address,
+                    is_64bit,
+                    prefetchable: low & 0b1000 != 0,
+                };
+            }
+            if is_64bit {
+                // The upper half occupies the next slot
+                index += 1;
+            }
+        }
+        index += 1;
+    }
+    bars
+}
+
+static mut DEVICES: Vec<Device> = Vec::new();
+
+fn scan_function(bus: u8, device: u8, function: u8, devices: &mut Vec<Device>) {
+    let id = read_config32(bus, device, function, register::VENDOR_ID as u16);
+    let class = read_config32(bus, device, function, register::CLASS_CODE as u16);
+    let header_type = (read_config32(bus, device, function, register::HEADER_TYPE as u16) >> 16) as u8;
+    let class_code = ClassCode {
+        base: (class >> 24) as u8,
+        sub: (class >> 16) as u8,
+        interface: (class >> 8) as u8,
+    };
+
+    devices.push(Device {
+        bus,
+        device,
+        function,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        revision: class as u8,
+        class_code,
+        header_type,
+        bars: read_bars(bus, device, function, header_type),
+    });
+
+    if class_code.is_pci_bridge() {
+        let secondary_bus = (read_config32(bus, device, function, register::BUS_NUMBERS as u16) >> 8) as u8;
+        // A bridge pointing back at its own bus would recurse forever
+        if secondary_bus > bus {
+            scan_bus(secondary_bus, devices);
+        }
+    }
+}
+
+fn vendor_id(bus: u8, device: u8, function: u8) -> u16 {
+    read_config32(bus, device, function, register::VENDOR_ID as u16) as u16
+}
+
+fn is_multi_function(bus: u8, device: u8) -> bool {
+    (read_config32(bus, device, 0, register::HEADER_TYPE as u16) >> 16) as u8 & HEADER_TYPE_MULTI_FUNCTION != 0
+}
+
+fn scan_device(bus: u8, device: u8, devices: &mut Vec<Device>) {
+    scan_function(bus, device, 0, devices);
+    if !is_multi_function(bus, device) {
+        return;
+    }
+    for function in 1..MAX_FUNCTION {
+        if vendor_id(bus, device, function) != INVALID_VENDOR_ID {
+            scan_function(bus, device, function, devices);
+        }
+    }
+}
+
+fn scan_bus(bus: u8, devices: &mut Vec<Device>) {
+    for device in 0..MAX_DEVICE {
+        if vendor_id(bus, device, 0) != INVALID_VENDOR_ID {
+            scan_device(bus, device, devices);
+        }
+    }
+}
+
+/// Choose the configuration mechanism and enumerate every function reachable
+/// from the host bridges, following PCI-to-PCI bridges.
+pub fn init() {
+    if let Some(entry) = acpi::tables().mcfg.iter().find(|entry| entry.segment_group == 0) {
+        unsafe {
+            ACCESS = ConfigAccess::Ecam {
+                base: entry.base_address as usize,
+                start_bus: entry.start_bus,
+                end_bus: entry.end_bus,
+            };
+        }
+    }
+
+    let mut devices = Vec::new();
+    if !is_multi_function(0, 0) {
+        scan_bus(0, &mut devices);
+    } else {
+        // Each function of a multi-function host bridge is the root of one bus
+        for function in 0..MAX_FUNCTION {
+            if vendor_id(0, 0, function) != INVALID_VENDOR_ID {
+                scan_bus(function, &mut devices);
+            }
+        }
+    }
+    unsafe {
+        DEVICES = devices;
+    }
+}
+
+pub fn devices() -> &'static [Device] {
+    unsafe { &*core::ptr::addr_of!(DEVICES) }
+}
+
+pub fn uses_ecam() -> bool {
+    matches!(unsafe { ACCESS }, ConfigAccess::Ecam { .. })
+}
+
+fn class_name(class_code: &ClassCode) -> &'static str {
+    match (class_code.base, class_code.sub, class_code.interface) {
+        (0x01, 0x01, _) => "IDE controller",
+        (0x01, 0x06, 0x01) => "SATA controller (AHCI)",
+        (0x01, 0x08, _) => "NVMe controller",
+        (0x01, _, _) => "Mass storage controller",
+        (0x02, 0x00, _) => "Ethernet controller",
+        (0x02, _, _) => "Network controller",
+        (0x03, 0x00, _) => "VGA compatible controller",
+        (0x03, _, _) => "Display controller",
+        (0x04, _, _) => "Multimedia controller",
+        (0x06, 0x00, _) => "Host bridge",
+        (0x06, 0x01, _) => "ISA bridge",
+        (0x06, 0x04, _) => "PCI bridge",
+        (0x06, _, _) => "Bridge",
+        (0x0c, 0x03, 0x00) => "USB controller (UHCI)",
+        (0x0c, 0x03, 0x10) => "USB controller (OHCI)",
+        (0x0c, 0x03, 0x20) => "USB controller (EHCI)",
+        (0x0c, 0x03, 0x30) => "USB controller (xHCI)",
+        (0x0c, 0x05, _) => "SMBus",
+        (0x0c, _, _) => "Serial bus controller",
+        _ => "Unknown device",
+    }
+}
+
+/// Print the devices found by `init()` in an lspci like format
+pub fn print_devices() {
+    for device in devices() {
+        let interrupts = match (
+            device.find_capability(capability::MSIX).is_some(),
+            device.find_capability(capability::MSI).is_some(),
+        ) {
+            (true, true) => " MSI-X MSI",
+            (true, false) => " MSI-X",
+            (false, true) => " MSI",
+            (false, false) => "",
+        };
+        println!(
+            "{:02x}:{:02x}.{} {} [{:04x}:{:04x}] (class {:02x}{:02x}{:02x}, rev {:02x}, header {:02x}){}",
+            device.bus,
+            device.device,
+            device.function,
+            class_name(&device.class_code),
+            device.vendor_id,
+            device.device_id,
+            device.class_code.base,
+            device.class_code.sub,
+            device.class_code.interface,
+            device.revision,
+            device.header_type,
+            interrupts
+        );
+    }
+}
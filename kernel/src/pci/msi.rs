@@ -0,0 +1,283 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Message signaled interrupts (MSI and MSI-X) delivered to the Local APIC
+
+use super::{capability, command, Bar, Device};
+use crate::apic;
+use crate::interrupt::{self, InterruptHandler};
+
+/// Messages written to this window are interrupts for the Local APIC
+const MESSAGE_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+mod msi_control {
+    pub const ENABLE: u16 = 1 << 0;
+    /// log2 of the number of vectors the function can request, bits 1-3
+    pub const MULTIPLE_MESSAGE_CAPABLE_SHIFT: u16 = 1;
+    /// log2 of the number of vectors allocated, bits 4-6
+    pub const MULTIPLE_MESSAGE_ENABLE_SHIFT: u16 = 4;
+    pub const ADDRESS_64BIT: u16 = 1 << 7;
+    pub const PER_VECTOR_MASKING: u16 = 1 << 8;
+}
+
+mod msix_control {
+    pub const TABLE_SIZE_MASK: u16 = 0x07ff;
+    pub const FUNCTION_MASK: u16 = 1 << 14;
+    pub const ENABLE: u16 = 1 << 15;
+}
+
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_VECTOR_CONTROL_MASKED: u32 = 1;
+
+#[derive(Debug)]
+pub enum MsiError {
+    /// The function has neither an MSI nor an MSI-X capability
+    NotSupported,
+    /// More vectors were requested than the function supports
+    TooManyVectors,
+    /// The dynamic IDT vector range is exhausted
+    NoFreeVectors,
+    /// The MSI-X table lives in a BAR that is not memory mapped
+    InvalidBar,
+}
+
+/// Edge triggered, fixed delivery to the running CPU.
+///
+/// The destination field is 8 bits wide, so x2APIC IDs above 255 would need
+/// interrupt remapping, which is not supported.
+fn message_address() -> u32 {
+    MESSAGE_ADDRESS_BASE | (apic::id() & 0xff) << 12
+}
+
+fn message_data(vector: u8) -> u32 {
+    vector as u32
+}
+
+fn install_handlers(handlers: &[InterruptHandler]) -> Result<u8, MsiError> {
+    let first_vector = interrupt::allocate_vectors(handlers.len()).ok_or(MsiError::NoFreeVectors)?;
+    for (i, &handler) in handlers.iter().enumerate() {
+        interrupt::set_handler(first_vector + i as u8, handler);
+    }
+    Ok(first_vector)
+}
+
+/// Vectors configured through the MSI capability
+pub struct Msi {
+    device: Device,
+    mask_offset: Option<u16>,
+    first_vector: u8,
+    count: usize,
+}
+
+impl Msi {
+    pub fn vector(&self, index: usize) -> u8 {
+        self.first_vector + index as u8
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Per-vector masking is optional for MSI; returns false if unsupported
+    pub fn mask(&self, index: usize) -> bool {
+        self.update_mask(index, true)
+    }
+
+    pub fn unmask(&self, index: usize) -> bool {
+        self.update_mask(index, false)
+    }
+
+    fn update_mask(&self, index: usize, masked: bool) -> bool {
+        let offset = match self.mask_offset {
+            Some(offset) if index < self.count => offset,
+            _ => return false,
+        };
+        let bits = self.device.read_config32(offset);
+        let bits = if masked { bits | 1 << index } else { bits & !(1 << index) };
+        self.device.write_config32(offset, bits);
+        true
+    }
+}
+
+/// Configure MSI with one handler per vector. The number of handlers must be a
+/// power of two no larger than the function advertises; consecutive vectors are
+/// allocated because the device ORs the vector index into the message data.
+pub fn enable_msi(device: &Device, handlers: &[InterruptHandler]) -> Result<Msi, MsiError> {
+    let offset = device.find_capability(capability::MSI).ok_or(MsiError::NotSupported)?;
+    let control = device.read_config16(offset + 2);
+    let capable = 1 << ((control >> msi_control::MULTIPLE_MESSAGE_CAPABLE_SHIFT) & 0b111);
+    if handlers.is_empty() || handlers.len() > capable || !handlers.len().is_power_of_two() {
+        return Err(MsiError::TooManyVectors);
+    }
+    let first_vector = install_handlers(handlers)?;
+
+    let is_64bit = control & msi_control::ADDRESS_64BIT != 0;
+    let data_offset = if is_64bit { offset + 0x0c } else { offset + 0x08 };
+    device.write_config32(offset + 4, message_address());
+    if is_64bit {
+        device.write_config32(offset + 8, 0);
+    }
+    device.write_config16(data_offset, message_data(first_vector) as u16);
+
+    let mask_offset = if control & msi_control::PER_VECTOR_MASKING != 0 {
+        let mask_offset = data_offset + 4;
+        device.write_config32(mask_offset, 0);
+        Some(mask_offset)
+    } else {
+        None
+    };
+
+    let log2_count = handlers.len().trailing_zeros() as u16;
+    let control = (control & !(0b111 << msi_control::MULTIPLE_MESSAGE_ENABLE_SHIFT))
+        | log2_count << msi_control::MULTIPLE_MESSAGE_ENABLE_SHIFT
+        | msi_control::ENABLE;
+    device.write_config16(offset + 2, control);
+    device.set_command(command::BUS_MASTER | command::INTERRUPT_DISABLE);
+
+    Ok(Msi {
+        device: *device,
+        mask_offset,
+        first_vector,
+        count: handlers.len(),
+    })
+}
+
+/// Vectors configured through the MSI-X table
+pub struct MsiX {
+    table: usize,
+    first_vector: u8,
+    count: usize,
+}
+
+impl MsiX {
+    pub fn vector(&self, index: usize) -> u8 {
+        self.first_vector + index as u8
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    fn vector_control(&self, index: usize) -> *mut u32 {
+        (self.table + index * MSIX_ENTRY_SIZE + 12) as *mut u32
+    }
+
+    pub fn mask(&self, index: usize) -> bool {
+        if index >= self.count {
+            return false;
+        }
+        unsafe {
+            let control = self.vector_control(index);
+            control.write_volatile(control.read_volatile() | MSIX_VECTOR_CONTROL_MASKED);
+        }
+        true
+    }
+
+    pub fn unmask(&self, index: usize) -> bool {
+        if index >= self.count {
+            return false;
+        }
+        unsafe {
+            let control = self.vector_control(index);
+            control.write_volatile(control.read_volatile() & !MSIX_VECTOR_CONTROL_MASKED);
+        }
+        true
+    }
+}
+
+/// Configure MSI-X, routing table entry `i` to `handlers[i]`. Unused table
+/// entries stay masked.
+pub fn enable_msix(device: &Device, handlers: &[InterruptHandler]) -> Result<MsiX, MsiError> {
+    let offset = device.find_capability(capability::MSIX).ok_or(MsiError::NotSupported)?;
+    let control = device.read_config16(offset + 2);
+    let table_size = (control & msix_control::TABLE_SIZE_MASK) as usize + 1;
+    if handlers.is_empty() || handlers.len() > table_size {
+        return Err(MsiError::TooManyVectors);
+    }
+
+    let table_location = device.read_config32(offset + 4);
+    let table = match device.bars.get((table_location & 0b111) as usize) {
+        Some(Bar::Memory { address, .. }) => (*address + (table_location & !0b111) as u64) as usize,
+        _ => return Err(MsiError::InvalidBar),
+    };
+    let first_vector = install_handlers(handlers)?;
+
+    // Hold every vector off while the table is being written
+    device.write_config16(offset + 2, control | msix_control::ENABLE | msix_control::FUNCTION_MASK);
+    device.set_command(command::MEMORY_SPACE | command::BUS_MASTER | command::INTERRUPT_DISABLE);
+
+    let address = message_address();
+    for index in 0..table_size {
+        let entry = (table + index * MSIX_ENTRY_SIZE) as *mut u32;
+        unsafe {
+            if index < handlers.len() {
+                entry.write_volatile(address);
+                entry.add(1).write_volatile(0);
+                entry.add(2).write_volatile(message_data(first_vector + index as u8));
+                entry.add(3).write_volatile(0);
+            } else {
+                entry.add(3).write_volatile(MSIX_VECTOR_CONTROL_MASKED);
+            }
+        }
+    }
+
+    device.write_config16(offset + 2, (control | msix_control::ENABLE) & !msix_control::FUNCTION_MASK);
+
+    Ok(MsiX {
+        table,
+        first_vector,
+        count: handlers.len(),
+    })
+}
+
+/// Interrupts configured by `enable_interrupts()`
+pub enum Interrupts {
+    Msi(Msi),
+    MsiX(MsiX),
+}
+
+impl Interrupts {
+    pub fn vector(&self, index: usize) -> u8 {
+        match self {
+            Interrupts::Msi(msi) => msi.vector(index),
+            Interrupts::MsiX(msix) => msix.vector(index),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        match self {
+            Interrupts::Msi(msi) => msi.count(),
+            Interrupts::MsiX(msix) => msix.count(),
+        }
+    }
+
+    pub fn mask(&self, index: usize) -> bool {
+        match self {
+            Interrupts::Msi(msi) => msi.mask(index),
+            Interrupts::MsiX(msix) => msix.mask(index),
+        }
+    }
+
+    pub fn unmask(&self, index: usize) -> bool {
+        match self {
+            Interrupts::Msi(msi) => msi.unmask(index),
+            Interrupts::MsiX(msix) => msix.unmask(index),
+        }
+    }
+}
+
+/// Prefer MSI-X, falling back to MSI if the function lacks MSI-X or it cannot
+/// be set up. Handlers must send EOI to the Local APIC.
+pub fn enable_interrupts(device: &Device, handlers: &[InterruptHandler]) -> Result<Interrupts, MsiError> {
+    if device.find_capability(capability::MSIX).is_some() {
+        match enable_msix(device, handlers) {
+            Ok(msix) => return Ok(Interrupts::MsiX(msix)),
+            // Report why MSI-X failed rather than a plain NotSupported
+            Err(err) if device.find_capability(capability::MSI).is_none() => return Err(err),
+            Err(_) => {}
+        }
+    }
+    enable_msi(device, handlers).map(Interrupts::Msi)
+}
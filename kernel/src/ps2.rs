@@ -0,0 +1,319 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! PS/2 keyboard and mouse behind the i8042 controller
+
+use crate::apic;
+use crate::input::{self, InputEvent, Modifiers};
+use crate::interrupt::{vector, InterruptFrame};
+use crate::io_port::{in8, out8};
+use crate::ioapic;
+use crate::timer;
+
+mod scancode;
+
+use scancode::{Decoder, ScancodeSet};
+
+const DATA_PORT: u16 = 0x60;
+/// Status on read, command on write
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+mod command {
+    pub const READ_CONFIG: u8 = 0x20;
+    pub const WRITE_CONFIG: u8 = 0x60;
+    pub const DISABLE_SECOND_PORT: u8 = 0xa7;
+    pub const ENABLE_SECOND_PORT: u8 = 0xa8;
+    pub const TEST_SECOND_PORT: u8 = 0xa9;
+    pub const SELF_TEST: u8 = 0xaa;
+    pub const TEST_FIRST_PORT: u8 = 0xab;
+    pub const DISABLE_FIRST_PORT: u8 = 0xad;
+    pub const ENABLE_FIRST_PORT: u8 = 0xae;
+    /// The next data byte goes to the second (mouse) port
+    pub const WRITE_SECOND_PORT: u8 = 0xd4;
+}
+
+const CONFIG_FIRST_PORT_INTERRUPT: u8 = 1 << 0;
+const CONFIG_SECOND_PORT_INTERRUPT: u8 = 1 << 1;
+const CONFIG_SECOND_PORT_CLOCK_DISABLED: u8 = 1 << 5;
+/// The controller translates set 2 scancodes into set 1
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+mod device_command {
+    pub const SET_SAMPLE_RATE: u8 = 0xf3;
+    pub const GET_ID: u8 = 0xf2;
+    pub const ENABLE_REPORTING: u8 = 0xf4;
+    pub const RESET: u8 = 0xff;
+}
+
+const ACKNOWLEDGE: u8 = 0xfa;
+const RESET_PASSED: u8 = 0xaa;
+/// Device ID a mouse reports after the IntelliMouse sample rate sequence
+const MOUSE_ID_INTELLIMOUSE: u8 = 3;
+
+const KEYBOARD_IRQ: u8 = 1;
+const MOUSE_IRQ: u8 = 12;
+
+const TIMEOUT_MS: u64 = 100;
+/// Devices take a while to finish their power-on self test after a reset
+const RESET_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug)]
+pub enum Ps2Error {
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(u8),
+    NoKeyboard,
+}
+
+fn wait(condition: impl Fn() -> bool, timeout_ms: u64) -> bool {
+    let deadline = timer::uptime() + timeout_ms * 1_000_000;
+    while !condition() {
+        if timer::uptime() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn status() -> u8 {
+    unsafe { in8(STATUS_COMMAND_PORT) }
+}
+
+fn write_command(command: u8) -> Result<(), Ps2Error> {
+    if !wait(|| status() & STATUS_INPUT_FULL == 0, TIMEOUT_MS) {
+        return Err(Ps2Error::Timeout);
+    }
+    unsafe { out8(STATUS_COMMAND_PORT, command) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), Ps2Error> {
+    if !wait(|| status() & STATUS_INPUT_FULL == 0, TIMEOUT_MS) {
+        return Err(Ps2Error::Timeout);
+    }
+    unsafe { out8(DATA_PORT, data) };
+    Ok(())
+}
+
+fn read_data(timeout_ms: u64) -> Result<u8, Ps2Error> {
+    if !wait(|| status() & STATUS_OUTPUT_FULL != 0, timeout_ms) {
+        return Err(Ps2Error::Timeout);
+    }
+    Ok(unsafe { in8(DATA_PORT) })
+}
+
+fn flush_output() {
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        unsafe { in8(DATA_PORT) };
+    }
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    write_command(command::READ_CONFIG)?;
+    read_data(TIMEOUT_MS)
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_command(command::WRITE_CONFIG)?;
+    write_data(config)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Port {
+    First,
+    Second,
+}
+
+/// Send a byte to a device and wait for its acknowledgement
+fn send(port: Port, byte: u8) -> Result<(), Ps2Error> {
+    if port == Port::Second {
+        write_command(command::WRITE_SECOND_PORT)?;
+    }
+    write_data(byte)?;
+    match read_data(TIMEOUT_MS)? {
+        ACKNOWLEDGE => Ok(()),
+        _ => Err(Ps2Error::Timeout),
+    }
+}
+
+fn reset_device(port: Port) -> Result<(), Ps2Error> {
+    send(port, device_command::RESET)?;
+    match read_data(RESET_TIMEOUT_MS)? {
+        RESET_PASSED => {}
+        response => return Err(Ps2Error::SelfTestFailed(response)),
+    }
+    // A mouse follows up with its device ID
+    if port == Port::Second {
+        let _ = read_data(TIMEOUT_MS);
+    }
+    Ok(())
+}
+
+/// Magic sample rate sequence that unlocks the wheel; returns true if it worked
+fn enable_wheel() -> Result<bool, Ps2Error> {
+    for rate in [200, 100, 80] {
+        send(Port::Second, device_command::SET_SAMPLE_RATE)?;
+        send(Port::Second, rate)?;
+    }
+    send(Port::Second, device_command::GET_ID)?;
+    Ok(read_data(TIMEOUT_MS)? == MOUSE_ID_INTELLIMOUSE)
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    modifiers: Modifiers,
+}
+
+struct Mouse {
+    packet: [u8; 4],
+    received: usize,
+    packet_length: usize,
+}
+
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_OVERFLOW: u8 = 0b11 << 6;
+
+impl Mouse {
+    fn feed(&mut self, byte: u8) {
+        // Resynchronize on a byte that cannot start a packet
+        if self.received == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_length {
+            return;
+        }
+        self.received = 0;
+
+        let flags = self.packet[0];
+        if flags & PACKET_OVERFLOW != 0 {
+            return;
+        }
+        // 9-bit two's complement; PS/2 Y grows upwards
+        let dx = self.packet[1] as i32 - if flags & PACKET_X_SIGN != 0 { 0x100 } else { 0 };
+        let dy = self.packet[2] as i32 - if flags & PACKET_Y_SIGN != 0 { 0x100 } else { 0 };
+        // The fourth byte of wheel mice is read only to keep packets aligned
+        input::push_event(InputEvent::Mouse {
+            dx,
+            dy: -dy,
+            buttons: flags & 0b111,
+        });
+    }
+}
+
+static mut KEYBOARD: Keyboard = Keyboard {
+    decoder: Decoder::new(ScancodeSet::Set2),
+    modifiers: Modifiers(0),
+};
+
+static mut MOUSE: Option<Mouse> = None;
+
+extern "x86-interrupt" fn keyboard_handler(_frame: InterruptFrame) {
+    let byte = unsafe { in8(DATA_PORT) };
+    let keyboard = unsafe { &mut *core::ptr::addr_of_mut!(KEYBOARD) };
+    if let Some((keycode, pressed)) = keyboard.decoder.feed(byte) {
+        keyboard.modifiers.update(keycode, pressed);
+        input::push_event(InputEvent::key(keycode, keyboard.modifiers, pressed));
+    }
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn mouse_handler(_frame: InterruptFrame) {
+    let byte = unsafe { in8(DATA_PORT) };
+    if let Some(mouse) = unsafe { (*core::ptr::addr_of_mut!(MOUSE)).as_mut() } {
+        mouse.feed(byte);
+    }
+    apic::end_of_interrupt();
+}
+
+/// Initialize the controller and whatever is plugged into it. A missing mouse
+/// is not an error. Needs interrupts enabled for its timeouts.
+pub fn init() -> Result<(), Ps2Error> {
+    write_command(command::DISABLE_FIRST_PORT)?;
+    write_command(command::DISABLE_SECOND_PORT)?;
+    flush_output();
+
+    let mut config = read_config()? & !(CONFIG_FIRST_PORT_INTERRUPT | CONFIG_SECOND_PORT_INTERRUPT);
+    write_config(config)?;
+
+    write_command(command::SELF_TEST)?;
+    match read_data(TIMEOUT_MS)? {
+        SELF_TEST_PASSED => {}
+        response => return Err(Ps2Error::SelfTestFailed(response)),
+    }
+    // Some controllers reset their configuration during the self test
+    write_config(config)?;
+
+    // Enabling the second port clears its clock-disable bit only on dual channel controllers
+    write_command(command::ENABLE_SECOND_PORT)?;
+    let dual_channel = read_config()? & CONFIG_SECOND_PORT_CLOCK_DISABLED == 0;
+    write_command(command::DISABLE_SECOND_PORT)?;
+
+    write_command(command::TEST_FIRST_PORT)?;
+    match read_data(TIMEOUT_MS)? {
+        PORT_TEST_PASSED => {}
+        response => return Err(Ps2Error::PortTestFailed(response)),
+    }
+    let mut has_mouse = dual_channel && {
+        write_command(command::TEST_SECOND_PORT)?;
+        read_data(TIMEOUT_MS)? == PORT_TEST_PASSED
+    };
+
+    write_command(command::ENABLE_FIRST_PORT)?;
+    if has_mouse {
+        write_command(command::ENABLE_SECOND_PORT)?;
+    }
+    reset_device(Port::First).map_err(|_| Ps2Error::NoKeyboard)?;
+    let set = if config & CONFIG_TRANSLATION != 0 { ScancodeSet::Set1 } else { ScancodeSet::Set2 };
+    unsafe { (*core::ptr::addr_of_mut!(KEYBOARD)).decoder = Decoder::new(set) };
+
+    let mut wheel = false;
+    if has_mouse {
+        let mouse = reset_device(Port::Second).and_then(|_| enable_wheel());
+        match mouse.and_then(|found| send(Port::Second, device_command::ENABLE_REPORTING).map(|_| found)) {
+            Ok(found) => wheel = found,
+            Err(_) => has_mouse = false,
+        }
+    }
+    if has_mouse {
+        unsafe {
+            MOUSE = Some(Mouse {
+                packet: [0; 4],
+                received: 0,
+                packet_length: if wheel { 4 } else { 3 },
+            });
+        }
+    }
+    flush_output();
+
+    ioapic::register_isa_irq(KEYBOARD_IRQ, vector::PS2_KEYBOARD, keyboard_handler);
+    config |= CONFIG_FIRST_PORT_INTERRUPT;
+    if has_mouse {
+        ioapic::register_isa_irq(MOUSE_IRQ, vector::PS2_MOUSE, mouse_handler);
+        config = (config | CONFIG_SECOND_PORT_INTERRUPT) & !CONFIG_SECOND_PORT_CLOCK_DISABLED;
+    }
+    write_config(config)?;
+
+    println!(
+        "PS/2: keyboard (scancode {:?}), {}",
+        set,
+        match (has_mouse, wheel) {
+            (true, true) => "mouse with wheel",
+            (true, false) => "mouse",
+            _ => "no mouse",
+        }
+    );
+    Ok(())
+}
@@ -0,0 +1,254 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Scancode sets 1 and 2 decoded into HID usage IDs
+
+const PREFIX_EXTENDED: u8 = 0xe0;
+/// Only used by the Pause key, which has no break code
+const PREFIX_PAUSE: u8 = 0xe1;
+const SET2_BREAK: u8 = 0xf0;
+const SET1_BREAK_BIT: u8 = 0x80;
+
+/// Bytes following E1 in the Pause sequence of each set
+const SET1_PAUSE_LENGTH: u8 = 5;
+const SET2_PAUSE_LENGTH: u8 = 7;
+
+/// Usage ID of the Pause key
+const KEYCODE_PAUSE: u8 = 0x48;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// XT codes, also what the controller produces when translating set 2
+    Set1,
+    Set2,
+}
+
+/// Assembles multi-byte scancodes into `(keycode, pressed)` pairs
+pub struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    release: bool,
+    /// Remaining bytes of a Pause sequence to swallow
+    skip: u8,
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            extended: false,
+            release: false,
+            skip: 0,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<(u8, bool)> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return if self.skip == 0 { Some((KEYCODE_PAUSE, true)) } else { None };
+        }
+        match byte {
+            PREFIX_EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+            PREFIX_PAUSE => {
+                self.skip = match self.set {
+                    ScancodeSet::Set1 => SET1_PAUSE_LENGTH,
+                    ScancodeSet::Set2 => SET2_PAUSE_LENGTH,
+                };
+                return None;
+            }
+            SET2_BREAK if self.set == ScancodeSet::Set2 => {
+                self.release = true;
+                return None;
+            }
+            _ => {}
+        }
+
+        let extended = core::mem::replace(&mut self.extended, false);
+        let (code, pressed) = match self.set {
+            ScancodeSet::Set1 => (byte & !SET1_BREAK_BIT, byte & SET1_BREAK_BIT == 0),
+            ScancodeSet::Set2 => (byte, !core::mem::replace(&mut self.release, false)),
+        };
+        let keycode = match self.set {
+            ScancodeSet::Set1 => set1_to_keycode(code, extended),
+            ScancodeSet::Set2 => set2_to_keycode(code, extended),
+        }?;
+        Some((keycode, pressed))
+    }
+}
+
+fn set1_to_keycode(code: u8, extended: bool) -> Option<u8> {
+    if extended {
+        // E0 2A / E0 36 are fake shifts sent around some extended keys and map to None
+        return match code {
+            0x1c => Some(0x58),
+            0x1d => Some(0xe4),
+            0x35 => Some(0x54),
+            0x37 => Some(0x46),
+            0x38 => Some(0xe6),
+            0x47 => Some(0x4a),
+            0x48 => Some(0x52),
+            0x49 => Some(0x4b),
+            0x4b => Some(0x50),
+            0x4d => Some(0x4f),
+            0x4f => Some(0x4d),
+            0x50 => Some(0x51),
+            0x51 => Some(0x4e),
+            0x52 => Some(0x49),
+            0x53 => Some(0x4c),
+            0x5b => Some(0xe3),
+            0x5c => Some(0xe7),
+            0x5d => Some(0x65),
+            _ => None,
+        };
+    }
+    #[rustfmt::skip]
+    const TABLE: [u8; 0x80] = [
+        // 0x00
+        0x00, 0x29, 0x1e, 0x1f, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x2d, 0x2e, 0x2a, 0x2b,
+        // 0x10
+        0x14, 0x1a, 0x08, 0x15, 0x17, 0x1c, 0x18, 0x0c, 0x12, 0x13, 0x2f, 0x30, 0x28, 0xe0, 0x04, 0x16,
+        // 0x20
+        0x07, 0x09, 0x0a, 0x0b, 0x0d, 0x0e, 0x0f, 0x33, 0x34, 0x35, 0xe1, 0x31, 0x1d, 0x1b, 0x06, 0x19,
+        // 0x30
+        0x05, 0x11, 0x10, 0x36, 0x37, 0x38, 0xe5, 0x55, 0xe2, 0x2c, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e,
+        // 0x40
+        0x3f, 0x40, 0x41, 0x42, 0x43, 0x53, 0x47, 0x5f, 0x60, 0x61, 0x56, 0x5c, 0x5d, 0x5e, 0x57, 0x59,
+        // 0x50
+        0x5a, 0x5b, 0x62, 0x63, 0x00, 0x00, 0x64, 0x44, 0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // 0x60
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // 0x70: Katakana/Hiragana, Ro, Henkan, Muhenkan, Yen
+        0x88, 0x00, 0x00, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x8a, 0x00, 0x8b, 0x00, 0x89, 0x00, 0x00,
+    ];
+    match TABLE[code as usize] {
+        0 => None,
+        keycode => Some(keycode),
+    }
+}
+
+fn set2_to_keycode(code: u8, extended: bool) -> Option<u8> {
+    if extended {
+        // E0 12 / E0 59 are fake shifts and map to None
+        return match code {
+            0x11 => Some(0xe6),
+            0x14 => Some(0xe4),
+            0x1f => Some(0xe3),
+            0x27 => Some(0xe7),
+            0x2f => Some(0x65),
+            0x4a => Some(0x54),
+            0x5a => Some(0x58),
+            0x69 => Some(0x4d),
+            0x6b => Some(0x50),
+            0x6c => Some(0x4a),
+            0x70 => Some(0x49),
+            0x71 => Some(0x4c),
+            0x72 => Some(0x51),
+            0x74 => Some(0x4f),
+            0x75 => Some(0x52),
+            0x7a => Some(0x4e),
+            0x7c => Some(0x46),
+            0x7d => Some(0x4b),
+            _ => None,
+        };
+    }
+    let keycode = match code {
+        0x01 => 0x42,
+        0x03 => 0x3e,
+        0x04 => 0x3c,
+        0x05 => 0x3a,
+        0x06 => 0x3b,
+        0x07 => 0x45,
+        0x09 => 0x43,
+        0x0a => 0x41,
+        0x0b => 0x3f,
+        0x0c => 0x3d,
+        0x0d => 0x2b,
+        0x0e => 0x35,
+        0x11 => 0xe2,
+        0x12 => 0xe1,
+        0x13 => 0x88,
+        0x14 => 0xe0,
+        0x15 => 0x14,
+        0x16 => 0x1e,
+        0x1a => 0x1d,
+        0x1b => 0x16,
+        0x1c => 0x04,
+        0x1d => 0x1a,
+        0x1e => 0x1f,
+        0x21 => 0x06,
+        0x22 => 0x1b,
+        0x23 => 0x07,
+        0x24 => 0x08,
+        0x25 => 0x21,
+        0x26 => 0x20,
+        0x29 => 0x2c,
+        0x2a => 0x19,
+        0x2b => 0x09,
+        0x2c => 0x17,
+        0x2d => 0x15,
+        0x2e => 0x22,
+        0x31 => 0x11,
+        0x32 => 0x05,
+        0x33 => 0x0b,
+        0x34 => 0x0a,
+        0x35 => 0x1c,
+        0x36 => 0x23,
+        0x3a => 0x10,
+        0x3b => 0x0d,
+        0x3c => 0x18,
+        0x3d => 0x24,
+        0x3e => 0x25,
+        0x41 => 0x36,
+        0x42 => 0x0e,
+        0x43 => 0x0c,
+        0x44 => 0x12,
+        0x45 => 0x27,
+        0x46 => 0x26,
+        0x49 => 0x37,
+        0x4a => 0x38,
+        0x4b => 0x0f,
+        0x4c => 0x33,
+        0x4d => 0x13,
+        0x4e => 0x2d,
+        0x51 => 0x87,
+        0x52 => 0x34,
+        0x54 => 0x2f,
+        0x55 => 0x2e,
+        0x58 => 0x39,
+        0x59 => 0xe5,
+        0x5a => 0x28,
+        0x5b => 0x30,
+        0x5d => 0x31,
+        0x61 => 0x64,
+        0x64 => 0x8a,
+        0x66 => 0x2a,
+        0x67 => 0x8b,
+        0x69 => 0x59,
+        0x6a => 0x89,
+        0x6b => 0x5c,
+        0x6c => 0x5f,
+        0x70 => 0x62,
+        0x71 => 0x63,
+        0x72 => 0x5a,
+        0x73 => 0x5d,
+        0x74 => 0x5e,
+        0x75 => 0x60,
+        0x76 => 0x29,
+        0x77 => 0x53,
+        0x78 => 0x44,
+        0x79 => 0x57,
+        0x7a => 0x5b,
+        0x7b => 0x56,
+        0x7c => 0x55,
+        0x7d => 0x61,
+        0x7e => 0x47,
+        0x83 => 0x40,
+        _ => return None,
+    };
+    Some(keycode)
+}
@@ -0,0 +1,120 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Dump the screen as a binary PPM over the serial port.
+//!
+//! The image is base64 encoded between marker lines so that a test harness can
+//! cut it out of the serial log (see `tools/extract_screenshot.py`):
+//!
+//! ```text
+//! -----BEGIN RIKAN SCREENSHOT ppm 1024x768 2359312-----
+//! UDYKMTAyNCA3NjgKMjU1Cv...
+//! -----END RIKAN SCREENSHOT crc32=1a2b3c4d-----
+//! ```
+//!
+//! The size in the first line and the CRC-32 are those of the decoded PPM.
+
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::graphics::{self, DrawTarget};
+use crate::serial::SerialPort;
+
+const BASE64_TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64_LINE_LENGTH: usize = 76;
+
+/// Streaming base64 encoder that also keeps a CRC-32 of its input
+struct Base64Writer<'a> {
+    port: &'a mut SerialPort,
+    pending: [u8; 3],
+    pending_len: usize,
+    column: usize,
+    crc: u32,
+}
+
+impl<'a> Base64Writer<'a> {
+    fn new(port: &'a mut SerialPort) -> Self {
+        Self { port, pending: [0; 3], pending_len: 0, column: 0, crc: !0 }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.update_crc(byte);
+            self.pending[self.pending_len] = byte;
+            self.pending_len += 1;
+            if self.pending_len == 3 {
+                self.emit_group();
+            }
+        }
+    }
+
+    fn update_crc(&mut self, byte: u8) {
+        self.crc ^= byte as u32;
+        for _ in 0..8 {
+            self.crc = if self.crc & 1 != 0 { (self.crc >> 1) ^ 0xedb8_8320 } else { self.crc >> 1 };
+        }
+    }
+
+    fn emit_group(&mut self) {
+        let [a, b, c] = self.pending;
+        let group = (a as u32) << 16 | (b as u32) << 8 | c as u32;
+        for i in 0..4 {
+            let symbol = if i <= self.pending_len {
+                BASE64_TABLE[(group >> (18 - 6 * i) & 0x3f) as usize]
+            } else {
+                b'='
+            };
+            self.port.write_byte(symbol);
+        }
+        self.pending = [0; 3];
+        self.pending_len = 0;
+        self.column += 4;
+        if self.column >= BASE64_LINE_LENGTH {
+            self.port.write_byte(b'\n');
+            self.column = 0;
+        }
+    }
+
+    /// Flush the last partial group and return the CRC-32
+    fn finish(mut self) -> u32 {
+        if self.pending_len > 0 {
+            self.emit_group();
+        }
+        if self.column > 0 {
+            self.port.write_byte(b'\n');
+        }
+        !self.crc
+    }
+}
+
+/// Flush pending drawing and send the whole screen to `port`.
+///
+/// Holds the GUI lock throughout so that the frame stays consistent.
+pub fn capture(port: &mut SerialPort) {
+    graphics::with_lock(|| write_screen(port));
+}
+
+fn write_screen(port: &mut SerialPort) {
+    graphics::flush();
+    let screen = graphics::screen();
+    let (width, height) = (screen.width(), screen.height());
+
+    let mut header = String::<32>::new();
+    write!(header, "P6\n{} {}\n255\n", width, height).unwrap();
+    let size = header.len() + 3 * width as usize * height as usize;
+
+    write!(port, "-----BEGIN RIKAN SCREENSHOT ppm {}x{} {}-----\n", width, height, size).unwrap();
+    let mut encoder = Base64Writer::new(port);
+    encoder.write(header.as_bytes());
+    for y in 0..height {
+        for x in 0..width {
+            let color = screen.read_pixel(x, y);
+            encoder.write(&[color.red, color.green, color.blue]);
+        }
+    }
+    let crc = encoder.finish();
+    write!(port, "-----END RIKAN SCREENSHOT crc32={:08x}-----\n", crc).unwrap();
+}
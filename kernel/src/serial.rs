@@ -0,0 +1,68 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Polling driver for a 16550 compatible UART
+
+use core::fmt;
+
+use crate::io_port::{in8, out8};
+
+pub const COM1: u16 = 0x3f8;
+
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    /// 115200 baud, 8 data bits, no parity, one stop bit, interrupts off
+    pub fn init(&self) {
+        unsafe {
+            out8(self.base + INTERRUPT_ENABLE, 0x00);
+            // Set DLAB to program the divisor
+            out8(self.base + LINE_CONTROL, 0x80);
+            out8(self.base + DATA, 0x01);
+            out8(self.base + INTERRUPT_ENABLE, 0x00);
+            out8(self.base + LINE_CONTROL, 0x03);
+            // Enable and clear FIFOs, 14 byte threshold
+            out8(self.base + FIFO_CONTROL, 0xc7);
+            // DTR, RTS and OUT2
+            out8(self.base + MODEM_CONTROL, 0x0b);
+        }
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        unsafe {
+            while in8(self.base + LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            out8(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Application processor startup and per-CPU data.
+//!
+//! The bootstrap processor (BSP) runs `kernel_main`. Every other usable CPU
+//! in the MADT is started with the INIT-SIPI-SIPI sequence and enters Rust at
+//! `ap_main` through the real mode `trampoline`. Each CPU gets its own GDT,
+//! TSS, kernel stack and `PerCpu` area, whose address is kept in the GS base
+//! so `current_index()` needs neither a lock nor the APIC.
+//!
+//! CPUs are numbered in the order they were started, the BSP being 0.
+
+use alloc::vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::acpi;
+use crate::apic;
+use crate::fpu;
+use crate::gdt::Gdt;
+use crate::interrupt;
+use crate::memory_map::{MemoryMap, MemoryType, UEFI_PAGE_SIZE};
+use crate::msr;
+use crate::task;
+use crate::timer;
+
+mod trampoline;
+
+use trampoline::{BootParameters, PARAMETERS_OFFSET};
+
+pub const MAX_CPUS: usize = 16;
+const STACK_SIZE: usize = 64 * 1024;
+/// Wait between INIT and the first STARTUP IPI, from the MultiProcessor Specification
+const INIT_DELAY_MS: u64 = 10;
+/// Time a CPU gets to come online after each STARTUP IPI
+const STARTUP_TIMEOUT_MS: u64 = 100;
+/// The trampoline must start in real mode addressable memory
+const LOW_MEMORY_END: usize = 0x10_0000;
+
+const CR4_PAE: u64 = 1 << 5;
+const CR4_PGE: u64 = 1 << 7;
+const CR4_LA57: u64 = 1 << 12;
+const EFER_SCE: u64 = 1 << 0;
+const EFER_LME: u64 = 1 << 8;
+const EFER_NXE: u64 = 1 << 11;
+
+#[derive(Debug)]
+pub enum SmpError {
+    /// Without a MADT the other CPUs cannot be found
+    NoMadt,
+    /// No free page below 1 MiB for the trampoline
+    NoLowMemory,
+    /// CR3 does not fit the 32 bit register loaded in protected mode
+    PageTablesAbove4GiB,
+}
+
+#[repr(C)]
+struct PerCpu {
+    /// Read through GS by `current_index()`; must stay the first field
+    index: usize,
+    apic_id: u32,
+    /// Set by the CPU itself once it is ready to run tasks
+    online: AtomicBool,
+    stack_top: u64,
+    gdt: Gdt,
+}
+
+impl PerCpu {
+    // Only used as the repeat operand that initializes `CPUS`, so every entry
+    // gets its own `online` flag
+    #[allow(clippy::declare_interior_mutable_const)]
+    const OFFLINE: PerCpu = PerCpu {
+        index: 0,
+        apic_id: 0,
+        online: AtomicBool::new(false),
+        stack_top: 0,
+        gdt: Gdt::new(),
+    };
+}
+
+/// Each CPU only touches its own entry once it has been started
+static mut CPUS: [PerCpu; MAX_CPUS] = [PerCpu::OFFLINE; MAX_CPUS];
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+fn cpu(index: usize) -> &'static mut PerCpu {
+    unsafe { &mut (*core::ptr::addr_of_mut!(CPUS))[index] }
+}
+
+/// Load the per-CPU GDT and point GS at the `PerCpu` area of `index`
+fn set_up_cpu(index: usize) {
+    let cpu = cpu(index);
+    cpu.index = index;
+    let stack_top = cpu.stack_top;
+    let base = cpu as *mut PerCpu as u64;
+    unsafe {
+        cpu.gdt.load(stack_top);
+        // After the GDT, which reloads GS
+        msr::write(msr::IA32_GS_BASE, base);
+    }
+}
+
+/// Set up the per-CPU data of the bootstrap processor. Must run before
+/// anything else in `kernel_main`, since locks ask for the CPU index.
+pub fn init() {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    // The bootloader's stack has no known top; the current position is good enough for RSP0
+    cpu(0).stack_top = rsp & !0xf;
+    set_up_cpu(0);
+    cpu(0).online.store(true, Ordering::Release);
+}
+
+/// Index of the running CPU, 0 for the bootstrap processor
+pub fn current_index() -> usize {
+    let index: usize;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) index, options(readonly, nostack, preserves_flags)) };
+    index
+}
+
+pub fn is_bootstrap_processor() -> bool {
+    current_index() == 0
+}
+
+/// Number of CPUs running tasks
+pub fn online_count() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// First free page below 1 MiB, skipping page 0 which holds the real mode IVT
+fn find_trampoline_page(memory_map: &MemoryMap) -> Option<usize> {
+    memory_map
+        .iter()
+        .filter(|desc| desc.is_type(MemoryType::EfiConventionalMemory))
+        .find_map(|desc| {
+            let start = (desc.physical_start as usize).max(UEFI_PAGE_SIZE);
+            let end = (desc.physical_start as usize + desc.number_of_pages as usize * UEFI_PAGE_SIZE).min(LOW_MEMORY_END);
+            if start + UEFI_PAGE_SIZE <= end { Some(start) } else { None }
+        })
+}
+
+fn boot_parameters(page: usize) -> *mut BootParameters {
+    (page + PARAMETERS_OFFSET) as *mut BootParameters
+}
+
+/// Copy the trampoline to `page` and fill in the parameters shared by all CPUs
+fn install_trampoline(page: usize, cr3: u64) {
+    unsafe {
+        let start = &trampoline::ap_trampoline_start as *const u8;
+        let size = &trampoline::ap_trampoline_end as *const u8 as usize - start as usize;
+        assert!(size <= PARAMETERS_OFFSET, "AP trampoline overlaps its parameters");
+        core::ptr::copy_nonoverlapping(start, page as *mut u8, size);
+
+        let gdt = &trampoline::ap_trampoline_gdt as *const u8 as usize;
+        let gdt_end = &trampoline::ap_trampoline_gdt_end as *const u8 as usize;
+        let cr4: u64;
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        let efer = msr::read(msr::IA32_EFER);
+        boot_parameters(page).write_volatile(BootParameters {
+            gdt_limit: (gdt_end - gdt - 1) as u16,
+            gdt_base: (page + (gdt - start as usize)) as u32,
+            reserved0: 0,
+            cr3: cr3 as u32,
+            // Match the paging mode of the bootstrap processor
+            cr4: (cr4 & (CR4_PAE | CR4_PGE | CR4_LA57) | CR4_PAE) as u32,
+            efer: (efer & (EFER_SCE | EFER_LME | EFER_NXE) | EFER_LME) as u32,
+            reserved1: 0,
+            stack_top: 0,
+            entry: ap_main as usize as u64,
+            argument: 0,
+        });
+    }
+}
+
+fn wait_online(index: usize, milliseconds: u64) -> bool {
+    let deadline = timer::uptime() + milliseconds * 1_000_000;
+    while timer::uptime() < deadline {
+        if cpu(index).online.load(Ordering::Acquire) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    cpu(index).online.load(Ordering::Acquire)
+}
+
+/// Start the CPU with `apic_id` as CPU `index` and wait until it runs tasks
+fn start_cpu(page: usize, index: usize, apic_id: u32) -> bool {
+    // Never freed: CPUs do not go offline
+    let stack = vec![0u64; STACK_SIZE / 8].leak();
+    let stack_top = stack.as_mut_ptr_range().end as u64 & !0xf;
+    let cpu = cpu(index);
+    cpu.apic_id = apic_id;
+    cpu.stack_top = stack_top;
+    unsafe {
+        let parameters = boot_parameters(page);
+        core::ptr::addr_of_mut!((*parameters).stack_top).write_unaligned(stack_top);
+        core::ptr::addr_of_mut!((*parameters).argument).write_unaligned(index as u64);
+    }
+
+    apic::send_init(apic_id);
+    timer::sleep_ms(INIT_DELAY_MS);
+    // A second STARTUP IPI is only needed if the first one was lost
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (page / UEFI_PAGE_SIZE) as u8);
+        if wait_online(index, STARTUP_TIMEOUT_MS) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Start every other usable CPU in the MADT, one at a time. Needs the
+/// timer and the scheduler, and interrupts enabled for the waits. Returns
+/// the number of online CPUs.
+pub fn start_application_processors(memory_map: &MemoryMap) -> Result<usize, SmpError> {
+    let madt = acpi::tables().madt.as_ref().ok_or(SmpError::NoMadt)?;
+    let cr3: u64;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+    if cr3 >> 32 != 0 {
+        return Err(SmpError::PageTablesAbove4GiB);
+    }
+    let page = find_trampoline_page(memory_map).ok_or(SmpError::NoLowMemory)?;
+    install_trampoline(page, cr3);
+
+    let bootstrap_apic_id = apic::id();
+    cpu(0).apic_id = bootstrap_apic_id;
+    // Indexes are not reused: a CPU that missed its deadline may still come up later
+    let mut entries = madt.local_apics.iter().filter(|entry| entry.usable && entry.apic_id != bootstrap_apic_id);
+    for (index, entry) in (1..MAX_CPUS).zip(entries.by_ref()) {
+        if !start_cpu(page, index, entry.apic_id) {
+            println!("[ERROR] SMP: CPU with APIC ID {} did not start", entry.apic_id);
+        }
+    }
+    if entries.next().is_some() {
+        println!("SMP: only the first {} CPUs are used", MAX_CPUS);
+    }
+    Ok(online_count())
+}
+
+/// Called by the trampoline on the CPU's own stack, with interrupts disabled
+extern "C" fn ap_main(index: u64) -> ! {
+    let index = index as usize;
+    set_up_cpu(index);
+    interrupt::init();
+    apic::init_application_processor();
+    timer::init_application_processor();
+    fpu::init_application_processor();
+    // The code running here becomes the idle task of this CPU
+    task::init_application_processor();
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+    cpu(index).online.store(true, Ordering::Release);
+    interrupt::enable();
+    task::idle_loop();
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Real mode entry point of the application processors.
+//!
+//! A STARTUP IPI starts a CPU in real mode at a page below 1 MiB, so the code
+//! between `ap_trampoline_start` and `ap_trampoline_end` is copied to such a
+//! page. It only uses addresses relative to where it runs and takes
+//! everything else from `BootParameters`, which the bootstrap processor
+//! writes at `PARAMETERS_OFFSET` in the same page before each start.
+//!
+//! The CPU goes through protected mode with a temporary GDT, enables PAE,
+//! loads the bootstrap processor's page tables and enters long mode, then
+//! calls `entry(argument)` on the given stack.
+
+use core::arch::global_asm;
+
+/// Offset of `BootParameters` in the trampoline page; the `.set` lines below must match
+pub const PARAMETERS_OFFSET: usize = 0xf00;
+
+#[repr(C, packed)]
+pub struct BootParameters {
+    /// Operand of `lgdt` for the temporary GDT
+    pub gdt_limit: u16,
+    pub gdt_base: u32,
+    pub reserved0: u16,
+    /// The page tables must be below 4 GiB to be loaded from protected mode
+    pub cr3: u32,
+    pub cr4: u32,
+    /// Bits ORed into IA32_EFER
+    pub efer: u32,
+    pub reserved1: u32,
+    pub stack_top: u64,
+    pub entry: u64,
+    pub argument: u64,
+}
+
+extern "C" {
+    pub static ap_trampoline_start: u8;
+    pub static ap_trampoline_gdt: u8;
+    pub static ap_trampoline_gdt_end: u8;
+    pub static ap_trampoline_end: u8;
+}
+
+global_asm!(
+    ".set PARAM_GDT_POINTER, 0xf00",
+    ".set PARAM_CR3, 0xf08",
+    ".set PARAM_CR4, 0xf0c",
+    ".set PARAM_EFER, 0xf10",
+    ".set PARAM_STACK_TOP, 0xf18",
+    ".set PARAM_ENTRY, 0xf20",
+    ".set PARAM_ARGUMENT, 0xf28",
+    "",
+    ".code16",
+    ".global ap_trampoline_start",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    movw %cs, %ax",
+    "    movw %ax, %ds",
+    "    movw %ax, %ss",
+    // The stack is only needed for the far returns below
+    "    movw $PARAM_GDT_POINTER, %sp",
+    // Linear address of the page, kept in EBX until long mode
+    "    xorl %ebx, %ebx",
+    "    movw %ax, %bx",
+    "    shll $4, %ebx",
+    "    lgdtl PARAM_GDT_POINTER",
+    "    movl %cr0, %eax",
+    "    orl $1, %eax",
+    "    movl %eax, %cr0",
+    "    leal (ap_trampoline_32 - ap_trampoline_start)(%ebx), %eax",
+    "    pushl $0x08",
+    "    pushl %eax",
+    "    lretl",
+    "",
+    ".code32",
+    "ap_trampoline_32:",
+    "    movw $0x10, %ax",
+    "    movw %ax, %ds",
+    "    movw %ax, %es",
+    "    movw %ax, %ss",
+    "    leal PARAM_GDT_POINTER(%ebx), %esp",
+    "    movl PARAM_CR4(%ebx), %eax",
+    "    movl %eax, %cr4",
+    "    movl PARAM_CR3(%ebx), %eax",
+    "    movl %eax, %cr3",
+    "    movl $0xc0000080, %ecx",
+    "    rdmsr",
+    "    orl PARAM_EFER(%ebx), %eax",
+    "    wrmsr",
+    "    movl %cr0, %eax",
+    "    orl $0x80000000, %eax",
+    "    movl %eax, %cr0",
+    "    leal (ap_trampoline_64 - ap_trampoline_start)(%ebx), %eax",
+    "    pushl $0x18",
+    "    pushl %eax",
+    "    lretl",
+    "",
+    ".code64",
+    "ap_trampoline_64:",
+    // The upper halves of registers are undefined after the mode switch
+    "    movl %ebx, %ebx",
+    "    movq PARAM_STACK_TOP(%rbx), %rsp",
+    "    movq PARAM_ARGUMENT(%rbx), %rdi",
+    "    movq PARAM_ENTRY(%rbx), %rax",
+    "    callq *%rax",
+    "    ud2",
+    "",
+    // Null, 32 bit code, data, 64 bit code
+    ".balign 8",
+    ".global ap_trampoline_gdt",
+    "ap_trampoline_gdt:",
+    "    .quad 0",
+    "    .quad 0x00cf9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "    .quad 0x00af9a000000ffff",
+    ".global ap_trampoline_gdt_end",
+    "ap_trampoline_gdt_end:",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    options(att_syntax),
+);
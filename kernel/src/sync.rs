@@ -0,0 +1,23 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Locks and one-time initialization for data shared between tasks and
+//! interrupt handlers.
+//!
+//! `SpinLock` disables interrupts while held, so it may also be taken by
+//! interrupt handlers. `Mutex` puts the waiting task to sleep instead and is
+//! meant for longer critical sections in task context. With the `lock-debug`
+//! feature, every acquisition is checked for recursion and for inconsistent
+//! lock order, and problems are reported on the console.
+
+#[cfg(feature = "lock-debug")]
+mod debug;
+mod mutex;
+mod once;
+mod spin;
+
+pub use mutex::Mutex;
+pub use once::OnceCell;
+pub use spin::{SpinLock, SpinLockGuard};
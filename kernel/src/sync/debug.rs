@@ -0,0 +1,170 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Lock checker built with the `lock-debug` feature.
+//!
+//! Spin locks held by each CPU are tracked on a small stack. Taking a lock
+//! that is already on the stack is reported and stops the kernel, since it
+//! would spin forever. Every time a lock is taken while another is held the
+//! pair is remembered; seeing the same two locks taken in the opposite order
+//! later, on any CPU, is reported once as a potential deadlock.
+//!
+//! Nothing here allocates or uses `SpinLock`, because the allocator itself is
+//! behind one.
+
+use core::fmt::{self, Write};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::serial::{SerialPort, COM1};
+use crate::smp::{self, MAX_CPUS};
+
+const MAX_HELD: usize = 16;
+const MAX_ORDERS: usize = 128;
+
+#[derive(Clone, Copy)]
+struct Held {
+    address: usize,
+    name: &'static str,
+}
+
+/// `first` was held while `second` was taken
+struct Order {
+    first: Held,
+    second: Held,
+    reported: bool,
+}
+
+struct CpuState {
+    held: heapless::Vec<Held, MAX_HELD>,
+    /// Set while a report is written, which takes locks itself
+    reporting: bool,
+}
+
+impl CpuState {
+    const EMPTY: CpuState = CpuState { held: heapless::Vec::new(), reporting: false };
+}
+
+/// Plain test-and-set lock for the state shared between CPUs
+struct RawLock(AtomicBool);
+
+impl RawLock {
+    fn lock(&self) {
+        while self.0.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Only touched by the CPU it belongs to, with interrupts disabled
+static mut CPUS: [CpuState; MAX_CPUS] = [CpuState::EMPTY; MAX_CPUS];
+/// Guarded by `ORDERS_LOCK`
+static mut ORDERS: heapless::Vec<Order, MAX_ORDERS> = heapless::Vec::new();
+static ORDERS_LOCK: RawLock = RawLock(AtomicBool::new(false));
+/// Keeps reports from different CPUs apart; guards `SERIAL_READY`
+static REPORT_LOCK: RawLock = RawLock(AtomicBool::new(false));
+static mut SERIAL_READY: bool = false;
+
+fn cpu_state() -> &'static mut CpuState {
+    unsafe { &mut (*core::ptr::addr_of_mut!(CPUS))[smp::current_index()] }
+}
+
+fn orders() -> &'static mut heapless::Vec<Order, MAX_ORDERS> {
+    unsafe { &mut *core::ptr::addr_of_mut!(ORDERS) }
+}
+
+/// Write to the console, unless its lock is the one in trouble, and to COM1
+fn report(args: fmt::Arguments) {
+    let state = cpu_state();
+    state.reporting = true;
+    REPORT_LOCK.lock();
+    let mut port = SerialPort::new(COM1);
+    unsafe {
+        if !SERIAL_READY {
+            port.init();
+            SERIAL_READY = true;
+        }
+    }
+    let _ = port.write_fmt(args);
+    crate::console::try_print(args);
+    REPORT_LOCK.unlock();
+    state.reporting = false;
+}
+
+fn print_held(state: &CpuState) {
+    for held in state.held.iter().rev() {
+        report(format_args!("  holding {} at {:#x}\n", held.name, held.address));
+    }
+}
+
+pub fn before_acquire(address: usize, name: &'static str) {
+    let state = cpu_state();
+    if state.reporting {
+        return;
+    }
+    if state.held.iter().any(|held| held.address == address) {
+        recursive_acquisition(address, name);
+    }
+
+    let new = Held { address, name };
+    ORDERS_LOCK.lock();
+    let orders = orders();
+    for index in 0..state.held.len() {
+        let held = state.held[index];
+        let inverted = orders
+            .iter_mut()
+            .find(|order| order.first.address == address && order.second.address == held.address);
+        match inverted {
+            Some(order) if !order.reported => {
+                order.reported = true;
+                report(format_args!(
+                    "[LOCK] order inversion: {} ({:#x}) taken while holding {} ({:#x}), the opposite order was seen before\n",
+                    name, address, held.name, held.address
+                ));
+                print_held(state);
+            }
+            Some(_) => {}
+            None => {
+                let known = orders
+                    .iter()
+                    .any(|order| order.first.address == held.address && order.second.address == address);
+                if !known {
+                    // Pairs beyond the table size go unchecked
+                    let _ = orders.push(Order { first: held, second: new, reported: false });
+                }
+            }
+        }
+    }
+    ORDERS_LOCK.unlock();
+}
+
+pub fn acquired(address: usize, name: &'static str) {
+    let state = cpu_state();
+    if state.reporting {
+        return;
+    }
+    // Locks nested deeper than the stack are not tracked
+    let _ = state.held.push(Held { address, name });
+}
+
+/// Called on the CPU that releases the lock. A lock held across a task
+/// switch is released by the task switched to, on the same CPU that took it.
+pub fn released(address: usize) {
+    let state = cpu_state();
+    if let Some(index) = state.held.iter().rposition(|held| held.address == address) {
+        state.held.remove(index);
+    }
+}
+
+/// Report a lock taken again by its holder and stop
+pub fn recursive_acquisition(address: usize, name: &'static str) -> ! {
+    report(format_args!("[LOCK] recursive acquisition of {} at {:#x}\n", name, address));
+    print_held(cpu_state());
+    panic!("recursive acquisition of {}", name);
+}
@@ -0,0 +1,100 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+#[cfg(feature = "lock-debug")]
+use super::debug;
+use super::SpinLock;
+use crate::task::{self, TaskId};
+
+struct MutexState {
+    owner: Option<TaskId>,
+    /// Tasks blocked in `lock()`, first come first served
+    waiters: Vec<TaskId>,
+}
+
+/// Lock that blocks the calling task while another task holds it.
+///
+/// Interrupts stay enabled and other tasks keep running while it is held, so
+/// it suits long critical sections. Never take it in an interrupt handler.
+pub struct Mutex<T> {
+    state: SpinLock<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: SpinLock::new(MutexState { owner: None, waiters: Vec::new() }),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let current = task::current_id();
+        loop {
+            let mut state = self.state.lock();
+            match state.owner {
+                None => {
+                    state.owner = Some(current);
+                    return MutexGuard { mutex: self };
+                }
+                #[cfg(feature = "lock-debug")]
+                Some(owner) if owner == current => {
+                    drop(state);
+                    debug::recursive_acquisition(self as *const Self as usize, core::any::type_name::<T>());
+                }
+                Some(_) => {
+                    if !state.waiters.contains(&current) {
+                        state.waiters.push(current);
+                    }
+                }
+            }
+            drop(state);
+            // A wake from `unlock()` arriving right here is remembered by the scheduler
+            task::block();
+        }
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        state.owner = None;
+        if !state.waiters.is_empty() {
+            // The woken task competes for the lock again when it runs
+            let waiter = state.waiters.remove(0);
+            task::wake(waiter);
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const EMPTY: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// Value written once and read-only afterwards, for kernel singletons that
+/// are set up at boot.
+///
+/// Readers never take a lock. A reader racing with the initialization spins
+/// until the value is ready.
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+unsafe impl<T: Send> Send for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == READY {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Store `value` unless the cell was already initialized, in which case it is given back
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+        match value {
+            Some(value) => Err(value),
+            None => Ok(()),
+        }
+    }
+
+    /// The stored value, running `f` to create it if this is the first call
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        loop {
+            match self.state.compare_exchange(EMPTY, INITIALIZING, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => {
+                    unsafe { (*self.value.get()).write(f()) };
+                    self.state.store(READY, Ordering::Release);
+                }
+                Err(READY) => {}
+                Err(_) => {
+                    spin_loop();
+                    continue;
+                }
+            }
+            return self.get().unwrap();
+        }
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { core::ptr::drop_in_place(self.value.get_mut().as_mut_ptr()) };
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "lock-debug")]
+use super::debug;
+use crate::interrupt;
+
+/// Busy-waiting lock that keeps interrupts disabled while it is held.
+///
+/// Disabling interrupts means an interrupt handler can never spin on a lock
+/// held by the code it interrupted, and the scheduler never switches away
+/// from a task holding one. Guards should be dropped in reverse order of
+/// acquisition so that interrupts come back on only after the last one.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    /// Interrupt flag to restore on drop
+    interrupts_were_enabled: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    #[cfg(feature = "lock-debug")]
+    fn address(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts_were_enabled = interrupt::are_enabled();
+        interrupt::disable();
+        #[cfg(feature = "lock-debug")]
+        debug::before_acquire(self.address(), core::any::type_name::<T>());
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        #[cfg(feature = "lock-debug")]
+        debug::acquired(self.address(), core::any::type_name::<T>());
+        SpinLockGuard { lock: self, interrupts_were_enabled }
+    }
+
+    /// Take the lock only if it is free right now
+    #[cfg(feature = "lock-debug")]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupt::are_enabled();
+        interrupt::disable();
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            if interrupts_were_enabled {
+                interrupt::enable();
+            }
+            return None;
+        }
+        debug::acquired(self.address(), core::any::type_name::<T>());
+        Some(SpinLockGuard { lock: self, interrupts_were_enabled })
+    }
+
+    /// Release the lock without a guard, leaving interrupts disabled.
+    ///
+    /// # Safety
+    /// The running CPU must hold the lock through a guard left on the stack
+    /// of another task, as when the scheduler starts a new task. That guard
+    /// is dropped only after the lock has been taken again for its task.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lock-debug")]
+        debug::released(self.address());
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lock-debug")]
+        debug::released(self.lock.address());
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_were_enabled {
+            interrupt::enable();
+        }
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Kernel tasks and a preemptive round-robin scheduler.
+//!
+//! Every task has its own kernel stack. Tasks of the highest level that has a
+//! runnable task take turns, each running for `TIMESLICE_TICKS` timer ticks
+//! unless it yields, sleeps or blocks first; lower levels only run when all
+//! higher ones are waiting.
+//!
+//! The run queues are shared by all CPUs, so a task may continue on another
+//! CPU each time it is switched to. Every CPU has an idle task of its own that
+//! runs when the queues are empty; it is never queued.
+//!
+//! The scheduler lock is held across `switch_context`: the task switched to
+//! releases it, either by dropping the guard it took before it was switched
+//! away, or in `task_main` when it runs for the first time.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+
+use crate::fpu::ExtendedState;
+use crate::interrupt;
+use crate::message::MessageQueue;
+use crate::smp::{self, MAX_CPUS};
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::timer;
+
+mod context;
+
+pub type TaskId = u64;
+
+/// Scheduling levels; a higher level always runs before a lower one
+pub const LEVEL_COUNT: usize = 4;
+pub const DEFAULT_LEVEL: usize = 1;
+/// For tasks that spend most of their time waiting for input, like the main task
+pub const INTERACTIVE_LEVEL: usize = 2;
+
+const MAIN_TASK_ID: TaskId = 0;
+const STACK_SIZE: usize = 64 * 1024;
+/// Timer ticks a task may run before the next task of its level gets the CPU
+const TIMESLICE_TICKS: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    /// Waiting in a run queue
+    Ready,
+    Running,
+    /// Waiting for `wake()` or a sleep deadline
+    Blocked,
+    /// Finished; its stack is freed once another task runs
+    Exited,
+}
+
+struct Task {
+    id: TaskId,
+    name: &'static str,
+    level: usize,
+    state: TaskState,
+    /// Idle task of a CPU: runs only there and only when nothing else is ready
+    idle: bool,
+    /// `wake()` was called while the task was not blocked; the next `block()` returns at once
+    wake_pending: bool,
+    /// Saved stack pointer while not running
+    rsp: u64,
+    /// x87/SSE/AVX registers while not running
+    extended_state: ExtendedState,
+    messages: Arc<MessageQueue>,
+    /// Only owned so it lives as long as the task. `None` for tasks running
+    /// on a stack set up before the scheduler, like the main task.
+    #[allow(dead_code)]
+    stack: Option<Vec<u64>>,
+}
+
+#[derive(Clone, Copy)]
+struct Cpu {
+    /// `None` until the CPU joins the scheduler
+    current: Option<TaskId>,
+    idle: Option<TaskId>,
+    ticks_left: u32,
+}
+
+struct Scheduler {
+    /// Boxed so that the saved `rsp` fields keep their address while the list grows
+    #[allow(clippy::vec_box)]
+    tasks: Vec<Box<Task>>,
+    run_queues: [VecDeque<TaskId>; LEVEL_COUNT],
+    /// Indexed by `smp::current_index()`
+    cpus: [Cpu; MAX_CPUS],
+    next_id: TaskId,
+}
+
+static SCHEDULER: SpinLock<Option<Scheduler>> = SpinLock::new(None);
+
+type SchedulerGuard = SpinLockGuard<'static, Option<Scheduler>>;
+
+fn scheduler(guard: &mut SchedulerGuard) -> &mut Scheduler {
+    guard.as_mut().expect("task::init has not been called")
+}
+
+impl Scheduler {
+    fn task(&mut self, id: TaskId) -> Option<&mut Task> {
+        self.tasks.iter_mut().find(|task| task.id == id).map(|task| &mut **task)
+    }
+
+    fn current(&self) -> Option<TaskId> {
+        self.cpus[smp::current_index()].current
+    }
+
+    fn make_ready(&mut self, id: TaskId) {
+        if let Some(task) = self.task(id) {
+            task.state = TaskState::Ready;
+            if !task.idle {
+                let level = task.level;
+                self.run_queues[level].push_back(id);
+            }
+        }
+    }
+
+    fn add(&mut self, name: &'static str, level: usize, stack: Option<Vec<u64>>, rsp: u64) -> TaskId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.tasks.push(Box::new(Task {
+            id,
+            name,
+            level: level.min(LEVEL_COUNT - 1),
+            state: TaskState::Blocked,
+            idle: false,
+            wake_pending: false,
+            rsp,
+            extended_state: ExtendedState::new(),
+            messages: Arc::new(MessageQueue::new()),
+            stack,
+        }));
+        id
+    }
+
+    /// Make `id` the idle task of the running CPU
+    fn set_idle(&mut self, id: TaskId) {
+        if let Some(task) = self.task(id) {
+            task.idle = true;
+            task.level = 0;
+        }
+        self.cpus[smp::current_index()].idle = Some(id);
+    }
+
+    /// Free the stacks of exited tasks that no CPU is running
+    fn reap(&mut self) {
+        let cpus = self.cpus;
+        self.tasks.retain(|task| {
+            task.state != TaskState::Exited || cpus.iter().any(|cpu| cpu.current == Some(task.id))
+        });
+    }
+
+    /// Level from which a ready task should preempt the running one
+    fn preempting_level(&mut self, id: TaskId) -> usize {
+        match self.task(id) {
+            Some(task) if task.idle => 0,
+            Some(task) => task.level + 1,
+            None => 0,
+        }
+    }
+}
+
+/// Give the running CPU to the first task of the highest non-empty level, or
+/// to its idle task. A running task goes to the back of its queue first, so
+/// it keeps the CPU only if no other task of the same or a higher level is
+/// ready. Returns when the current task is resumed, possibly on another CPU.
+fn switch_to_next(mut guard: SchedulerGuard) {
+    let cpu = smp::current_index();
+    let scheduler = scheduler(&mut guard);
+    scheduler.reap();
+    let current = match scheduler.cpus[cpu].current {
+        Some(current) => current,
+        None => return,
+    };
+    if scheduler.task(current).map_or(false, |task| task.state == TaskState::Running) {
+        scheduler.make_ready(current);
+    }
+    let next = scheduler
+        .run_queues
+        .iter_mut()
+        .rev()
+        .find_map(|queue| queue.pop_front())
+        .or(scheduler.cpus[cpu].idle)
+        .unwrap_or(current);
+    scheduler.cpus[cpu].ticks_left = TIMESLICE_TICKS;
+    let next_task = scheduler.task(next).map(|task| task as *mut Task);
+    if let Some(task) = next_task {
+        unsafe { (*task).state = TaskState::Running };
+    }
+    if next == current {
+        return;
+    }
+    let current_task = scheduler.task(current).map(|task| task as *mut Task);
+    scheduler.cpus[cpu].current = Some(next);
+    if let (Some(current_task), Some(next_task)) = (current_task, next_task) {
+        // The boxes outlive the switch: a task is never reaped while a CPU
+        // runs it, and the next one only exits after it has been switched to.
+        // Nothing from here to the switch uses the extended registers.
+        unsafe {
+            (*current_task).extended_state.save();
+            (*next_task).extended_state.restore();
+            context::switch_context(&mut (*current_task).rsp, (*next_task).rsp);
+        }
+    }
+    // Resumed: the guard now releases the lock taken by whoever switched back here
+    drop(guard);
+}
+
+/// Called by `task_entry_trampoline` on the new task's stack
+#[no_mangle]
+extern "C" fn task_main(entry: u64, argument: u64) -> ! {
+    // The guard of the switch that started this task is on another stack
+    unsafe { SCHEDULER.force_unlock() };
+    let entry: fn(u64) = unsafe { core::mem::transmute(entry as usize) };
+    interrupt::enable();
+    entry(argument);
+    exit();
+}
+
+/// Halt until there is something to do, forever
+pub fn idle_loop() -> ! {
+    loop {
+        unsafe { asm!("hlt", options(nomem, nostack)) }
+    }
+}
+
+fn idle(_: u64) {
+    idle_loop();
+}
+
+/// Turn the running code into the main task and start the idle task of the
+/// bootstrap processor. The kernel heap must be ready; switching starts with
+/// the next timer tick.
+pub fn init() {
+    let mut scheduler = Scheduler {
+        tasks: Vec::new(),
+        run_queues: [VecDeque::new(), VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        cpus: [Cpu { current: None, idle: None, ticks_left: TIMESLICE_TICKS }; MAX_CPUS],
+        next_id: 0,
+    };
+    let main = scheduler.add("main", INTERACTIVE_LEVEL, None, 0);
+    debug_assert_eq!(main, MAIN_TASK_ID);
+    scheduler.task(main).unwrap().state = TaskState::Running;
+    scheduler.cpus[smp::current_index()].current = Some(main);
+
+    let mut stack = vec![0u64; STACK_SIZE / 8];
+    let rsp = context::initial_stack(&mut stack, idle as usize as u64, 0);
+    let idle = scheduler.add("idle", 0, Some(stack), rsp);
+    scheduler.set_idle(idle);
+    *SCHEDULER.lock() = Some(scheduler);
+}
+
+/// Join the scheduler on an application processor: the running code becomes
+/// the CPU's idle task and must continue with `idle_loop()`.
+pub fn init_application_processor() {
+    let mut guard = SCHEDULER.lock();
+    let scheduler = scheduler(&mut guard);
+    let id = scheduler.add("idle", 0, None, 0);
+    scheduler.set_idle(id);
+    scheduler.task(id).unwrap().state = TaskState::Running;
+    scheduler.cpus[smp::current_index()].current = Some(id);
+}
+
+/// Start `entry(argument)` as a new task at `DEFAULT_LEVEL`. Returning from
+/// `entry` ends the task.
+pub fn spawn(name: &'static str, entry: fn(u64), argument: u64) -> TaskId {
+    spawn_with_level(name, DEFAULT_LEVEL, entry, argument)
+}
+
+pub fn spawn_with_level(name: &'static str, level: usize, entry: fn(u64), argument: u64) -> TaskId {
+    let mut stack = vec![0u64; STACK_SIZE / 8];
+    let rsp = context::initial_stack(&mut stack, entry as usize as u64, argument);
+    let mut guard = SCHEDULER.lock();
+    let scheduler = scheduler(&mut guard);
+    let id = scheduler.add(name, level, Some(stack), rsp);
+    scheduler.make_ready(id);
+    id
+}
+
+/// Id of the running task; the code before `init()` counts as the main task
+pub fn current_id() -> TaskId {
+    SCHEDULER.lock().as_ref().and_then(|scheduler| scheduler.current()).unwrap_or(MAIN_TASK_ID)
+}
+
+/// Queue for messages to `id`, see `message::send()`
+pub fn message_queue(id: TaskId) -> Option<Arc<MessageQueue>> {
+    SCHEDULER.lock().as_mut()?.task(id).map(|task| task.messages.clone())
+}
+
+/// Let other ready tasks of the same or a higher level run first
+pub fn yield_now() {
+    switch_to_next(SCHEDULER.lock());
+}
+
+/// Stop running the current task until `wake()` is called for it.
+///
+/// A wake that arrives after deciding to block but before calling this is
+/// remembered, so this returns immediately in that case. Wakeups can also be
+/// spurious, e.g. from the timer of an earlier `sleep()`, so check the
+/// condition again after this returns.
+pub fn block() {
+    let mut guard = SCHEDULER.lock();
+    let scheduler = scheduler(&mut guard);
+    if let Some(task) = scheduler.current().and_then(|current| scheduler.task(current)) {
+        if core::mem::replace(&mut task.wake_pending, false) {
+            return;
+        }
+        task.state = TaskState::Blocked;
+    }
+    switch_to_next(guard);
+}
+
+/// Make a blocked task ready again. Safe to call from interrupt handlers and
+/// for tasks running on other CPUs.
+pub fn wake(id: TaskId) {
+    let mut guard = SCHEDULER.lock();
+    let scheduler = scheduler(&mut guard);
+    match scheduler.task(id).map(|task| task.state) {
+        Some(TaskState::Blocked) => scheduler.make_ready(id),
+        Some(TaskState::Ready) | Some(TaskState::Running) => scheduler.task(id).unwrap().wake_pending = true,
+        _ => {}
+    }
+}
+
+/// Block the current task for at least `milliseconds`
+pub fn sleep(milliseconds: u64) {
+    timer::add_timer_after_ms(milliseconds, wake, current_id());
+    block();
+}
+
+/// End the current task
+pub fn exit() -> ! {
+    let mut guard = SCHEDULER.lock();
+    let scheduler = scheduler(&mut guard);
+    if let Some(task) = scheduler.current().and_then(|current| scheduler.task(current)) {
+        task.state = TaskState::Exited;
+    }
+    switch_to_next(guard);
+    unreachable!("exited task was resumed");
+}
+
+/// Move a task to another level; takes effect the next time it is queued
+pub fn set_level(id: TaskId, level: usize) {
+    let mut guard = SCHEDULER.lock();
+    let scheduler = scheduler(&mut guard);
+    let level = level.min(LEVEL_COUNT - 1);
+    let old_level = match scheduler.task(id) {
+        Some(task) if !task.idle => core::mem::replace(&mut task.level, level),
+        _ => return,
+    };
+    let queue = &mut scheduler.run_queues[old_level];
+    if let Some(index) = queue.iter().position(|&queued| queued == id) {
+        queue.remove(index);
+        scheduler.run_queues[level].push_back(id);
+    }
+}
+
+/// Called from the timer interrupt of every CPU after the end of interrupt
+/// has been signaled
+pub fn on_timer_tick() {
+    let mut guard = SCHEDULER.lock();
+    let scheduler = match guard.as_mut() {
+        Some(scheduler) => scheduler,
+        None => return,
+    };
+    let cpu = &mut scheduler.cpus[smp::current_index()];
+    let current = match cpu.current {
+        Some(current) => current,
+        None => return,
+    };
+    cpu.ticks_left = cpu.ticks_left.saturating_sub(1);
+    let expired = cpu.ticks_left == 0;
+    // A task woken at a higher level preempts the current one right away
+    let level = scheduler.preempting_level(current);
+    let higher_ready = scheduler.run_queues[level..].iter().any(|queue| !queue.is_empty());
+    if expired || higher_ready {
+        switch_to_next(guard);
+    }
+}
+
+/// Print every task with its level and state, and the CPU running it
+pub fn dump() {
+    // Copied out first: printing takes the GUI lock, which is held while
+    // sending window events and so must not be taken under the scheduler lock
+    let tasks: Vec<_> = {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = scheduler(&mut guard);
+        let cpus = scheduler.cpus;
+        scheduler
+            .tasks
+            .iter()
+            .map(|task| {
+                let cpu = cpus.iter().position(|cpu| cpu.current == Some(task.id));
+                (task.id, task.name, task.level, task.state, cpu)
+            })
+            .collect()
+    };
+    for (id, name, level, state, cpu) in tasks {
+        match cpu {
+            Some(cpu) => println!("  task {} {:<8} level {} {:?} on CPU {}", id, name, level, state, cpu),
+            None => println!("  task {} {:<8} level {} {:?}", id, name, level, state),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Stack switching between tasks.
+//!
+//! A suspended task's registers live on its own stack: `switch_context` pushes
+//! RFLAGS and the callee-saved registers, stores RSP, then loads the other
+//! task's RSP and pops the same frame. Caller-saved registers are already
+//! saved by whoever called it, including the compiler generated prologue of
+//! an `extern "x86-interrupt"` handler when a task is preempted.
+
+use core::arch::global_asm;
+
+extern "C" {
+    /// Save the current task's RSP to `*save_rsp` and resume the task whose
+    /// stack pointer is `load_rsp`. Returns when the current task is resumed.
+    pub fn switch_context(save_rsp: *mut u64, load_rsp: u64);
+    fn task_entry_trampoline();
+}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "    pushfq",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [rdi], rsp",
+    "    mov rsp, rsi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    popfq",
+    "    ret",
+    "",
+    // First code of a new task: `switch_context` returns here with the entry
+    // point in r12 and its argument in r13, see `initial_stack`
+    ".global task_entry_trampoline",
+    "task_entry_trampoline:",
+    "    mov rdi, r12",
+    "    mov rsi, r13",
+    "    call task_main",
+    "    ud2",
+);
+
+/// Registers popped by `switch_context`, lowest address first
+#[repr(C)]
+struct SwitchFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    rflags: u64,
+    return_address: u64,
+}
+
+/// RFLAGS of a new task: interrupts stay disabled until `task_main` enables them
+const INITIAL_RFLAGS: u64 = 0x2;
+
+/// Lay out a frame on `stack` that makes `switch_context` start `task_main(entry, argument)`.
+/// Returns the stack pointer to resume.
+pub fn initial_stack(stack: &mut [u64], entry: u64, argument: u64) -> u64 {
+    // The return slot sits in the topmost 16 byte aligned word so that RSP is
+    // aligned again when the trampoline calls into Rust
+    let top = (stack.as_mut_ptr_range().end as u64) & !0xf;
+    let frame = (top - core::mem::size_of::<SwitchFrame>() as u64) as *mut SwitchFrame;
+    unsafe {
+        frame.write(SwitchFrame {
+            r15: 0,
+            r14: 0,
+            r13: argument,
+            r12: entry,
+            rbx: 0,
+            rbp: 0,
+            rflags: INITIAL_RFLAGS,
+            return_address: task_entry_trampoline as usize as u64,
+        });
+    }
+    frame as u64
+}
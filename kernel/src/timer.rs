@@ -0,0 +1,245 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Local APIC timer driven kernel clock.
+//!
+//! The APIC timer frequency is not architecturally defined, so it is measured
+//! once against a clock with a known rate: the ACPI PM timer, the HPET, or the
+//! legacy PIT when ACPI describes neither (see `acpi::reference_clock()`).
+
+use alloc::collections::BinaryHeap;
+use core::arch::asm;
+use core::cmp::{Ordering, Reverse};
+use core::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+use crate::apic::{self, register, LVT_MASKED};
+use crate::interrupt::{self, vector, InterruptFrame};
+use crate::io_port::{in32, in8, out8};
+use crate::smp;
+use crate::sync::SpinLock;
+use crate::task;
+
+/// Periodic tick rate
+pub const TICK_HZ: u64 = 100;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const NANOS_PER_TICK: u64 = NANOS_PER_SECOND / TICK_HZ;
+const CALIBRATION_MICROS: u64 = 10_000;
+
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide the bus clock by 1
+const DIVIDE_BY_1: u32 = 0b1011;
+
+/// Clock with a known frequency used to measure the APIC timer
+#[derive(Clone, Copy, Debug)]
+pub enum ReferenceClock {
+    /// ACPI PM timer at 3.579545 MHz; `extended` means a 32 bit counter instead of 24 bit
+    PmTimer { port: u16, extended: bool },
+    /// HPET register block mapped at `base`
+    Hpet { base: usize },
+    /// 8254 PIT channel 2, present on every PC compatible
+    Pit,
+}
+
+const PM_TIMER_HZ: u64 = 3_579_545;
+const HPET_CAPABILITIES: usize = 0x000;
+const HPET_CONFIGURATION: usize = 0x010;
+const HPET_MAIN_COUNTER: usize = 0x0f0;
+const HPET_ENABLE: u64 = 1;
+const FEMTOS_PER_MICRO: u64 = 1_000_000_000;
+const PIT_HZ: u64 = 1_193_182;
+
+impl ReferenceClock {
+    /// Busy wait for `micros` microseconds
+    fn wait(&self, micros: u64) {
+        match *self {
+            ReferenceClock::PmTimer { port, extended } => {
+                let mask: u32 = if extended { u32::MAX } else { 0x00ff_ffff };
+                let ticks = (PM_TIMER_HZ * micros / 1_000_000) as u32;
+                let start = unsafe { in32(port) } & mask;
+                while (unsafe { in32(port) } & mask).wrapping_sub(start) & mask < ticks {
+                    core::hint::spin_loop();
+                }
+            }
+            ReferenceClock::Hpet { base } => unsafe {
+                let read = |offset: usize| ((base + offset) as *const u64).read_volatile();
+                let configuration = (base + HPET_CONFIGURATION) as *mut u64;
+                configuration.write_volatile(configuration.read_volatile() | HPET_ENABLE);
+                // Counter period in femtoseconds is in the upper half of the capabilities
+                let period = read(HPET_CAPABILITIES) >> 32;
+                let ticks = micros * FEMTOS_PER_MICRO / period;
+                let start = read(HPET_MAIN_COUNTER);
+                while read(HPET_MAIN_COUNTER).wrapping_sub(start) < ticks {
+                    core::hint::spin_loop();
+                }
+            },
+            ReferenceClock::Pit => unsafe {
+                // Channel 2 in mode 0 counts down once; OUT2 (port 0x61 bit 5) goes high at zero.
+                // The count is 16 bits, so long waits are split up.
+                let mut remaining = PIT_HZ * micros / 1_000_000;
+                while remaining > 0 {
+                    let count = remaining.min(0xffff) as u16;
+                    remaining -= count as u64;
+                    let gate = in8(0x61) & !0x03;
+                    out8(0x61, gate);
+                    out8(0x43, 0b1011_0000);
+                    out8(0x42, count as u8);
+                    out8(0x42, (count >> 8) as u8);
+                    out8(0x61, gate | 0x01);
+                    while in8(0x61) & 0x20 == 0 {
+                        core::hint::spin_loop();
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Callback registered with `add_timer`
+struct Timer {
+    deadline: u64,
+    callback: fn(u64),
+    data: u64,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.deadline.cmp(&other.deadline)
+    }
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Largest value returned by `uptime()` so far
+static LAST_UPTIME: AtomicU64 = AtomicU64::new(0);
+static mut APIC_TIMER_HZ: u64 = 0;
+static mut COUNTS_PER_TICK: u32 = 0;
+/// Earliest deadline first
+static TIMERS: SpinLock<Option<BinaryHeap<Reverse<Timer>>>> = SpinLock::new(None);
+
+/// Remove and return the earliest timer if it is due at `now`
+fn pop_expired(now: u64) -> Option<Timer> {
+    let mut timers = TIMERS.lock();
+    let timers = timers.get_or_insert_with(BinaryHeap::new);
+    match timers.peek() {
+        Some(Reverse(timer)) if timer.deadline <= now => timers.pop().map(|Reverse(timer)| timer),
+        _ => None,
+    }
+}
+
+extern "x86-interrupt" fn timer_handler(_frame: InterruptFrame) {
+    // Every CPU ticks for its scheduler, but only the bootstrap processor
+    // advances the clock and runs the callbacks
+    if smp::is_bootstrap_processor() {
+        TICKS.fetch_add(1, AtomicOrdering::Relaxed);
+        let now = uptime();
+        // The lock is released before each callback so that callbacks can add timers
+        while let Some(timer) = pop_expired(now) {
+            (timer.callback)(timer.data);
+        }
+    }
+    // Signal the end of interrupt first: the scheduler may switch to a task
+    // that does not come back here for a while
+    apic::end_of_interrupt();
+    task::on_timer_tick();
+}
+
+/// Measure the APIC timer against `reference` and start the periodic tick
+pub fn init(reference: ReferenceClock) {
+    apic::write_register(register::TIMER_DIVIDE_CONFIG, DIVIDE_BY_1);
+    apic::write_register(register::LVT_TIMER, LVT_MASKED);
+    apic::write_register(register::TIMER_INITIAL_COUNT, u32::MAX);
+    reference.wait(CALIBRATION_MICROS);
+    let elapsed = u32::MAX - apic::read_register(register::TIMER_CURRENT_COUNT);
+    apic::write_register(register::TIMER_INITIAL_COUNT, 0);
+
+    let hz = elapsed as u64 * 1_000_000 / CALIBRATION_MICROS;
+    unsafe {
+        APIC_TIMER_HZ = hz;
+        COUNTS_PER_TICK = (hz / TICK_HZ) as u32;
+    }
+
+    interrupt::set_handler(vector::LOCAL_APIC_TIMER, timer_handler);
+    start_periodic_tick();
+}
+
+/// Start the tick on an application processor, using the rate measured by `init()`
+pub fn init_application_processor() {
+    apic::write_register(register::TIMER_DIVIDE_CONFIG, DIVIDE_BY_1);
+    start_periodic_tick();
+}
+
+fn start_periodic_tick() {
+    apic::write_register(register::LVT_TIMER, LVT_TIMER_PERIODIC | vector::LOCAL_APIC_TIMER as u32);
+    apic::write_register(register::TIMER_INITIAL_COUNT, unsafe { COUNTS_PER_TICK });
+}
+
+/// Measured APIC timer frequency
+pub fn apic_timer_hz() -> u64 {
+    unsafe { APIC_TIMER_HZ }
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(AtomicOrdering::Relaxed)
+}
+
+/// Nanoseconds since `init()`. Never goes backwards.
+///
+/// The time within the current tick comes from the calling CPU's own APIC
+/// timer, which runs at the same rate but not in phase with the bootstrap
+/// processor's, so calls on different CPUs may be off by up to a tick.
+pub fn uptime() -> u64 {
+    let counts_per_tick = unsafe { COUNTS_PER_TICK };
+    if counts_per_tick == 0 {
+        return 0;
+    }
+    // Retry if a tick arrived while reading the count
+    let now = loop {
+        let ticks = ticks();
+        let current = apic::read_register(register::TIMER_CURRENT_COUNT);
+        if ticks == self::ticks() {
+            let elapsed = (counts_per_tick - current.min(counts_per_tick)) as u64;
+            break ticks * NANOS_PER_TICK + elapsed * NANOS_PER_TICK / counts_per_tick as u64;
+        }
+    };
+    // With interrupts disabled the count can wrap before TICKS is incremented
+    let last = LAST_UPTIME.fetch_max(now, AtomicOrdering::Relaxed);
+    now.max(last)
+}
+
+/// Call `callback(data)` from the timer interrupt once `uptime()` reaches `deadline`.
+///
+/// Callbacks run with interrupts disabled and should return quickly.
+pub fn add_timer(deadline: u64, callback: fn(u64), data: u64) {
+    TIMERS.lock().get_or_insert_with(BinaryHeap::new).push(Reverse(Timer { deadline, callback, data }));
+}
+
+pub fn add_timer_after_ms(milliseconds: u64, callback: fn(u64), data: u64) {
+    add_timer(uptime() + milliseconds * 1_000_000, callback, data);
+}
+
+/// Wait for at least `milliseconds`, halting the CPU between ticks
+pub fn sleep_ms(milliseconds: u64) {
+    let deadline = uptime() + milliseconds * 1_000_000;
+    while uptime() < deadline {
+        if interrupt::are_enabled() {
+            unsafe { asm!("hlt", options(nomem, nostack)) }
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! USB requests and descriptors shared by host controller and class drivers
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+mod hid;
+pub mod xhci;
+
+pub mod request {
+    pub const CLEAR_FEATURE: u8 = 1;
+    pub const GET_DESCRIPTOR: u8 = 6;
+    pub const SET_CONFIGURATION: u8 = 9;
+}
+
+pub mod descriptor_type {
+    pub const DEVICE: u8 = 1;
+    pub const CONFIGURATION: u8 = 2;
+    pub const INTERFACE: u8 = 4;
+    pub const ENDPOINT: u8 = 5;
+}
+
+/// Driver bound to one interface of a configured device
+pub trait ClassDriver: Send {
+    /// Called once the configuration is active. Queue the first transfers here.
+    fn start(&mut self, controller: &mut xhci::Controller, slot_id: u8) -> Result<(), xhci::XhciError>;
+
+    /// An IN transfer queued by this driver on `endpoint_address` completed
+    fn on_transfer(&mut self, controller: &mut xhci::Controller, slot_id: u8, endpoint_address: u8, data: &[u8]);
+
+    /// An IN transfer on `endpoint_address` failed. The endpoint has been
+    /// reset and is empty, so the driver has to queue transfers again.
+    fn on_error(&mut self, controller: &mut xhci::Controller, slot_id: u8, endpoint_address: u8, error: xhci::XhciError);
+
+    fn owns_endpoint(&self, endpoint_address: u8) -> bool;
+}
+
+/// Find a driver for `interface`
+pub fn probe(interface: &InterfaceDescriptor) -> Option<Box<dyn ClassDriver>> {
+    hid::probe(interface)
+}
+
+/// bmRequestType bits
+pub mod request_type {
+    pub const DIRECTION_IN: u8 = 1 << 7;
+    pub const CLASS: u8 = 1 << 5;
+    pub const RECIPIENT_INTERFACE: u8 = 1;
+    pub const RECIPIENT_ENDPOINT: u8 = 2;
+}
+
+/// Feature selector of CLEAR_FEATURE for endpoints
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+pub const DEVICE_DESCRIPTOR_LENGTH: u16 = 18;
+pub const CONFIGURATION_DESCRIPTOR_LENGTH: u16 = 9;
+
+/// Setup packet of a control transfer
+#[derive(Clone, Copy, Debug)]
+pub struct SetupData {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupData {
+    pub fn get_descriptor(descriptor_type: u8, index: u8, length: u16) -> Self {
+        Self {
+            request_type: request_type::DIRECTION_IN,
+            request: request::GET_DESCRIPTOR,
+            value: (descriptor_type as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> Self {
+        Self {
+            request_type: 0,
+            request: request::SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// Resume an endpoint that answered with STALL
+    pub fn clear_endpoint_halt(endpoint_address: u8) -> Self {
+        Self {
+            request_type: request_type::RECIPIENT_ENDPOINT,
+            request: request::CLEAR_FEATURE,
+            value: FEATURE_ENDPOINT_HALT,
+            index: endpoint_address as u16,
+            length: 0,
+        }
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & request_type::DIRECTION_IN != 0
+    }
+
+    /// Little endian wire format
+    pub fn to_u64(self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceDescriptor {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl DeviceDescriptor {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < DEVICE_DESCRIPTOR_LENGTH as usize || data[1] != descriptor_type::DEVICE {
+            return None;
+        }
+        Some(Self {
+            vendor_id: read_u16(data, 8),
+            product_id: read_u16(data, 10),
+        })
+    }
+}
+
+pub mod transfer_type {
+    pub const CONTROL: u8 = 0;
+    pub const ISOCHRONOUS: u8 = 1;
+    pub const BULK: u8 = 2;
+    pub const INTERRUPT: u8 = 3;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    /// Raw wMaxPacketSize; high speed periodic endpoints keep extra transactions in bits 11-12
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn transfer_type(&self) -> u8 {
+        self.attributes & 0b11
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConfigurationDescriptor {
+    pub total_length: u16,
+    pub value: u8,
+    pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+impl ConfigurationDescriptor {
+    /// Parse a configuration descriptor together with the interface and
+    /// endpoint descriptors that follow it. Unknown descriptors are skipped.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < CONFIGURATION_DESCRIPTOR_LENGTH as usize || data[1] != descriptor_type::CONFIGURATION {
+            return None;
+        }
+        let mut configuration = Self {
+            total_length: read_u16(data, 2),
+            value: data[5],
+            interfaces: Vec::new(),
+        };
+        let end = data.len().min(configuration.total_length as usize);
+        let mut offset = data[0] as usize;
+        while offset + 2 <= end {
+            let length = data[offset] as usize;
+            if length < 2 || offset + length > end {
+                break;
+            }
+            let descriptor = &data[offset..offset + length];
+            match descriptor[1] {
+                descriptor_type::INTERFACE if length >= 9 => configuration.interfaces.push(InterfaceDescriptor {
+                    number: descriptor[2],
+                    alternate_setting: descriptor[3],
+                    class: descriptor[5],
+                    subclass: descriptor[6],
+                    protocol: descriptor[7],
+                    endpoints: Vec::new(),
+                }),
+                descriptor_type::ENDPOINT if length >= 7 => {
+                    if let Some(interface) = configuration.interfaces.last_mut() {
+                        interface.endpoints.push(EndpointDescriptor {
+                            address: descriptor[2],
+                            attributes: descriptor[3],
+                            max_packet_size: read_u16(descriptor, 4),
+                            interval: descriptor[6],
+                        });
+                    }
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        Some(configuration)
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! HID class driver for boot protocol keyboards and mice
+
+use alloc::boxed::Box;
+
+use super::xhci::{Controller, XhciError};
+use super::{request_type, transfer_type, ClassDriver, EndpointDescriptor, InterfaceDescriptor, SetupData};
+use crate::input::{self, InputEvent, Modifiers, KEYCODE_LEFT_CONTROL};
+
+const CLASS_HID: u8 = 3;
+const SUBCLASS_BOOT: u8 = 1;
+const PROTOCOL_KEYBOARD: u8 = 1;
+const PROTOCOL_MOUSE: u8 = 2;
+
+const REQUEST_SET_IDLE: u8 = 0x0a;
+const REQUEST_SET_PROTOCOL: u8 = 0x0b;
+const BOOT_PROTOCOL: u16 = 0;
+
+/// Reported in every key slot when too many keys are held down
+const KEYCODE_ERROR_ROLL_OVER: u8 = 0x01;
+const KEYBOARD_REPORT_KEYS: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Keyboard,
+    Mouse,
+}
+
+pub struct HidDriver {
+    kind: Kind,
+    interface: u8,
+    endpoint: EndpointDescriptor,
+    modifiers: Modifiers,
+    keys: [u8; KEYBOARD_REPORT_KEYS],
+}
+
+/// Bind to boot keyboards and mice that have an interrupt IN endpoint
+pub fn probe(interface: &InterfaceDescriptor) -> Option<Box<dyn ClassDriver>> {
+    if interface.class != CLASS_HID || interface.subclass != SUBCLASS_BOOT {
+        return None;
+    }
+    let kind = match interface.protocol {
+        PROTOCOL_KEYBOARD => Kind::Keyboard,
+        PROTOCOL_MOUSE => Kind::Mouse,
+        _ => return None,
+    };
+    let endpoint = interface
+        .endpoints
+        .iter()
+        .find(|endpoint| endpoint.is_in() && endpoint.transfer_type() == transfer_type::INTERRUPT)?;
+    Some(Box::new(HidDriver {
+        kind,
+        interface: interface.number,
+        endpoint: *endpoint,
+        modifiers: Modifiers::default(),
+        keys: [0; KEYBOARD_REPORT_KEYS],
+    }))
+}
+
+impl HidDriver {
+    fn class_request(&self, request: u8, value: u16) -> SetupData {
+        SetupData {
+            request_type: request_type::CLASS | request_type::RECIPIENT_INTERFACE,
+            request,
+            value,
+            index: self.interface as u16,
+            length: 0,
+        }
+    }
+
+    fn queue_report(&self, controller: &mut Controller, slot_id: u8) -> Result<(), XhciError> {
+        let length = (self.endpoint.max_packet_size & 0x7ff) as usize;
+        controller.queue_transfer_in(slot_id, self.endpoint.address, length)
+    }
+
+    /// Boot keyboard report: modifiers, reserved, then up to six pressed keys
+    fn on_keyboard_report(&mut self, report: &[u8]) {
+        if report.len() < 2 + KEYBOARD_REPORT_KEYS {
+            return;
+        }
+        let modifiers = Modifiers(report[0]);
+        let mut keys = [0; KEYBOARD_REPORT_KEYS];
+        keys.copy_from_slice(&report[2..2 + KEYBOARD_REPORT_KEYS]);
+        if keys.contains(&KEYCODE_ERROR_ROLL_OVER) {
+            return;
+        }
+
+        // Modifiers are reported as key events too, in usage ID order
+        let changed = modifiers.0 ^ self.modifiers.0;
+        for bit in 0..8 {
+            if changed & 1 << bit != 0 {
+                let pressed = modifiers.0 & 1 << bit != 0;
+                input::push_event(InputEvent::key(KEYCODE_LEFT_CONTROL + bit, modifiers, pressed));
+            }
+        }
+        for &keycode in self.keys.iter().filter(|&&keycode| keycode != 0 && !keys.contains(&keycode)) {
+            input::push_event(InputEvent::key(keycode, modifiers, false));
+        }
+        for &keycode in keys.iter().filter(|&&keycode| keycode != 0 && !self.keys.contains(&keycode)) {
+            input::push_event(InputEvent::key(keycode, modifiers, true));
+        }
+        self.modifiers = modifiers;
+        self.keys = keys;
+    }
+
+    /// Boot mouse report: buttons, then signed X and Y displacement. Anything
+    /// after that, like the wheel most mice append, is ignored.
+    fn on_mouse_report(&mut self, report: &[u8]) {
+        if report.len() < 3 {
+            return;
+        }
+        input::push_event(InputEvent::Mouse {
+            dx: report[1] as i8 as i32,
+            dy: report[2] as i8 as i32,
+            buttons: report[0] & 0b111,
+        });
+    }
+}
+
+impl ClassDriver for HidDriver {
+    fn start(&mut self, controller: &mut Controller, slot_id: u8) -> Result<(), XhciError> {
+        controller.control_transfer(slot_id, self.class_request(REQUEST_SET_PROTOCOL, BOOT_PROTOCOL), &mut [])?;
+        if self.kind == Kind::Keyboard {
+            // Report only on change; some keyboards refuse, which is harmless
+            let _ = controller.control_transfer(slot_id, self.class_request(REQUEST_SET_IDLE, 0), &mut []);
+        }
+        println!(
+            "USB: slot {} interface {}: HID boot {}",
+            slot_id,
+            self.interface,
+            if self.kind == Kind::Keyboard { "keyboard" } else { "mouse" }
+        );
+        self.queue_report(controller, slot_id)
+    }
+
+    fn on_transfer(&mut self, controller: &mut Controller, slot_id: u8, _endpoint_address: u8, data: &[u8]) {
+        match self.kind {
+            Kind::Keyboard => self.on_keyboard_report(data),
+            Kind::Mouse => self.on_mouse_report(data),
+        }
+        if let Err(err) = self.queue_report(controller, slot_id) {
+            println!("[ERROR] USB: slot {}: {:?}", slot_id, err);
+        }
+    }
+
+    fn on_error(&mut self, controller: &mut Controller, slot_id: u8, _endpoint_address: u8, _error: XhciError) {
+        // Reports already seen are kept, so a lost one only delays the next change
+        if let Err(err) = self.queue_report(controller, slot_id) {
+            println!("[ERROR] USB: slot {}: {:?}", slot_id, err);
+        }
+    }
+
+    fn owns_endpoint(&self, endpoint_address: u8) -> bool {
+        self.endpoint.address == endpoint_address
+    }
+}
@@ -0,0 +1,830 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! xHCI USB host controller driver
+//!
+//! Commands and control transfers are issued synchronously, polling the event
+//! ring until the matching completion arrives. Events that show up in the
+//! meantime are kept and handled by `poll()`.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::apic;
+use crate::interrupt::InterruptFrame;
+use crate::memory_manager::{self, BYTES_PER_FRAME};
+use crate::message::{self, Message};
+use crate::pci;
+use crate::sync::Mutex;
+use crate::task;
+use crate::timer;
+use crate::usb::{
+    self, descriptor_type, transfer_type, ClassDriver, ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, SetupData,
+    CONFIGURATION_DESCRIPTOR_LENGTH, DEVICE_DESCRIPTOR_LENGTH,
+};
+
+mod context;
+mod registers;
+mod ring;
+
+use context::{endpoint_type, DeviceContext, EndpointConfig, InputContext, CONTROL_ENDPOINT_DCI};
+use registers::*;
+use ring::{completion_code, trb_type, EventRing, Ring, Trb};
+
+const COMMAND_RING_SIZE: usize = 32;
+const EVENT_RING_SIZE: usize = 64;
+const TRANSFER_RING_SIZE: usize = 32;
+/// Upper bound on device slots, well above what a keyboard and mouse need
+const MAX_SLOTS: u8 = 16;
+/// In 250ns units, so at most one interrupt per millisecond
+const INTERRUPT_MODERATION_INTERVAL: u16 = 4000;
+
+const HALT_TIMEOUT_MS: u64 = 100;
+const RESET_TIMEOUT_MS: u64 = 1000;
+const PORT_RESET_TIMEOUT_MS: u64 = 500;
+const COMMAND_TIMEOUT_MS: u64 = 1000;
+const TRANSFER_TIMEOUT_MS: u64 = 1000;
+
+/// Default protocol speed IDs reported in PORTSC
+pub mod speed {
+    pub const FULL: u8 = 1;
+    pub const LOW: u8 = 2;
+    pub const HIGH: u8 = 3;
+    pub const SUPER: u8 = 4;
+}
+
+fn speed_name(speed: u8) -> &'static str {
+    match speed {
+        speed::FULL => "full speed",
+        speed::LOW => "low speed",
+        speed::HIGH => "high speed",
+        speed::SUPER => "SuperSpeed",
+        _ => "SuperSpeedPlus",
+    }
+}
+
+#[derive(Debug)]
+pub enum XhciError {
+    NoController,
+    /// BAR0 is not a memory BAR
+    NoMmio,
+    /// The controller does not support 4KiB pages
+    UnsupportedPageSize,
+    NoMemory,
+    Timeout,
+    NotConnected,
+    InvalidSlot,
+    CommandFailed(u8),
+    TransferFailed(u8),
+    InvalidDescriptor,
+    /// The endpoint already has a transfer in flight
+    EndpointBusy,
+}
+
+/// Zeroed, page aligned, physically contiguous memory the controller reads
+/// and writes. Page alignment also keeps rings and contexts from crossing the
+/// page and 64KiB boundaries the specification forbids.
+struct DmaBuffer {
+    address: usize,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    fn new(bytes: usize) -> Result<Self, XhciError> {
+        let frames = (bytes + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME;
+        let address = memory_manager::allocate_frames(frames).ok_or(XhciError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(address as *mut u8, 0, frames * BYTES_PER_FRAME) };
+        Ok(Self { address, frames })
+    }
+
+    fn address(&self) -> usize {
+        self.address
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        memory_manager::free_frames(self.address, self.frames);
+    }
+}
+
+/// Spin until `condition` holds. Relies on the timer interrupt for the timeout.
+fn wait_for(condition: impl Fn() -> bool, timeout_ms: u64) -> bool {
+    let deadline = timer::uptime() + timeout_ms * 1_000_000;
+    while !condition() {
+        if timer::uptime() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Normal TRB in flight on an IN endpoint, reading into the endpoint's buffer
+struct Transfer {
+    trb: u64,
+    dci: u8,
+    length: usize,
+}
+
+/// A device that has been given a slot
+struct Device {
+    port: u8,
+    speed: u8,
+    device_context: DeviceContext,
+    input_context: InputContext,
+    /// Transfer rings indexed by device context index
+    rings: Vec<Option<Ring>>,
+    /// Bounce buffer for control transfers
+    buffer: DmaBuffer,
+    /// One page per IN endpoint, indexed like `rings` and reused by every transfer
+    endpoint_buffers: Vec<Option<DmaBuffer>>,
+    descriptor: Option<DeviceDescriptor>,
+    configuration: Option<ConfigurationDescriptor>,
+    drivers: Vec<Box<dyn ClassDriver>>,
+    transfers: Vec<Transfer>,
+}
+
+impl Device {
+    fn new(port: u8, speed: u8, context_size: usize) -> Result<Self, XhciError> {
+        let mut rings = Vec::new();
+        rings.resize_with(32, || None);
+        let mut endpoint_buffers = Vec::new();
+        endpoint_buffers.resize_with(32, || None);
+        rings[CONTROL_ENDPOINT_DCI as usize] = Some(Ring::new(TRANSFER_RING_SIZE)?);
+        Ok(Self {
+            port,
+            speed,
+            device_context: DeviceContext::new(context_size)?,
+            input_context: InputContext::new(context_size)?,
+            rings,
+            buffer: DmaBuffer::new(BYTES_PER_FRAME)?,
+            endpoint_buffers,
+            descriptor: None,
+            configuration: None,
+            drivers: Vec::new(),
+            transfers: Vec::new(),
+        })
+    }
+
+    /// Packet size assumed for the default control endpoint until the device descriptor is read
+    fn initial_max_packet_size(&self) -> u16 {
+        match self.speed {
+            speed::LOW | speed::FULL => 8,
+            speed::HIGH => 64,
+            _ => 512,
+        }
+    }
+}
+
+/// Device context index of a non-control endpoint
+fn endpoint_dci(endpoint: &EndpointDescriptor) -> u8 {
+    address_to_dci(endpoint.address)
+}
+
+fn address_to_dci(endpoint_address: u8) -> u8 {
+    (endpoint_address & 0x0f) * 2 + (endpoint_address >> 7)
+}
+
+fn dci_to_address(dci: u8) -> u8 {
+    (dci / 2) | ((dci & 1) << 7)
+}
+
+fn endpoint_type(endpoint: &EndpointDescriptor) -> u8 {
+    match (endpoint.transfer_type(), endpoint.is_in()) {
+        (transfer_type::CONTROL, _) => endpoint_type::CONTROL,
+        (transfer_type::ISOCHRONOUS, true) => endpoint_type::ISOCH_IN,
+        (transfer_type::ISOCHRONOUS, false) => endpoint_type::ISOCH_OUT,
+        (transfer_type::BULK, true) => endpoint_type::BULK_IN,
+        (transfer_type::BULK, false) => endpoint_type::BULK_OUT,
+        (_, true) => endpoint_type::INTERRUPT_IN,
+        (_, false) => endpoint_type::INTERRUPT_OUT,
+    }
+}
+
+/// Convert bInterval into the exponent of 125us the endpoint context expects
+fn endpoint_interval(speed: u8, endpoint: &EndpointDescriptor) -> u8 {
+    match (endpoint.transfer_type(), speed) {
+        // Frames of 1ms; round down to a power of two of microframes
+        (transfer_type::INTERRUPT, speed::LOW | speed::FULL) => {
+            let microframes = endpoint.interval.max(1) as u32 * 8;
+            ((31 - microframes.leading_zeros()) as u8).clamp(3, 10)
+        }
+        (transfer_type::ISOCHRONOUS, speed::FULL) => endpoint.interval.clamp(1, 16) + 2,
+        (transfer_type::INTERRUPT | transfer_type::ISOCHRONOUS, _) => endpoint.interval.clamp(1, 16) - 1,
+        _ => 0,
+    }
+}
+
+pub struct Controller {
+    registers: Registers,
+    device_context_base_array: DmaBuffer,
+    /// Kept alive for the controller; never touched by the driver
+    _scratchpad_buffers: Vec<DmaBuffer>,
+    command_ring: Ring,
+    event_ring: EventRing,
+    /// Indexed by slot ID
+    devices: Vec<Option<Device>>,
+    /// Events that arrived while waiting for a specific completion
+    pending_events: VecDeque<Trb>,
+    interrupts: Option<pci::Interrupts>,
+}
+
+impl Controller {
+    fn new(pci_device: &pci::Device) -> Result<Self, XhciError> {
+        let mmio_base = pci_device.memory_bar(0).ok_or(XhciError::NoMmio)? as usize;
+        pci_device.set_command(pci::command::MEMORY_SPACE | pci::command::BUS_MASTER);
+        let registers = unsafe { Registers::new(mmio_base) };
+        registers.request_ownership();
+
+        registers.set_usbcmd(registers.usbcmd() & !(USBCMD_RUN_STOP | USBCMD_INTERRUPTER_ENABLE));
+        if !wait_for(|| registers.usbsts() & USBSTS_HC_HALTED != 0, HALT_TIMEOUT_MS) {
+            return Err(XhciError::Timeout);
+        }
+        registers.set_usbcmd(USBCMD_HOST_CONTROLLER_RESET);
+        let reset_done = || {
+            registers.usbcmd() & USBCMD_HOST_CONTROLLER_RESET == 0
+                && registers.usbsts() & USBSTS_CONTROLLER_NOT_READY == 0
+        };
+        if !wait_for(reset_done, RESET_TIMEOUT_MS) {
+            return Err(XhciError::Timeout);
+        }
+        if registers.page_size() & 1 == 0 {
+            return Err(XhciError::UnsupportedPageSize);
+        }
+
+        let slots = registers.max_slots().min(MAX_SLOTS);
+        registers.set_max_slots_enabled(slots);
+
+        // Entry 0 points to the scratchpad buffer array when the controller wants one
+        let device_context_base_array = DmaBuffer::new((slots as usize + 1) * 8)?;
+        let mut scratchpad_buffers = Vec::new();
+        let scratchpad_count = registers.max_scratchpad_buffers();
+        if scratchpad_count > 0 {
+            let array = DmaBuffer::new(scratchpad_count * 8)?;
+            for i in 0..scratchpad_count {
+                let buffer = DmaBuffer::new(BYTES_PER_FRAME)?;
+                unsafe { (array.address() as *mut u64).add(i).write_volatile(buffer.address() as u64) };
+                scratchpad_buffers.push(buffer);
+            }
+            unsafe { (device_context_base_array.address() as *mut u64).write_volatile(array.address() as u64) };
+            scratchpad_buffers.push(array);
+        }
+        registers.set_device_context_base_array(device_context_base_array.address() as u64);
+
+        let command_ring = Ring::new(COMMAND_RING_SIZE)?;
+        registers.set_command_ring(command_ring.address());
+
+        let event_ring = EventRing::new(EVENT_RING_SIZE)?;
+        registers.set_event_ring(event_ring.table_address(), event_ring.table_size(), event_ring.dequeue_pointer());
+        registers.enable_primary_interrupter(INTERRUPT_MODERATION_INTERVAL);
+
+        let mut devices = Vec::new();
+        devices.resize_with(slots as usize + 1, || None);
+
+        Ok(Self {
+            registers,
+            device_context_base_array,
+            _scratchpad_buffers: scratchpad_buffers,
+            command_ring,
+            event_ring,
+            devices,
+            pending_events: VecDeque::new(),
+            interrupts: None,
+        })
+    }
+
+    fn run(&self) -> Result<(), XhciError> {
+        let registers = &self.registers;
+        registers.set_usbcmd(registers.usbcmd() | USBCMD_RUN_STOP | USBCMD_INTERRUPTER_ENABLE);
+        if !wait_for(|| registers.usbsts() & USBSTS_HC_HALTED == 0, HALT_TIMEOUT_MS) {
+            return Err(XhciError::Timeout);
+        }
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<Trb> {
+        let event = self.event_ring.pop()?;
+        self.registers.set_event_ring_dequeue_pointer(self.event_ring.dequeue_pointer());
+        Some(event)
+    }
+
+    fn wait_event(&mut self, matches: impl Fn(&Trb) -> bool, timeout_ms: u64) -> Result<Trb, XhciError> {
+        let deadline = timer::uptime() + timeout_ms * 1_000_000;
+        loop {
+            match self.next_event() {
+                Some(event) if matches(&event) => return Ok(event),
+                Some(event) => self.pending_events.push_back(event),
+                None if timer::uptime() >= deadline => return Err(XhciError::Timeout),
+                None => task::yield_now(),
+            }
+        }
+    }
+
+    fn execute_command(&mut self, command: Trb) -> Result<Trb, XhciError> {
+        let address = self.command_ring.push(command);
+        self.registers.ring_doorbell(0, 0);
+        let event = self.wait_event(
+            |event| event.trb_type() == trb_type::COMMAND_COMPLETION && event.parameter == address,
+            COMMAND_TIMEOUT_MS,
+        )?;
+        match event.completion_code() {
+            completion_code::SUCCESS => Ok(event),
+            code => Err(XhciError::CommandFailed(code)),
+        }
+    }
+
+    fn device_mut(&mut self, slot_id: u8) -> Result<&mut Device, XhciError> {
+        self.devices
+            .get_mut(slot_id as usize)
+            .and_then(Option::as_mut)
+            .ok_or(XhciError::InvalidSlot)
+    }
+
+    /// Run a control transfer on the default endpoint. `data` must hold
+    /// `setup.length` bytes, which may not exceed a page.
+    pub fn control_transfer(&mut self, slot_id: u8, setup: SetupData, data: &mut [u8]) -> Result<usize, XhciError> {
+        let length = (setup.length as usize).min(data.len()).min(BYTES_PER_FRAME);
+        let is_in = setup.is_in();
+        let device = self.device_mut(slot_id)?;
+        let buffer = device.buffer.address();
+        if !is_in {
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, length) };
+        }
+
+        let ring = device.rings[CONTROL_ENDPOINT_DCI as usize].as_mut().ok_or(XhciError::InvalidSlot)?;
+        ring.push(Trb::setup_stage(&setup));
+        let data_trb = if length > 0 {
+            Some(ring.push(Trb::data_stage(buffer as u64, length as u32, is_in)))
+        } else {
+            None
+        };
+        let status_trb = ring.push(Trb::status_stage(length == 0 || !is_in));
+        self.registers.ring_doorbell(slot_id, CONTROL_ENDPOINT_DCI);
+
+        let mut transferred = length;
+        loop {
+            let event = self.wait_event(
+                |event| {
+                    event.trb_type() == trb_type::TRANSFER_EVENT
+                        && event.slot_id() == slot_id
+                        && event.endpoint_id() == CONTROL_ENDPOINT_DCI
+                },
+                TRANSFER_TIMEOUT_MS,
+            )?;
+            match event.completion_code() {
+                completion_code::SUCCESS => {}
+                completion_code::SHORT_PACKET if Some(event.parameter) == data_trb => {
+                    transferred = length - (event.residual_length() as usize).min(length);
+                }
+                code => {
+                    // A STALL on the default endpoint clears itself with the next setup stage
+                    self.recover_endpoint(slot_id, CONTROL_ENDPOINT_DCI)?;
+                    return Err(XhciError::TransferFailed(code));
+                }
+            }
+            if event.parameter == status_trb {
+                break;
+            }
+        }
+
+        if is_in {
+            unsafe { core::ptr::copy_nonoverlapping(buffer as *const u8, data.as_mut_ptr(), transferred) };
+        }
+        Ok(transferred)
+    }
+
+    /// Reset a USB2 port, or wait for a USB3 port that enables itself after
+    /// link training. Returns the port speed.
+    fn reset_port(&self, port: u8) -> Result<u8, XhciError> {
+        let registers = &self.registers;
+        if registers.portsc(port) & PORTSC_CURRENT_CONNECT == 0 {
+            return Err(XhciError::NotConnected);
+        }
+        if registers.portsc(port) & PORTSC_ENABLED == 0 {
+            registers.write_portsc(port, PORTSC_RESET);
+            if !wait_for(|| registers.portsc(port) & PORTSC_RESET_CHANGE != 0, PORT_RESET_TIMEOUT_MS) {
+                return Err(XhciError::Timeout);
+            }
+        }
+        registers.write_portsc(port, registers.portsc(port) & PORTSC_CHANGE_BITS);
+
+        let portsc = registers.portsc(port);
+        if portsc & PORTSC_ENABLED == 0 {
+            return Err(XhciError::NotConnected);
+        }
+        Ok((portsc >> PORTSC_SPEED_SHIFT & PORTSC_SPEED_MASK) as u8)
+    }
+
+    fn slot_of_port(&self, port: u8) -> Option<u8> {
+        self.devices
+            .iter()
+            .position(|device| matches!(device, Some(device) if device.port == port))
+            .map(|slot_id| slot_id as u8)
+    }
+
+    fn attach(&mut self, port: u8) -> Result<u8, XhciError> {
+        let speed = self.reset_port(port)?;
+        let slot_id = self.execute_command(Trb::enable_slot())?.slot_id();
+        if let Err(err) = self.initialize_device(slot_id, port, speed) {
+            self.release_slot(slot_id);
+            return Err(err);
+        }
+        Ok(slot_id)
+    }
+
+    fn initialize_device(&mut self, slot_id: u8, port: u8, speed: u8) -> Result<(), XhciError> {
+        if slot_id == 0 || slot_id as usize >= self.devices.len() {
+            return Err(XhciError::InvalidSlot);
+        }
+        let device = Device::new(port, speed, self.registers.context_size())?;
+        let input = &device.input_context;
+        input.set_add_flags(1 << 0 | 1 << CONTROL_ENDPOINT_DCI);
+        input.set_slot(speed, port, CONTROL_ENDPOINT_DCI);
+        input.set_endpoint(&EndpointConfig {
+            dci: CONTROL_ENDPOINT_DCI,
+            endpoint_type: endpoint_type::CONTROL,
+            max_packet_size: device.initial_max_packet_size(),
+            max_burst: 0,
+            interval: 0,
+            ring: device.rings[CONTROL_ENDPOINT_DCI as usize].as_ref().unwrap().address(),
+            average_trb_length: 8,
+        });
+        let input_address = input.address();
+        unsafe {
+            (self.device_context_base_array.address() as *mut u64)
+                .add(slot_id as usize)
+                .write_volatile(device.device_context.address());
+        }
+        self.devices[slot_id as usize] = Some(device);
+        self.execute_command(Trb::address_device(input_address, slot_id))?;
+
+        self.update_control_max_packet_size(slot_id)?;
+
+        let mut data = [0u8; DEVICE_DESCRIPTOR_LENGTH as usize];
+        let setup = SetupData::get_descriptor(descriptor_type::DEVICE, 0, DEVICE_DESCRIPTOR_LENGTH);
+        let length = self.control_transfer(slot_id, setup, &mut data)?;
+        let descriptor = DeviceDescriptor::parse(&data[..length]).ok_or(XhciError::InvalidDescriptor)?;
+
+        // Read the header for the total length, then everything
+        let mut header = [0u8; CONFIGURATION_DESCRIPTOR_LENGTH as usize];
+        let setup = SetupData::get_descriptor(descriptor_type::CONFIGURATION, 0, CONFIGURATION_DESCRIPTOR_LENGTH);
+        let length = self.control_transfer(slot_id, setup, &mut header)?;
+        let header = ConfigurationDescriptor::parse(&header[..length]).ok_or(XhciError::InvalidDescriptor)?;
+        let mut data = vec![0u8; header.total_length as usize];
+        let setup = SetupData::get_descriptor(descriptor_type::CONFIGURATION, 0, header.total_length);
+        let length = self.control_transfer(slot_id, setup, &mut data)?;
+        let configuration = ConfigurationDescriptor::parse(&data[..length]).ok_or(XhciError::InvalidDescriptor)?;
+
+        self.configure_endpoints(slot_id, &configuration)?;
+        self.control_transfer(slot_id, SetupData::set_configuration(configuration.value), &mut [])?;
+
+        println!(
+            "USB: port {} slot {} {:04x}:{:04x} ({}), {} interface(s)",
+            port,
+            slot_id,
+            descriptor.vendor_id,
+            descriptor.product_id,
+            speed_name(speed),
+            configuration.interfaces.len()
+        );
+        let drivers = configuration
+            .interfaces
+            .iter()
+            .filter(|interface| interface.alternate_setting == 0)
+            .filter_map(usb::probe)
+            .collect();
+        let device = self.device_mut(slot_id)?;
+        device.descriptor = Some(descriptor);
+        device.configuration = Some(configuration);
+        device.drivers = drivers;
+        self.start_drivers(slot_id)
+    }
+
+    /// Drivers get the controller itself, so they are taken out of the device while they run
+    fn with_drivers(&mut self, slot_id: u8, mut f: impl FnMut(&mut Self, &mut Box<dyn ClassDriver>)) {
+        let mut drivers = match self.device_mut(slot_id) {
+            Ok(device) => core::mem::take(&mut device.drivers),
+            Err(_) => return,
+        };
+        for driver in drivers.iter_mut() {
+            f(self, driver);
+        }
+        // The device may have been released meanwhile
+        if let Ok(device) = self.device_mut(slot_id) {
+            device.drivers = drivers;
+        }
+    }
+
+    fn start_drivers(&mut self, slot_id: u8) -> Result<(), XhciError> {
+        let mut result = Ok(());
+        self.with_drivers(slot_id, |controller, driver| {
+            if let Err(err) = driver.start(controller, slot_id) {
+                result = Err(err);
+            }
+        });
+        result
+    }
+
+    /// Queue a Normal TRB reading up to `length` bytes from an IN endpoint. The
+    /// data is handed to the class driver owning the endpoint on completion.
+    /// Transfers share the endpoint's buffer, so only one may be in flight.
+    pub fn queue_transfer_in(&mut self, slot_id: u8, endpoint_address: u8, length: usize) -> Result<(), XhciError> {
+        let dci = address_to_dci(endpoint_address);
+        let length = length.min(BYTES_PER_FRAME);
+        let device = self.device_mut(slot_id)?;
+        if device.transfers.iter().any(|transfer| transfer.dci == dci) {
+            return Err(XhciError::EndpointBusy);
+        }
+        let buffer = device
+            .endpoint_buffers
+            .get(dci as usize)
+            .and_then(Option::as_ref)
+            .ok_or(XhciError::InvalidSlot)?
+            .address();
+        let ring = device
+            .rings
+            .get_mut(dci as usize)
+            .and_then(Option::as_mut)
+            .ok_or(XhciError::InvalidSlot)?;
+        let trb = ring.push(Trb::normal(buffer as u64, length as u32));
+        device.transfers.push(Transfer { trb, dci, length });
+        self.registers.ring_doorbell(slot_id, dci);
+        Ok(())
+    }
+
+    /// Bring an endpoint halted by a transfer error back into service. Whatever
+    /// was still queued on it is dropped.
+    fn recover_endpoint(&mut self, slot_id: u8, dci: u8) -> Result<(), XhciError> {
+        self.execute_command(Trb::reset_endpoint(slot_id, dci))?;
+        let device = self.device_mut(slot_id)?;
+        device.transfers.retain(|transfer| transfer.dci != dci);
+        let dequeue = device
+            .rings
+            .get(dci as usize)
+            .and_then(Option::as_ref)
+            .ok_or(XhciError::InvalidSlot)?
+            .enqueue_pointer();
+        self.execute_command(Trb::set_tr_dequeue_pointer(dequeue, slot_id, dci))?;
+        Ok(())
+    }
+
+    fn on_transfer_event(&mut self, event: Trb) {
+        let slot_id = event.slot_id();
+        let device = match self.device_mut(slot_id) {
+            Ok(device) => device,
+            Err(_) => return,
+        };
+        let index = match device.transfers.iter().position(|transfer| transfer.trb == event.parameter) {
+            Some(index) => index,
+            None => return,
+        };
+        let transfer = device.transfers.swap_remove(index);
+        let endpoint_address = dci_to_address(transfer.dci);
+        match event.completion_code() {
+            completion_code::SUCCESS | completion_code::SHORT_PACKET => {}
+            code => {
+                println!("[ERROR] USB: slot {} endpoint {}: completion code {}", slot_id, transfer.dci, code);
+                if let Err(err) = self.recover_endpoint(slot_id, transfer.dci) {
+                    println!("[ERROR] USB: slot {} endpoint {}: reset failed: {:?}", slot_id, transfer.dci, err);
+                    return;
+                }
+                // The device halted its side of the endpoint as well
+                if code == completion_code::STALL_ERROR {
+                    let _ = self.control_transfer(slot_id, SetupData::clear_endpoint_halt(endpoint_address), &mut []);
+                }
+                self.with_drivers(slot_id, |controller, driver| {
+                    if driver.owns_endpoint(endpoint_address) {
+                        driver.on_error(controller, slot_id, endpoint_address, XhciError::TransferFailed(code));
+                    }
+                });
+                return;
+            }
+        }
+        let buffer = match device.endpoint_buffers.get(transfer.dci as usize).and_then(Option::as_ref) {
+            Some(buffer) => buffer.address(),
+            None => return,
+        };
+        let length = transfer.length - (event.residual_length() as usize).min(transfer.length);
+        // Stays valid while the drivers run: nothing is queued on the endpoint until they do
+        let data = unsafe { core::slice::from_raw_parts(buffer as *const u8, length) };
+        self.with_drivers(slot_id, |controller, driver| {
+            if driver.owns_endpoint(endpoint_address) {
+                driver.on_transfer(controller, slot_id, endpoint_address, data);
+            }
+        });
+    }
+
+    /// Read bMaxPacketSize0 and tell the controller if the initial guess was wrong
+    fn update_control_max_packet_size(&mut self, slot_id: u8) -> Result<(), XhciError> {
+        let mut header = [0u8; 8];
+        let setup = SetupData::get_descriptor(descriptor_type::DEVICE, 0, header.len() as u16);
+        if self.control_transfer(slot_id, setup, &mut header)? < header.len() {
+            return Err(XhciError::InvalidDescriptor);
+        }
+        let device = self.device_mut(slot_id)?;
+        let max_packet_size = if device.speed >= speed::SUPER {
+            1 << header[7].min(15)
+        } else {
+            header[7] as u16
+        };
+        if max_packet_size == device.initial_max_packet_size() {
+            return Ok(());
+        }
+        let input = &device.input_context;
+        input.set_add_flags(1 << CONTROL_ENDPOINT_DCI);
+        input.set_control_max_packet_size(max_packet_size);
+        let input_address = input.address();
+        self.execute_command(Trb::evaluate_context(input_address, slot_id))?;
+        Ok(())
+    }
+
+    /// Give every endpoint of the default alternate settings a transfer ring
+    fn configure_endpoints(&mut self, slot_id: u8, configuration: &ConfigurationDescriptor) -> Result<(), XhciError> {
+        let device = self.device_mut(slot_id)?;
+        let mut add_flags = 1 << 0;
+        let mut last_dci = CONTROL_ENDPOINT_DCI;
+        for interface in configuration.interfaces.iter().filter(|interface| interface.alternate_setting == 0) {
+            for endpoint in &interface.endpoints {
+                let dci = endpoint_dci(endpoint);
+                let ring = Ring::new(TRANSFER_RING_SIZE)?;
+                let is_periodic = matches!(
+                    endpoint.transfer_type(),
+                    transfer_type::INTERRUPT | transfer_type::ISOCHRONOUS
+                );
+                let max_burst = if device.speed == speed::HIGH && is_periodic {
+                    (endpoint.max_packet_size >> 11 & 0b11) as u8
+                } else {
+                    0
+                };
+                device.input_context.set_endpoint(&EndpointConfig {
+                    dci,
+                    endpoint_type: endpoint_type(endpoint),
+                    max_packet_size: endpoint.max_packet_size & 0x7ff,
+                    max_burst,
+                    interval: endpoint_interval(device.speed, endpoint),
+                    ring: ring.address(),
+                    average_trb_length: if is_periodic { 1024 } else { 3072 },
+                });
+                device.rings[dci as usize] = Some(ring);
+                if endpoint.is_in() {
+                    device.endpoint_buffers[dci as usize] = Some(DmaBuffer::new(BYTES_PER_FRAME)?);
+                }
+                add_flags |= 1 << dci;
+                last_dci = last_dci.max(dci);
+            }
+        }
+        if add_flags == 1 << 0 {
+            return Ok(());
+        }
+        device.input_context.set_add_flags(add_flags);
+        device.input_context.raise_context_entries(last_dci);
+        let input_address = device.input_context.address();
+        self.execute_command(Trb::configure_endpoint(input_address, slot_id))?;
+        Ok(())
+    }
+
+    /// Disable the slot, then free its contexts once the controller is done with them
+    fn release_slot(&mut self, slot_id: u8) {
+        if let Err(err) = self.execute_command(Trb::disable_slot(slot_id)) {
+            println!("[WARN] xHCI: disable slot {}: {:?}", slot_id, err);
+        }
+        if let Some(device) = self.devices.get_mut(slot_id as usize) {
+            unsafe {
+                (self.device_context_base_array.address() as *mut u64)
+                    .add(slot_id as usize)
+                    .write_volatile(0);
+            }
+            *device = None;
+        }
+    }
+
+    fn attach_port(&mut self, port: u8) {
+        if let Err(err) = self.attach(port) {
+            println!("[ERROR] USB: port {}: {:?}", port, err);
+        }
+    }
+
+    fn on_port_status_change(&mut self, port: u8) {
+        if port == 0 || port > self.registers.max_ports() {
+            return;
+        }
+        let portsc = self.registers.portsc(port);
+        self.registers.write_portsc(port, portsc & PORTSC_CHANGE_BITS);
+        match (portsc & PORTSC_CURRENT_CONNECT != 0, self.slot_of_port(port)) {
+            (true, None) => self.attach_port(port),
+            (false, Some(slot_id)) => {
+                println!("USB: port {} slot {} disconnected", port, slot_id);
+                self.release_slot(slot_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_event(&mut self, event: Trb) {
+        match event.trb_type() {
+            trb_type::PORT_STATUS_CHANGE => self.on_port_status_change(event.port_id()),
+            // Completions we stopped waiting for after a timeout
+            trb_type::COMMAND_COMPLETION => {}
+            trb_type::TRANSFER_EVENT => self.on_transfer_event(event),
+            _ => {}
+        }
+    }
+
+    /// Handle devices that were connected before the controller started
+    fn scan_ports(&mut self) {
+        for port in 1..=self.registers.max_ports() {
+            let portsc = self.registers.portsc(port);
+            if portsc & PORTSC_CURRENT_CONNECT != 0 && self.slot_of_port(port).is_none() {
+                self.registers.write_portsc(port, portsc & PORTSC_CHANGE_BITS);
+                self.attach_port(port);
+            }
+        }
+    }
+
+    /// Process every event produced since the last call
+    pub fn poll(&mut self) {
+        // Events arriving meanwhile are drained here anyway; the device holds
+        // the message back until the vector is unmasked instead of queueing
+        // a `Message::Xhci` for each of them
+        if let Some(interrupts) = &self.interrupts {
+            interrupts.mask(0);
+        }
+        self.registers.clear_usbsts(USBSTS_EVENT_INTERRUPT);
+        while let Some(event) = self.pending_events.pop_front().or_else(|| self.next_event()) {
+            self.handle_event(event);
+        }
+        if let Some(interrupts) = &self.interrupts {
+            interrupts.unmask(0);
+        }
+    }
+}
+
+/// IMAN of the primary interrupter, for the interrupt handler
+static INTERRUPTER_MANAGEMENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Task that called `init()`, which gets `Message::Xhci` when there are events
+static EVENT_TASK: AtomicU64 = AtomicU64::new(0);
+/// Without MSI the event ring is checked this often instead
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// Events are processed by `poll()`; the interrupt only tells the event task
+extern "x86-interrupt" fn interrupt_handler(_frame: InterruptFrame) {
+    let iman = INTERRUPTER_MANAGEMENT.load(Ordering::Relaxed);
+    if iman != 0 {
+        unsafe { (iman as *mut u32).write_volatile(IMAN_INTERRUPT_PENDING | IMAN_INTERRUPT_ENABLE) };
+    }
+    let _ = message::send(EVENT_TASK.load(Ordering::Relaxed), Message::Xhci);
+    apic::end_of_interrupt();
+}
+
+fn poll_timer(task: u64) {
+    let _ = message::send(task, Message::Xhci);
+    timer::add_timer_after_ms(POLL_INTERVAL_MS, poll_timer, task);
+}
+
+/// Only used from task context: `poll()` may wait for command completions
+/// while holding it, so a blocking lock lets other tasks run meanwhile
+static CONTROLLER: Mutex<Option<Controller>> = Mutex::new(None);
+
+/// Bring up the first xHC found on the PCI bus and attach connected devices.
+/// Needs interrupts enabled for its timeouts. The calling task is expected to
+/// call `poll()` whenever it receives `Message::Xhci`.
+pub fn init() -> Result<(), XhciError> {
+    let pci_device = pci::devices()
+        .iter()
+        .find(|device| device.class_code.matches(0x0c, 0x03, 0x30))
+        .ok_or(XhciError::NoController)?;
+    let mut controller = Controller::new(pci_device)?;
+
+    INTERRUPTER_MANAGEMENT.store(controller.registers.primary_interrupter_management(), Ordering::Relaxed);
+    let event_task = task::current_id();
+    EVENT_TASK.store(event_task, Ordering::Relaxed);
+    match pci::enable_interrupts(pci_device, &[interrupt_handler]) {
+        Ok(interrupts) => {
+            println!("xHCI: interrupt vector {:#04x} ({} in use)", interrupts.vector(0), interrupts.count());
+            controller.interrupts = Some(interrupts);
+        }
+        Err(err) => {
+            println!("[WARN] xHCI: no MSI ({:?}), polling only", err);
+            timer::add_timer_after_ms(POLL_INTERVAL_MS, poll_timer, event_task);
+        }
+    }
+    controller.run()?;
+    controller.scan_ports();
+
+    *CONTROLLER.lock() = Some(controller);
+    Ok(())
+}
+
+/// Handle pending events, such as hot plugged devices
+pub fn poll() {
+    if let Some(controller) = CONTROLLER.lock().as_mut() {
+        controller.poll();
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Slot, endpoint and input contexts shared with the controller
+
+use super::{DmaBuffer, XhciError};
+
+/// Device context index of the default control endpoint
+pub const CONTROL_ENDPOINT_DCI: u8 = 1;
+/// A device context holds the slot context and 31 endpoint contexts
+const DEVICE_CONTEXT_ENTRIES: usize = 32;
+
+pub mod endpoint_type {
+    pub const ISOCH_OUT: u8 = 1;
+    pub const BULK_OUT: u8 = 2;
+    pub const INTERRUPT_OUT: u8 = 3;
+    pub const CONTROL: u8 = 4;
+    pub const ISOCH_IN: u8 = 5;
+    pub const BULK_IN: u8 = 6;
+    pub const INTERRUPT_IN: u8 = 7;
+}
+
+/// Error count before the controller halts the endpoint
+const ENDPOINT_ERROR_COUNT: u32 = 3;
+
+/// Values for one endpoint context
+pub struct EndpointConfig {
+    pub dci: u8,
+    pub endpoint_type: u8,
+    pub max_packet_size: u16,
+    pub max_burst: u8,
+    /// Period of 2^interval * 125us for periodic endpoints
+    pub interval: u8,
+    pub ring: u64,
+    pub average_trb_length: u16,
+}
+
+/// Output device context, written by the controller
+pub struct DeviceContext {
+    memory: DmaBuffer,
+}
+
+impl DeviceContext {
+    pub fn new(context_size: usize) -> Result<Self, XhciError> {
+        Ok(Self {
+            memory: DmaBuffer::new(DEVICE_CONTEXT_ENTRIES * context_size)?,
+        })
+    }
+
+    pub fn address(&self) -> u64 {
+        self.memory.address() as u64
+    }
+}
+
+/// Input context: the input control context followed by a device context
+pub struct InputContext {
+    memory: DmaBuffer,
+    context_size: usize,
+}
+
+impl InputContext {
+    pub fn new(context_size: usize) -> Result<Self, XhciError> {
+        Ok(Self {
+            memory: DmaBuffer::new((DEVICE_CONTEXT_ENTRIES + 1) * context_size)?,
+            context_size,
+        })
+    }
+
+    pub fn address(&self) -> u64 {
+        self.memory.address() as u64
+    }
+
+    fn dword(&self, context: usize, index: usize) -> *mut u32 {
+        (self.memory.address() + context * self.context_size + index * 4) as *mut u32
+    }
+
+    fn write(&self, context: usize, index: usize, value: u32) {
+        unsafe { self.dword(context, index).write_volatile(value) }
+    }
+
+    fn read(&self, context: usize, index: usize) -> u32 {
+        unsafe { self.dword(context, index).read_volatile() }
+    }
+
+    /// Start a new command: clear the add/drop flags and select `contexts`
+    /// (bit 0 is the slot context, bit n the endpoint with DCI n)
+    pub fn set_add_flags(&self, contexts: u32) {
+        self.write(0, 0, 0);
+        self.write(0, 1, contexts);
+    }
+
+    /// Slot context for a device attached directly to a root hub port
+    pub fn set_slot(&self, speed: u8, root_hub_port: u8, context_entries: u8) {
+        self.write(1, 0, (speed as u32) << 20 | (context_entries as u32) << 27);
+        self.write(1, 1, (root_hub_port as u32) << 16);
+        self.write(1, 2, 0);
+        self.write(1, 3, 0);
+    }
+
+    /// Raise the context entries field so that newly added endpoints are valid
+    pub fn raise_context_entries(&self, context_entries: u8) {
+        let dword = self.read(1, 0);
+        if (dword >> 27) < context_entries as u32 {
+            self.write(1, 0, (dword & !(0x1f << 27)) | (context_entries as u32) << 27);
+        }
+    }
+
+    pub fn set_endpoint(&self, config: &EndpointConfig) {
+        let context = 1 + config.dci as usize;
+        self.write(context, 0, (config.interval as u32) << 16);
+        self.write(
+            context,
+            1,
+            ENDPOINT_ERROR_COUNT << 1
+                | (config.endpoint_type as u32) << 3
+                | (config.max_burst as u32) << 8
+                | (config.max_packet_size as u32) << 16,
+        );
+        // Dequeue cycle state starts at 1, matching a fresh ring
+        self.write(context, 2, config.ring as u32 | 1);
+        self.write(context, 3, (config.ring >> 32) as u32);
+        // Max ESIT payload is only meaningful for periodic endpoints; one packet per interval
+        let max_esit_payload = match config.endpoint_type {
+            endpoint_type::INTERRUPT_IN
+            | endpoint_type::INTERRUPT_OUT
+            | endpoint_type::ISOCH_IN
+            | endpoint_type::ISOCH_OUT => config.max_packet_size as u32 * (config.max_burst as u32 + 1),
+            _ => 0,
+        };
+        self.write(context, 4, config.average_trb_length as u32 | (max_esit_payload & 0xffff) << 16);
+    }
+
+    /// Update only the max packet size of the control endpoint, for Evaluate Context
+    pub fn set_control_max_packet_size(&self, max_packet_size: u16) {
+        let context = 1 + CONTROL_ENDPOINT_DCI as usize;
+        let dword = self.read(context, 1);
+        self.write(context, 1, (dword & 0xffff) | (max_packet_size as u32) << 16);
+    }
+}
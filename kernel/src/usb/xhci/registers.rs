@@ -0,0 +1,254 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Memory mapped xHCI register sets
+
+/// Offsets from the MMIO base
+mod capability {
+    pub const CAPLENGTH: usize = 0x00;
+    pub const HCSPARAMS1: usize = 0x04;
+    pub const HCSPARAMS2: usize = 0x08;
+    pub const HCCPARAMS1: usize = 0x10;
+    pub const DBOFF: usize = 0x14;
+    pub const RTSOFF: usize = 0x18;
+}
+
+/// Offsets from the operational base
+mod operational {
+    pub const USBCMD: usize = 0x00;
+    pub const USBSTS: usize = 0x04;
+    pub const PAGESIZE: usize = 0x08;
+    pub const CRCR: usize = 0x18;
+    pub const DCBAAP: usize = 0x30;
+    pub const CONFIG: usize = 0x38;
+    pub const PORT_REGISTER_SET: usize = 0x400;
+    pub const PORT_REGISTER_SET_SIZE: usize = 0x10;
+}
+
+/// Offsets within an interrupter register set
+mod interrupter {
+    pub const IMAN: usize = 0x00;
+    pub const IMOD: usize = 0x04;
+    pub const ERSTSZ: usize = 0x08;
+    pub const ERSTBA: usize = 0x10;
+    pub const ERDP: usize = 0x18;
+}
+
+const RUNTIME_INTERRUPTER_BASE: usize = 0x20;
+
+pub const USBCMD_RUN_STOP: u32 = 1 << 0;
+pub const USBCMD_HOST_CONTROLLER_RESET: u32 = 1 << 1;
+pub const USBCMD_INTERRUPTER_ENABLE: u32 = 1 << 2;
+
+pub const USBSTS_HC_HALTED: u32 = 1 << 0;
+pub const USBSTS_EVENT_INTERRUPT: u32 = 1 << 3;
+pub const USBSTS_CONTROLLER_NOT_READY: u32 = 1 << 11;
+
+pub const CRCR_RING_CYCLE_STATE: u64 = 1 << 0;
+
+pub const IMAN_INTERRUPT_PENDING: u32 = 1 << 0;
+pub const IMAN_INTERRUPT_ENABLE: u32 = 1 << 1;
+
+pub const ERDP_EVENT_HANDLER_BUSY: u64 = 1 << 3;
+
+pub const PORTSC_CURRENT_CONNECT: u32 = 1 << 0;
+pub const PORTSC_ENABLED: u32 = 1 << 1;
+pub const PORTSC_RESET: u32 = 1 << 4;
+pub const PORTSC_SPEED_SHIFT: u32 = 10;
+pub const PORTSC_SPEED_MASK: u32 = 0xf;
+pub const PORTSC_RESET_CHANGE: u32 = 1 << 21;
+/// Every write-one-to-clear change bit: CSC, PEC, WRC, OCC, PRC, PLC and CEC
+pub const PORTSC_CHANGE_BITS: u32 = 0x7f << 17;
+/// Read/write bits that must be written back unchanged. Everything else is
+/// read-only, write-one-to-clear, or (PED) disables the port when set.
+const PORTSC_PRESERVE: u32 = 0x0e00_c3e0;
+
+/// Extended capability ID of the USB legacy support (BIOS handoff) capability
+const EXTENDED_CAPABILITY_LEGACY_SUPPORT: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+/// SMI event bits of USBLEGCTLSTS are write-one-to-clear; all enables are zero
+const LEGACY_CONTROL_CLEAR_SMI: u32 = 0xe000_0000;
+const OWNERSHIP_TIMEOUT_MS: u64 = 1000;
+
+pub struct Registers {
+    operational: usize,
+    runtime: usize,
+    doorbell: usize,
+    extended_capabilities: Option<usize>,
+    hcsparams1: u32,
+    hcsparams2: u32,
+    hccparams1: u32,
+}
+
+unsafe fn read32(address: usize) -> u32 {
+    (address as *const u32).read_volatile()
+}
+
+unsafe fn write32(address: usize, value: u32) {
+    (address as *mut u32).write_volatile(value)
+}
+
+/// 64-bit registers are written as two dwords, low half first, which every
+/// controller accepts regardless of its AC64 setting
+unsafe fn write64(address: usize, value: u64) {
+    write32(address, value as u32);
+    write32(address + 4, (value >> 32) as u32);
+}
+
+impl Registers {
+    /// # Safety
+    /// `mmio_base` must be the identity mapped BAR0 of an xHC
+    pub unsafe fn new(mmio_base: usize) -> Self {
+        let capability_length = read32(mmio_base + capability::CAPLENGTH) as u8 as usize;
+        let hccparams1 = read32(mmio_base + capability::HCCPARAMS1);
+        let extended_capabilities = match (hccparams1 >> 16) as usize {
+            0 => None,
+            offset => Some(mmio_base + offset * 4),
+        };
+        Self {
+            operational: mmio_base + capability_length,
+            runtime: mmio_base + (read32(mmio_base + capability::RTSOFF) & !0x1f) as usize,
+            doorbell: mmio_base + (read32(mmio_base + capability::DBOFF) & !0b11) as usize,
+            extended_capabilities,
+            hcsparams1: read32(mmio_base + capability::HCSPARAMS1),
+            hcsparams2: read32(mmio_base + capability::HCSPARAMS2),
+            hccparams1,
+        }
+    }
+
+    pub fn max_slots(&self) -> u8 {
+        self.hcsparams1 as u8
+    }
+
+    pub fn max_ports(&self) -> u8 {
+        (self.hcsparams1 >> 24) as u8
+    }
+
+    pub fn max_scratchpad_buffers(&self) -> usize {
+        ((self.hcsparams2 >> 21 & 0x1f) << 5 | self.hcsparams2 >> 27) as usize
+    }
+
+    /// Contexts are 64 bytes instead of 32 when HCCPARAMS1.CSZ is set
+    pub fn context_size(&self) -> usize {
+        if self.hccparams1 & (1 << 2) != 0 {
+            64
+        } else {
+            32
+        }
+    }
+
+    /// Take the controller over from the firmware if it advertises legacy support
+    pub fn request_ownership(&self) {
+        let mut capability = match self.extended_capabilities {
+            Some(capability) => capability,
+            None => return,
+        };
+        loop {
+            let header = unsafe { read32(capability) };
+            if header & 0xff == EXTENDED_CAPABILITY_LEGACY_SUPPORT {
+                unsafe { write32(capability, header | LEGACY_OS_OWNED) };
+                if !super::wait_for(|| unsafe { read32(capability) } & LEGACY_BIOS_OWNED == 0, OWNERSHIP_TIMEOUT_MS) {
+                    println!("[WARN] xHCI: firmware did not release the controller");
+                }
+                unsafe { write32(capability + 4, LEGACY_CONTROL_CLEAR_SMI) };
+                return;
+            }
+            let next = (header >> 8 & 0xff) as usize;
+            if next == 0 {
+                return;
+            }
+            capability += next * 4;
+        }
+    }
+
+    pub fn usbcmd(&self) -> u32 {
+        unsafe { read32(self.operational + operational::USBCMD) }
+    }
+
+    pub fn set_usbcmd(&self, value: u32) {
+        unsafe { write32(self.operational + operational::USBCMD, value) }
+    }
+
+    pub fn usbsts(&self) -> u32 {
+        unsafe { read32(self.operational + operational::USBSTS) }
+    }
+
+    /// Status bits are write-one-to-clear
+    pub fn clear_usbsts(&self, bits: u32) {
+        unsafe { write32(self.operational + operational::USBSTS, bits) }
+    }
+
+    /// Bit n set means pages of 2^(n + 12) bytes are supported
+    pub fn page_size(&self) -> u32 {
+        unsafe { read32(self.operational + operational::PAGESIZE) }
+    }
+
+    pub fn set_command_ring(&self, address: u64) {
+        unsafe { write64(self.operational + operational::CRCR, address | CRCR_RING_CYCLE_STATE) }
+    }
+
+    pub fn set_device_context_base_array(&self, address: u64) {
+        unsafe { write64(self.operational + operational::DCBAAP, address) }
+    }
+
+    pub fn set_max_slots_enabled(&self, slots: u8) {
+        unsafe {
+            let config = read32(self.operational + operational::CONFIG);
+            write32(self.operational + operational::CONFIG, (config & !0xff) | slots as u32);
+        }
+    }
+
+    fn port_register(&self, port: u8) -> usize {
+        self.operational + operational::PORT_REGISTER_SET + (port as usize - 1) * operational::PORT_REGISTER_SET_SIZE
+    }
+
+    /// Ports are numbered from 1
+    pub fn portsc(&self, port: u8) -> u32 {
+        unsafe { read32(self.port_register(port)) }
+    }
+
+    /// Write `bits` (PR or change bits to clear) without disturbing the rest
+    pub fn write_portsc(&self, port: u8, bits: u32) {
+        let value = self.portsc(port) & PORTSC_PRESERVE;
+        unsafe { write32(self.port_register(port), value | bits) }
+    }
+
+    fn interrupter(&self, index: usize) -> usize {
+        self.runtime + RUNTIME_INTERRUPTER_BASE + index * 0x20
+    }
+
+    /// Address of IMAN of the primary interrupter, for the interrupt handler
+    pub fn primary_interrupter_management(&self) -> usize {
+        self.interrupter(0) + interrupter::IMAN
+    }
+
+    pub fn set_event_ring(&self, table: u64, table_size: u32, dequeue_pointer: u64) {
+        let base = self.interrupter(0);
+        unsafe {
+            write32(base + interrupter::ERSTSZ, table_size);
+            write64(base + interrupter::ERDP, dequeue_pointer);
+            // Writing ERSTBA last starts event ring operation
+            write64(base + interrupter::ERSTBA, table);
+        }
+    }
+
+    pub fn set_event_ring_dequeue_pointer(&self, dequeue_pointer: u64) {
+        unsafe { write64(self.interrupter(0) + interrupter::ERDP, dequeue_pointer | ERDP_EVENT_HANDLER_BUSY) }
+    }
+
+    pub fn enable_primary_interrupter(&self, moderation_interval: u16) {
+        let base = self.interrupter(0);
+        unsafe {
+            write32(base + interrupter::IMOD, moderation_interval as u32);
+            write32(base + interrupter::IMAN, IMAN_INTERRUPT_PENDING | IMAN_INTERRUPT_ENABLE);
+        }
+    }
+
+    /// Doorbell 0 is the command ring; other doorbells take the DCI as target
+    pub fn ring_doorbell(&self, slot_id: u8, target: u8) {
+        unsafe { write32(self.doorbell + slot_id as usize * 4, target as u32) }
+    }
+}
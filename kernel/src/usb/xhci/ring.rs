@@ -0,0 +1,293 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Transfer request blocks and the rings that carry them
+
+use super::{DmaBuffer, XhciError};
+use crate::usb::SetupData;
+
+pub mod trb_type {
+    pub const NORMAL: u8 = 1;
+    pub const SETUP_STAGE: u8 = 2;
+    pub const DATA_STAGE: u8 = 3;
+    pub const STATUS_STAGE: u8 = 4;
+    pub const LINK: u8 = 6;
+    pub const ENABLE_SLOT: u8 = 9;
+    pub const DISABLE_SLOT: u8 = 10;
+    pub const ADDRESS_DEVICE: u8 = 11;
+    pub const CONFIGURE_ENDPOINT: u8 = 12;
+    pub const EVALUATE_CONTEXT: u8 = 13;
+    pub const RESET_ENDPOINT: u8 = 14;
+    pub const SET_TR_DEQUEUE_POINTER: u8 = 16;
+    pub const TRANSFER_EVENT: u8 = 32;
+    pub const COMMAND_COMPLETION: u8 = 33;
+    pub const PORT_STATUS_CHANGE: u8 = 34;
+}
+
+pub mod completion_code {
+    pub const SUCCESS: u8 = 1;
+    pub const STALL_ERROR: u8 = 6;
+    pub const SHORT_PACKET: u8 = 13;
+}
+
+const CYCLE: u32 = 1 << 0;
+/// Link TRB: flip the producer cycle state when following this link
+const TOGGLE_CYCLE: u32 = 1 << 1;
+const INTERRUPT_ON_SHORT_PACKET: u32 = 1 << 2;
+const INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+const IMMEDIATE_DATA: u32 = 1 << 6;
+const DIRECTION_IN: u32 = 1 << 16;
+
+/// Setup stage transfer types
+const TRANSFER_TYPE_NO_DATA: u32 = 0;
+const TRANSFER_TYPE_OUT: u32 = 2;
+const TRANSFER_TYPE_IN: u32 = 3;
+
+#[repr(C, align(16))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Trb {
+    pub parameter: u64,
+    pub status: u32,
+    pub control: u32,
+}
+
+impl Trb {
+    fn new(trb_type: u8, parameter: u64, status: u32, control: u32) -> Self {
+        Self {
+            parameter,
+            status,
+            control: control | (trb_type as u32) << 10,
+        }
+    }
+
+    pub fn enable_slot() -> Self {
+        Self::new(trb_type::ENABLE_SLOT, 0, 0, 0)
+    }
+
+    pub fn disable_slot(slot_id: u8) -> Self {
+        Self::new(trb_type::DISABLE_SLOT, 0, 0, (slot_id as u32) << 24)
+    }
+
+    pub fn address_device(input_context: u64, slot_id: u8) -> Self {
+        Self::new(trb_type::ADDRESS_DEVICE, input_context, 0, (slot_id as u32) << 24)
+    }
+
+    pub fn configure_endpoint(input_context: u64, slot_id: u8) -> Self {
+        Self::new(trb_type::CONFIGURE_ENDPOINT, input_context, 0, (slot_id as u32) << 24)
+    }
+
+    pub fn evaluate_context(input_context: u64, slot_id: u8) -> Self {
+        Self::new(trb_type::EVALUATE_CONTEXT, input_context, 0, (slot_id as u32) << 24)
+    }
+
+    /// Take a halted endpoint back to the stopped state
+    pub fn reset_endpoint(slot_id: u8, dci: u8) -> Self {
+        Self::new(trb_type::RESET_ENDPOINT, 0, 0, (slot_id as u32) << 24 | (dci as u32) << 16)
+    }
+
+    /// `dequeue` carries the consumer cycle state in bit 0, see `Ring::enqueue_pointer()`
+    pub fn set_tr_dequeue_pointer(dequeue: u64, slot_id: u8, dci: u8) -> Self {
+        Self::new(trb_type::SET_TR_DEQUEUE_POINTER, dequeue, 0, (slot_id as u32) << 24 | (dci as u32) << 16)
+    }
+
+    fn link(target: u64) -> Self {
+        Self::new(trb_type::LINK, target, 0, TOGGLE_CYCLE)
+    }
+
+    /// The 8 byte setup packet travels inside the TRB itself
+    pub fn setup_stage(setup: &SetupData) -> Self {
+        let transfer_type = match (setup.length, setup.is_in()) {
+            (0, _) => TRANSFER_TYPE_NO_DATA,
+            (_, true) => TRANSFER_TYPE_IN,
+            (_, false) => TRANSFER_TYPE_OUT,
+        };
+        Self::new(trb_type::SETUP_STAGE, setup.to_u64(), 8, IMMEDIATE_DATA | transfer_type << 16)
+    }
+
+    /// A short packet raises an event so the actual length can be recovered
+    pub fn data_stage(buffer: u64, length: u32, is_in: bool) -> Self {
+        let direction = if is_in { DIRECTION_IN } else { 0 };
+        Self::new(trb_type::DATA_STAGE, buffer, length, INTERRUPT_ON_SHORT_PACKET | direction)
+    }
+
+    /// The status stage runs opposite to the data stage, or IN if there is none
+    pub fn status_stage(is_in: bool) -> Self {
+        let direction = if is_in { DIRECTION_IN } else { 0 };
+        Self::new(trb_type::STATUS_STAGE, 0, 0, INTERRUPT_ON_COMPLETION | direction)
+    }
+
+    pub fn normal(buffer: u64, length: u32) -> Self {
+        Self::new(
+            trb_type::NORMAL,
+            buffer,
+            length,
+            INTERRUPT_ON_SHORT_PACKET | INTERRUPT_ON_COMPLETION,
+        )
+    }
+
+    pub fn trb_type(&self) -> u8 {
+        (self.control >> 10 & 0x3f) as u8
+    }
+
+    fn cycle(&self) -> bool {
+        self.control & CYCLE != 0
+    }
+
+    pub fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// Bytes not transferred, for transfer events
+    pub fn residual_length(&self) -> u32 {
+        self.status & 0x00ff_ffff
+    }
+
+    pub fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// Device context index of the endpoint, for transfer events
+    pub fn endpoint_id(&self) -> u8 {
+        (self.control >> 16 & 0x1f) as u8
+    }
+
+    /// Root hub port number, for port status change events
+    pub fn port_id(&self) -> u8 {
+        (self.parameter >> 24) as u8
+    }
+}
+
+const TRB_SIZE: usize = core::mem::size_of::<Trb>();
+
+/// Producer side of a command or transfer ring. The last TRB links back to
+/// the start of the segment.
+pub struct Ring {
+    memory: DmaBuffer,
+    size: usize,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    /// `size` includes the link TRB
+    pub fn new(size: usize) -> Result<Self, XhciError> {
+        let memory = DmaBuffer::new(size * TRB_SIZE)?;
+        let base = memory.address() as u64;
+        let mut ring = Self {
+            memory,
+            size,
+            enqueue: 0,
+            cycle: true,
+        };
+        ring.write(size - 1, Trb::link(base));
+        Ok(ring)
+    }
+
+    pub fn address(&self) -> u64 {
+        self.memory.address() as u64
+    }
+
+    /// Where the next TRB goes, with the producer cycle state in bit 0. Moving
+    /// the controller's dequeue pointer here drops everything still queued.
+    pub fn enqueue_pointer(&self) -> u64 {
+        self.slot(self.enqueue) as u64 | self.cycle as u64
+    }
+
+    fn slot(&self, index: usize) -> *mut Trb {
+        (self.memory.address() + index * TRB_SIZE) as *mut Trb
+    }
+
+    /// Write with the producer cycle bit, making sure the control dword that
+    /// hands the TRB over to the controller is stored last
+    fn write(&mut self, index: usize, trb: Trb) {
+        let control = if self.cycle { trb.control | CYCLE } else { trb.control & !CYCLE };
+        let slot = self.slot(index);
+        unsafe {
+            core::ptr::addr_of_mut!((*slot).parameter).write_volatile(trb.parameter);
+            core::ptr::addr_of_mut!((*slot).status).write_volatile(trb.status);
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+            core::ptr::addr_of_mut!((*slot).control).write_volatile(control);
+        }
+    }
+
+    /// Returns the address of the enqueued TRB, which events refer back to
+    pub fn push(&mut self, trb: Trb) -> u64 {
+        let address = self.slot(self.enqueue) as u64;
+        self.write(self.enqueue, trb);
+        self.enqueue += 1;
+        if self.enqueue == self.size - 1 {
+            let link = Trb::link(self.address());
+            self.write(self.enqueue, link);
+            self.cycle = !self.cycle;
+            self.enqueue = 0;
+        }
+        address
+    }
+}
+
+/// Event ring segment table entry
+#[repr(C, align(64))]
+struct SegmentTableEntry {
+    base: u64,
+    size: u32,
+    reserved: u32,
+}
+
+/// Consumer side of the primary event ring, a single segment
+pub struct EventRing {
+    segment: DmaBuffer,
+    table: DmaBuffer,
+    size: usize,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    pub fn new(size: usize) -> Result<Self, XhciError> {
+        let segment = DmaBuffer::new(size * TRB_SIZE)?;
+        let table = DmaBuffer::new(core::mem::size_of::<SegmentTableEntry>())?;
+        unsafe {
+            (table.address() as *mut SegmentTableEntry).write_volatile(SegmentTableEntry {
+                base: segment.address() as u64,
+                size: size as u32,
+                reserved: 0,
+            });
+        }
+        Ok(Self {
+            segment,
+            table,
+            size,
+            dequeue: 0,
+            cycle: true,
+        })
+    }
+
+    pub fn table_address(&self) -> u64 {
+        self.table.address() as u64
+    }
+
+    /// Number of segment table entries
+    pub fn table_size(&self) -> u32 {
+        1
+    }
+
+    pub fn dequeue_pointer(&self) -> u64 {
+        (self.segment.address() + self.dequeue * TRB_SIZE) as u64
+    }
+
+    /// Take the next event if the controller has produced one
+    pub fn pop(&mut self) -> Option<Trb> {
+        let trb = unsafe { (self.dequeue_pointer() as *const Trb).read_volatile() };
+        if trb.cycle() != self.cycle {
+            return None;
+        }
+        self.dequeue += 1;
+        if self.dequeue == self.size {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+}
@@ -0,0 +1,408 @@
+// Copyright (c) 2024 MATSUSHITA Isato
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Decorated windows on top of the layer compositor.
+//!
+//! Every window is a layer with a frame, a title bar and a close button drawn
+//! around its client area. The window manager turns mouse input into focus
+//! changes, raising, dragging and closing, and sends key presses and clicks
+//! for the focused window to the task that created it as `Message::Window`.
+//! Owners draw through `draw()`.
+//!
+//! Any task on any CPU may call into this module, so the public functions
+//! hold the GUI lock (`graphics::with_lock()`) while they run.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::graphics::{self, cursor, Canvas, DrawTarget, LayerId, PixelColor, Rect};
+use crate::input::{mouse_button, InputEvent};
+use crate::message::{self, Message};
+use crate::task::{self, TaskId};
+
+pub type WindowId = LayerId;
+
+/// Space taken by the frame and title bar around the client area
+const FRAME_LEFT: u32 = 4;
+const FRAME_TOP: u32 = 24;
+const FRAME_RIGHT: u32 = 4;
+const FRAME_BOTTOM: u32 = 4;
+/// Height of the part of the frame that starts a drag
+const TITLE_BAR_HEIGHT: u32 = 22;
+
+const CLOSE_BUTTON_WIDTH: u32 = 16;
+const CLOSE_BUTTON_HEIGHT: u32 = 14;
+/// Narrowest frame with room for the close button and the edges around it
+const MIN_WIDTH: u32 = 5 + CLOSE_BUTTON_WIDTH + 5;
+
+/// Usage ID of F4, which closes the focused window together with Alt
+const KEYCODE_F4: u8 = 0x3d;
+
+#[rustfmt::skip]
+const CLOSE_BUTTON: [&[u8; CLOSE_BUTTON_WIDTH as usize]; CLOSE_BUTTON_HEIGHT as usize] = [
+    b"...............@",
+    b".:::::::::::::$@",
+    b".:::::::::::::$@",
+    b".:::@@::::@@::$@",
+    b".::::@@::@@:::$@",
+    b".:::::@@@@::::$@",
+    b".::::::@@:::::$@",
+    b".:::::@@@@::::$@",
+    b".::::@@::@@:::$@",
+    b".:::@@::::@@::$@",
+    b".:::::::::::::$@",
+    b".:::::::::::::$@",
+    b".$$$$$$$$$$$$$$@",
+    b"@@@@@@@@@@@@@@@@",
+];
+
+/// Color of the frame, also filling new client areas
+pub const FACE_COLOR: PixelColor = PixelColor::from_argb(0xc6c6c6);
+const HIGHLIGHT_COLOR: PixelColor = PixelColor::from_argb(0xffffff);
+const SHADOW_COLOR: PixelColor = PixelColor::from_argb(0x848484);
+const DARK_SHADOW_COLOR: PixelColor = PixelColor::from_argb(0x000000);
+const ACTIVE_TITLE_COLOR: PixelColor = PixelColor::from_argb(0x000084);
+const INACTIVE_TITLE_COLOR: PixelColor = PixelColor::from_argb(0x848484);
+const TITLE_TEXT_COLOR: PixelColor = PixelColor::from_argb(0xffffff);
+
+/// Input delivered to a window
+#[derive(Clone, Copy, Debug)]
+pub enum WindowEvent {
+    /// A key was pressed or released while the window had the focus
+    Key { pressed: bool, character: Option<char> },
+    /// The window gained or lost the keyboard focus
+    Focus(bool),
+    /// The window is gone, closed with its close button or Alt+F4
+    Closed,
+}
+
+/// Client area of a window as a `DrawTarget`; (0, 0) is its top left corner
+pub struct ClientArea<'a> {
+    buffer: &'a mut graphics::BackBuffer,
+    width: u32,
+    height: u32,
+}
+
+impl<'a> DrawTarget for ClientArea<'a> {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn read_pixel(&self, x: u32, y: u32) -> PixelColor {
+        DrawTarget::read_pixel(&*self.buffer, FRAME_LEFT + x, FRAME_TOP + y)
+    }
+
+    fn write_pixel(&mut self, x: u32, y: u32, color: PixelColor) {
+        self.buffer.write_pixel(FRAME_LEFT + x, FRAME_TOP + y, color);
+    }
+
+    fn invalidate(&mut self, rect: Rect) {
+        self.buffer.invalidate(Rect { x: FRAME_LEFT + rect.x, y: FRAME_TOP + rect.y, ..rect });
+    }
+}
+
+struct Window {
+    id: WindowId,
+    title: String,
+    /// Size including the frame
+    width: u32,
+    height: u32,
+    /// Task receiving the events of this window
+    owner: TaskId,
+}
+
+impl Window {
+    fn client_rect(&self) -> Rect {
+        Rect {
+            x: FRAME_LEFT,
+            y: FRAME_TOP,
+            width: self.width - FRAME_LEFT - FRAME_RIGHT,
+            height: self.height - FRAME_TOP - FRAME_BOTTOM,
+        }
+    }
+
+    fn title_bar_rect(&self) -> Rect {
+        Rect { x: 0, y: 0, width: self.width, height: TITLE_BAR_HEIGHT }
+    }
+
+    fn close_button_rect(&self) -> Rect {
+        Rect {
+            x: self.width - 5 - CLOSE_BUTTON_WIDTH,
+            y: 5,
+            width: CLOSE_BUTTON_WIDTH,
+            height: CLOSE_BUTTON_HEIGHT,
+        }
+    }
+
+    /// Events are dropped while the owner's queue is full
+    fn send_event(&self, event: WindowEvent) {
+        let _ = message::send(self.owner, Message::Window { window: self.id, event });
+    }
+
+    /// Draw the frame, leaving the client area as it is
+    fn draw_frame(&self, active: bool) {
+        let buffer = match graphics::layers().layer(self.id) {
+            Some(layer) => layer.buffer(),
+            None => return,
+        };
+        let (w, h) = (self.width as i32, self.height as i32);
+        let mut canvas = Canvas::new(buffer);
+        canvas.fill_rectangle(0, 0, w as u32, 1, FACE_COLOR);
+        canvas.fill_rectangle(1, 1, w as u32 - 2, 1, HIGHLIGHT_COLOR);
+        canvas.fill_rectangle(0, 0, 1, h as u32, FACE_COLOR);
+        canvas.fill_rectangle(1, 1, 1, h as u32 - 2, HIGHLIGHT_COLOR);
+        canvas.fill_rectangle(w - 2, 1, 1, h as u32 - 2, SHADOW_COLOR);
+        canvas.fill_rectangle(w - 1, 0, 1, h as u32, DARK_SHADOW_COLOR);
+        canvas.fill_rectangle(1, h - 2, w as u32 - 2, 1, SHADOW_COLOR);
+        canvas.fill_rectangle(0, h - 1, w as u32, 1, DARK_SHADOW_COLOR);
+        // Face between the outer edges and the client area
+        let client = self.client_rect();
+        canvas.fill_rectangle(2, 2, w as u32 - 4, FRAME_TOP - 2, FACE_COLOR);
+        canvas.fill_rectangle(2, FRAME_TOP as i32, FRAME_LEFT - 2, client.height, FACE_COLOR);
+        canvas.fill_rectangle(client.right() as i32, FRAME_TOP as i32, FRAME_RIGHT - 2, client.height, FACE_COLOR);
+        canvas.fill_rectangle(2, client.bottom() as i32, w as u32 - 4, FRAME_BOTTOM - 2, FACE_COLOR);
+
+        let title_color = if active { ACTIVE_TITLE_COLOR } else { INACTIVE_TITLE_COLOR };
+        canvas.fill_rectangle(3, 3, w as u32 - 6, TITLE_BAR_HEIGHT - 4, title_color);
+        let button = self.close_button_rect();
+        // Keep the title off the close button
+        canvas.set_clip(Rect { x: 3, y: 3, width: button.x.saturating_sub(3 + 2), height: TITLE_BAR_HEIGHT - 4 });
+        canvas.draw_string(8, 4, &self.title, TITLE_TEXT_COLOR);
+        canvas.reset_clip();
+
+        for (row, pattern) in CLOSE_BUTTON.iter().enumerate() {
+            for (column, &c) in pattern.iter().enumerate() {
+                let color = match c {
+                    b'@' => DARK_SHADOW_COLOR,
+                    b'$' => SHADOW_COLOR,
+                    b':' => FACE_COLOR,
+                    _ => HIGHLIGHT_COLOR,
+                };
+                canvas.draw_pixel(button.x as i32 + column as i32, button.y as i32 + row as i32, color);
+            }
+        }
+    }
+}
+
+struct WindowManager {
+    windows: Vec<Window>,
+    focused: Option<WindowId>,
+    /// Window following the pointer while the left button is held on its title bar
+    dragging: Option<WindowId>,
+    previous_buttons: u8,
+    previous_position: (u32, u32),
+    /// Where the next window is placed, moving down and right every time
+    next_position: (i32, i32),
+}
+
+static mut WINDOW_MANAGER: WindowManager = WindowManager {
+    windows: Vec::new(),
+    focused: None,
+    dragging: None,
+    previous_buttons: 0,
+    previous_position: (0, 0),
+    next_position: (64, 64),
+};
+
+fn manager() -> &'static mut WindowManager {
+    unsafe { &mut *core::ptr::addr_of_mut!(WINDOW_MANAGER) }
+}
+
+impl WindowManager {
+    fn window(&mut self, id: WindowId) -> Option<&mut Window> {
+        self.windows.iter_mut().find(|window| window.id == id)
+    }
+
+    fn window_at(&self, x: u32, y: u32) -> Option<WindowId> {
+        graphics::layers().find_at(x, y, |id| self.windows.iter().any(|window| window.id == id))
+    }
+
+    fn set_focus(&mut self, id: Option<WindowId>) {
+        if self.focused == id {
+            return;
+        }
+        for (target, active) in [(self.focused, false), (id, true)] {
+            if let Some(window) = target.and_then(|target| self.window(target)) {
+                window.draw_frame(active);
+                window.send_event(WindowEvent::Focus(active));
+            }
+        }
+        self.focused = id;
+    }
+
+    fn close(&mut self, id: WindowId) {
+        if self.focused == Some(id) {
+            self.set_focus(None);
+        }
+        if self.dragging == Some(id) {
+            self.dragging = None;
+        }
+        if let Some(index) = self.windows.iter().position(|window| window.id == id) {
+            let window = self.windows.remove(index);
+            graphics::layers().remove(id);
+            window.send_event(WindowEvent::Closed);
+        }
+    }
+
+    fn on_mouse(&mut self, x: u32, y: u32, buttons: u8) {
+        let (previous_x, previous_y) = self.previous_position;
+        let pressed = buttons & !self.previous_buttons;
+        self.previous_buttons = buttons;
+        self.previous_position = (x, y);
+
+        if let Some(id) = self.dragging {
+            if buttons & mouse_button::LEFT == 0 {
+                self.dragging = None;
+            } else {
+                graphics::layers().move_by(id, x as i32 - previous_x as i32, y as i32 - previous_y as i32);
+                return;
+            }
+        }
+
+        let target = self.window_at(x, y);
+        if pressed & mouse_button::LEFT != 0 {
+            self.set_focus(target);
+            if let Some(id) = target {
+                graphics::layers().raise(id);
+            }
+        }
+        let id = match target {
+            Some(id) => id,
+            None => return,
+        };
+        let (window_x, window_y) = graphics::layers().layer(id).map_or((0, 0), |layer| layer.position());
+        let point = Rect {
+            x: (x as i64 - window_x as i64) as u32,
+            y: (y as i64 - window_y as i64) as u32,
+            width: 1,
+            height: 1,
+        };
+        let window = match self.window(id) {
+            Some(window) => window,
+            None => return,
+        };
+        if pressed & mouse_button::LEFT != 0 {
+            if window.close_button_rect().contains(&point) {
+                self.close(id);
+                return;
+            }
+            if window.title_bar_rect().contains(&point) {
+                self.dragging = Some(id);
+            }
+        }
+    }
+}
+
+/// Create a focused window on top of the others whose client area is
+/// `client_width` x `client_height` pixels, filled with the frame color.
+/// Narrower client areas are widened to fit the close button. Its events
+/// are sent to the calling task.
+pub fn create(title: &str, client_width: u32, client_height: u32) -> WindowId {
+    graphics::with_lock(|| {
+        let manager = manager();
+        let width = (client_width + FRAME_LEFT + FRAME_RIGHT).max(MIN_WIDTH);
+        let height = client_height + FRAME_TOP + FRAME_BOTTOM;
+        let layers = graphics::layers();
+        let id = layers.new_layer(width, height);
+        let (x, y) = manager.next_position;
+        layers.move_to(id, x, y);
+        manager.next_position = ((x + 32) % 320, (y + 32) % 240);
+
+        let window = Window {
+            id,
+            title: String::from(title),
+            width,
+            height,
+            owner: task::current_id(),
+        };
+        let client = window.client_rect();
+        if let Some(layer) = layers.layer(id) {
+            layer.buffer().fill_rectangle(client, FACE_COLOR);
+        }
+        window.draw_frame(false);
+        manager.windows.push(window);
+        manager.set_focus(Some(id));
+        graphics::flush();
+        id
+    })
+}
+
+/// Move the top left corner of the frame to (x, y) on screen
+pub fn move_to(id: WindowId, x: i32, y: i32) {
+    graphics::with_lock(|| {
+        if manager().window(id).is_some() {
+            graphics::layers().move_to(id, x, y);
+            graphics::flush();
+        }
+    })
+}
+
+/// Width and height of the client area
+pub fn client_size(id: WindowId) -> Option<(u32, u32)> {
+    graphics::with_lock(|| {
+        manager().window(id).map(|window| {
+            let client = window.client_rect();
+            (client.width, client.height)
+        })
+    })
+}
+
+/// Draw into the client area and show the result. Returns false if the window is gone.
+pub fn draw(id: WindowId, f: impl FnOnce(&mut Canvas<ClientArea>)) -> bool {
+    graphics::with_lock(|| {
+        let (width, height) = match manager().window(id) {
+            Some(window) => {
+                let client = window.client_rect();
+                (client.width, client.height)
+            }
+            None => return false,
+        };
+        let buffer = match graphics::layers().layer(id) {
+            Some(layer) => layer.buffer(),
+            None => return false,
+        };
+        let mut client = ClientArea { buffer, width, height };
+        f(&mut Canvas::new(&mut client));
+        graphics::flush();
+        true
+    })
+}
+
+/// Feed the current pointer position and buttons to the window manager;
+/// call after every mouse event once the pointer has moved
+pub fn handle_mouse(buttons: u8) {
+    graphics::with_lock(|| {
+        if let Some((x, y)) = cursor::position() {
+            manager().on_mouse(x, y, buttons);
+            graphics::flush();
+        }
+    })
+}
+
+/// Queue a key event for the focused window, or close it on Alt+F4. Returns
+/// false if no window has the focus, leaving the key to the console.
+pub fn handle_key(event: &InputEvent) -> bool {
+    graphics::with_lock(|| {
+        let manager = manager();
+        let window = match manager.focused.and_then(|id| manager.window(id)) {
+            Some(window) => window,
+            None => return false,
+        };
+        if let InputEvent::Key { keycode, modifiers, pressed, character } = *event {
+            if pressed && keycode == KEYCODE_F4 && modifiers.alt() {
+                let id = window.id;
+                manager.close(id);
+                graphics::flush();
+            } else {
+                window.send_event(WindowEvent::Key { pressed, character });
+            }
+        }
+        true
+    })
+}
@@ -0,0 +1,52 @@
+#!/usr/bin/python3
+
+"""Cut a screenshot emitted by the kernel's screenshot module out of a serial log."""
+
+import argparse
+import base64
+import re
+import sys
+import zlib
+
+
+BEGIN_PATTERN = re.compile(r'-----BEGIN RIKAN SCREENSHOT (\w+) (\d+)x(\d+) (\d+)-----')
+END_PATTERN = re.compile(r'-----END RIKAN SCREENSHOT crc32=([0-9a-f]{8})-----')
+
+
+def extract(log: str) -> bytes:
+    begin = BEGIN_PATTERN.search(log)
+    if not begin:
+        raise ValueError('no screenshot in log')
+    end = END_PATTERN.search(log, begin.end())
+    if not end:
+        raise ValueError('screenshot is incomplete')
+
+    data = base64.b64decode(''.join(log[begin.end():end.start()].split()))
+    if len(data) != int(begin.group(4)):
+        raise ValueError(f'size mismatch: expected {begin.group(4)}, got {len(data)}')
+    if zlib.crc32(data) != int(end.group(1), 16):
+        raise ValueError('crc32 mismatch')
+    return data
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument('log', help='serial log file')
+    parser.add_argument('-o', dest='output', required=True, help='output PPM file')
+    ns = parser.parse_args()
+
+    with open(ns.log, errors='replace') as f:
+        log = f.read()
+
+    try:
+        data = extract(log)
+    except ValueError as e:
+        print(f'{ns.log}: {e}', file=sys.stderr)
+        sys.exit(1)
+
+    with open(ns.output, 'wb') as f:
+        f.write(data)
+
+
+if __name__ == '__main__':
+    main()